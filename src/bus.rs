@@ -0,0 +1,365 @@
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use crate::fdt::Fdt;
+
+/// A memory-mapped device. Accesses are 1, 2, 4 or 8 bytes wide, at an
+/// offset from the start of the range the device is mapped at, and return
+/// `None` to raise an access fault for accesses the device does not
+/// support.
+pub trait Device {
+    /// Read `size` bytes, zero-extended to 64 bits
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64>;
+
+    /// Write the low `size` bytes of `value`
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()>;
+
+    /// Advance the device by one cycle, for devices that change state on
+    /// their own, such as when host input arrives
+    fn tick(&mut self) {}
+
+    /// Access guest RAM directly, once per cycle after `tick`, for devices
+    /// that transfer data to and from memory themselves
+    fn dma(&mut self, _memory: &mut Dma) {}
+
+    /// Whether the device is asserting its interrupt line
+    fn interrupt(&self) -> bool {
+        false
+    }
+
+    /// Receive the interrupt sources whose devices are asserting their
+    /// lines, for the interrupt controller they are wired to
+    fn set_interrupt_sources(&mut self, _sources: &[u32]) {}
+
+    /// Bits of mip driven by the device, for interrupt controllers wired
+    /// directly to the hart
+    fn hart_interrupts(&self) -> u64 {
+        0
+    }
+
+    /// Current time, for the device providing the timer that the `time`
+    /// CSR reads
+    fn time(&self) -> Option<u64> {
+        None
+    }
+
+    /// Add a node describing the device, mapped at `base` and wired to
+    /// interrupt source `irq`, for devices guests discover through the
+    /// device tree
+    fn device_tree(&self, _fdt: &mut Fdt, _base: u64, _size: u64, _irq: Option<u32>) {}
+}
+
+/// Guest RAM as devices see it when they access it directly
+pub struct Dma<'a> {
+    base: u64,
+    ram: &'a mut [u8],
+}
+
+impl<'a> Dma<'a> {
+    /// RAM starting at physical address `base`
+    pub fn new(base: u64, ram: &'a mut [u8]) -> Self {
+        Self { base, ram }
+    }
+
+    fn range(&self, address: u64, size: usize) -> Option<Range<usize>> {
+        ram_range(self.base, self.ram.len(), address, size)
+    }
+
+    /// Size of RAM in bytes
+    pub fn size(&self) -> usize {
+        self.ram.len()
+    }
+
+    /// Whether the `size` bytes at `address` all lie in RAM
+    pub fn contains(&self, address: u64, size: usize) -> bool {
+        self.range(address, size).is_some()
+    }
+
+    /// Read `size` bytes of little-endian data, zero-extended to 64 bits
+    pub fn read(&self, address: u64, size: usize) -> Option<u64> {
+        let mut value = [0; 8];
+        self.read_bytes(address, &mut value[..size])?;
+        Some(u64::from_le_bytes(value))
+    }
+
+    /// Write the low `size` bytes of `value`
+    pub fn write(&mut self, address: u64, size: usize, value: u64) -> Option<()> {
+        self.write_bytes(address, &value.to_le_bytes()[..size])
+    }
+
+    /// Fill `bytes` from memory starting at `address`
+    pub fn read_bytes(&self, address: u64, bytes: &mut [u8]) -> Option<()> {
+        let range = self.range(address, bytes.len())?;
+        bytes.copy_from_slice(&self.ram[range]);
+        Some(())
+    }
+
+    pub fn write_bytes(&mut self, address: u64, bytes: &[u8]) -> Option<()> {
+        let range = self.range(address, bytes.len())?;
+        self.ram[range].copy_from_slice(bytes);
+        Some(())
+    }
+}
+
+/// Byte range of `size` bytes at `address` within RAM of `length` bytes at
+/// `base`, if it is entirely in bounds
+fn ram_range(base: u64, length: usize, address: u64, size: usize) -> Option<Range<usize>> {
+    let start = usize::try_from(address.checked_sub(base)?).ok()?;
+    let end = start.checked_add(size)?;
+    if end <= length {
+        Some(start..end)
+    } else {
+        None
+    }
+}
+
+struct Mapping {
+    base: u64,
+    size: u64,
+    /// Interrupt source number of the device's interrupt line, if wired
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+impl Mapping {
+    fn contains(&self, address: u64, size: usize) -> bool {
+        address >= self.base
+            && (address - self.base)
+                .checked_add(size as u64)
+                .is_some_and(|end| end <= self.size)
+    }
+}
+
+/// Physical address space: RAM, which the host also accesses directly to
+/// service syscalls and load programs, plus devices mapped around it.
+/// Addresses covered by neither raise access faults.
+pub struct Bus {
+    pub ram: Vec<u8>,
+    /// Physical address of the first byte of `ram`
+    pub ram_base: u64,
+    devices: Vec<Mapping>,
+    /// Interrupt sources asserted as of the last tick
+    asserted: Vec<u32>,
+}
+
+impl Bus {
+    pub fn new(ram_base: u64, ram: Vec<u8>) -> Self {
+        Self {
+            ram,
+            ram_base,
+            devices: Vec::new(),
+            asserted: Vec::new(),
+        }
+    }
+
+    /// Map `device` at `size` bytes starting at `base`. The range must not
+    /// overlap RAM or another device.
+    pub fn add_device(&mut self, base: u64, size: u64, device: Box<dyn Device>) {
+        self.map(base, size, None, device);
+    }
+
+    /// Map `device` as `add_device` does, with its interrupt line wired to
+    /// interrupt source `irq` of the interrupt controller
+    pub fn add_device_with_irq(&mut self, base: u64, size: u64, irq: u32, device: Box<dyn Device>) {
+        self.map(base, size, Some(irq), device);
+    }
+
+    fn map(&mut self, base: u64, size: u64, irq: Option<u32>, device: Box<dyn Device>) {
+        assert!(
+            !self.overlaps(base, size),
+            "device at 0x{:x} overlaps another mapping",
+            base
+        );
+        self.devices.push(Mapping {
+            base,
+            size,
+            irq,
+            device,
+        });
+    }
+
+    /// Whether any of `size` bytes starting at `base` is RAM or a device
+    pub fn overlaps(&self, base: u64, size: u64) -> bool {
+        let end = base.saturating_add(size);
+        let overlaps = |start: u64, length: u64| base < start + length && start < end;
+        overlaps(self.ram_base, self.ram.len() as u64)
+            || self
+                .devices
+                .iter()
+                .any(|mapping| overlaps(mapping.base, mapping.size))
+    }
+
+    /// Advance every device by one cycle, letting it access RAM, then
+    /// deliver the interrupt lines they assert to the interrupt controller
+    pub fn tick(&mut self) {
+        self.asserted.clear();
+        let mut memory = Dma::new(self.ram_base, &mut self.ram);
+        for mapping in &mut self.devices {
+            mapping.device.tick();
+            mapping.device.dma(&mut memory);
+            if let Some(irq) = mapping.irq {
+                if mapping.device.interrupt() {
+                    self.asserted.push(irq);
+                }
+            }
+        }
+        for mapping in &mut self.devices {
+            mapping.device.set_interrupt_sources(&self.asserted);
+        }
+    }
+
+    /// Bits of mip driven by devices
+    pub fn hart_interrupts(&self) -> u64 {
+        self.devices
+            .iter()
+            .fold(0, |mip, mapping| mip | mapping.device.hart_interrupts())
+    }
+
+    /// Current time of the platform timer, if there is one
+    pub fn time(&self) -> Option<u64> {
+        self.devices
+            .iter()
+            .find_map(|mapping| mapping.device.time())
+    }
+
+    /// Add nodes describing the devices to the device tree
+    pub fn device_tree(&self, fdt: &mut Fdt) {
+        for mapping in &self.devices {
+            mapping
+                .device
+                .device_tree(fdt, mapping.base, mapping.size, mapping.irq);
+        }
+    }
+
+    /// Byte range of RAM covered by an access, if it is entirely in bounds
+    pub(crate) fn ram_range(&self, address: u64, size: usize) -> Option<Range<usize>> {
+        ram_range(self.ram_base, self.ram.len(), address, size)
+    }
+
+    fn device(&mut self, address: u64, size: usize) -> Option<&mut Mapping> {
+        self.devices
+            .iter_mut()
+            .find(|mapping| mapping.contains(address, size))
+    }
+
+    /// Whether an access lies entirely within RAM or a single device
+    pub fn is_mapped(&self, address: u64, size: usize) -> bool {
+        self.ram_range(address, size).is_some()
+            || self
+                .devices
+                .iter()
+                .any(|mapping| mapping.contains(address, size))
+    }
+
+    /// Read `size` bytes of little-endian data, zero-extended to 64 bits
+    pub fn read(&mut self, address: u64, size: usize) -> Option<u64> {
+        if let Some(range) = self.ram_range(address, size) {
+            let mut value = [0; 8];
+            value[..size].copy_from_slice(&self.ram[range]);
+            return Some(u64::from_le_bytes(value));
+        }
+        let mapping = self.device(address, size)?;
+        mapping.device.read(address - mapping.base, size)
+    }
+
+    /// Write the low `size` bytes of `value`
+    pub fn write(&mut self, address: u64, size: usize, value: u64) -> Option<()> {
+        if let Some(range) = self.ram_range(address, size) {
+            self.ram[range].copy_from_slice(&value.to_le_bytes()[..size]);
+            return Some(());
+        }
+        let mapping = self.device(address, size)?;
+        mapping.device.write(address - mapping.base, size, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::trap::Exception;
+
+    /// A register that only supports word accesses, at any offset
+    struct Register {
+        value: u64,
+    }
+
+    impl Device for Register {
+        fn read(&mut self, _offset: u64, size: usize) -> Option<u64> {
+            if size == 4 {
+                Some(self.value)
+            } else {
+                None
+            }
+        }
+
+        fn write(&mut self, _offset: u64, size: usize, value: u64) -> Option<()> {
+            if size == 4 {
+                self.value = value;
+                Some(())
+            } else {
+                None
+            }
+        }
+    }
+
+    fn register() -> Box<Register> {
+        Box::new(Register { value: 0x1234 })
+    }
+
+    #[test]
+    fn test_bus() {
+        let mut bus = Bus::new(0x8000_0000, vec![0; 0x100]);
+        bus.add_device(0x1000_0000, 0x10, register());
+
+        assert_eq!(bus.write(0x8000_00fc, 4, 0xdeadbeef), Some(()));
+        assert_eq!(bus.read(0x8000_00fe, 2), Some(0xdead));
+        assert_eq!(bus.ram[0xfc], 0xef);
+        // Accesses running off the end of RAM or straddling a device fault
+        assert_eq!(bus.read(0x8000_00fd, 4), None);
+        assert_eq!(bus.write(0x8000_0100, 1, 0), None);
+        assert_eq!(bus.read(0x1000_000e, 4), None);
+        assert!(!bus.is_mapped(0x7fff_ffff, 1));
+
+        assert_eq!(bus.read(0x1000_0004, 4), Some(0x1234));
+        assert_eq!(bus.write(0x1000_0008, 4, 7), Some(()));
+        assert_eq!(bus.read(0x1000_0000, 4), Some(7));
+        // The device decides which sizes it supports
+        assert_eq!(bus.read(0x1000_0000, 8), None);
+        assert!(bus.is_mapped(0x1000_0000, 8));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_overlapping_devices() {
+        let mut bus = Bus::new(0, vec![0; 0x100]);
+        bus.add_device(0x1000, 0x10, register());
+        bus.add_device(0x1008, 0x10, register());
+    }
+
+    #[test]
+    fn test_device_access() {
+        let mut emu = Emulator::new(vec![
+            0x03, 0x26, 0x05, 0x00, // lw   a2,0(a0)
+            0x23, 0x10, 0xc5, 0x00, // sh   a2,0(a0)
+            0x03, 0x36, 0x05, 0x00, // ld   a2,0(a0)
+            0x23, 0x20, 0xb5, 0x00, // sw   a1,0(a0)
+        ]);
+        emu.bus.add_device(0x1000, 0x10, register());
+        emu.regs[10] = 0x1000;
+        emu.regs[11] = 0x55;
+
+        emu.step().unwrap();
+        assert_eq!(emu.regs[12], 0x1234);
+        assert_eq!(emu.step(), Err(Exception::StoreAccessFault(0x1000)));
+        emu.pc = 8;
+        assert_eq!(emu.step(), Err(Exception::LoadAccessFault(0x1000)));
+        emu.pc = 12;
+        emu.step().unwrap();
+        assert_eq!(emu.bus.read(0x1000, 4), Some(0x55));
+        // Unmapped addresses fault
+        emu.regs[10] = 0x2000;
+        emu.pc = 0;
+        assert_eq!(emu.step(), Err(Exception::LoadAccessFault(0x2000)));
+    }
+}
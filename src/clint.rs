@@ -0,0 +1,246 @@
+use std::time::Instant;
+
+use crate::bus::Device;
+use crate::csr::{MIP_MSIP, MIP_MTIP};
+use crate::fdt::{Fdt, CPU_INTC_PHANDLE};
+
+/// Address and size of the CLINT on QEMU's `virt` board
+pub const CLINT_BASE: u64 = 0x0200_0000;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+/// Frequency of mtime in Hz, as on QEMU's `virt` board
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+// Register offsets for hart 0, the only hart
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+/// Number of cycles between reads of the host clock
+const WALL_CLOCK_INTERVAL: u64 = 256;
+
+/// What mtime counts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeBase {
+    /// Host time elapsed, at `TIMEBASE_FREQUENCY`
+    WallClock,
+    /// Cycles the hart has run, one per instruction, so that runs are
+    /// reproducible
+    Instructions,
+}
+
+/// Core-local interruptor: the machine timer and software interrupts of
+/// a single hart
+pub struct Clint {
+    time_base: TimeBase,
+    msip: bool,
+    mtimecmp: u64,
+    mtime: u64,
+    /// Host time and mtime when mtime was last written, from which the wall
+    /// clock counts
+    epoch: Instant,
+    epoch_mtime: u64,
+    cycles: u64,
+}
+
+impl Clint {
+    pub fn new(time_base: TimeBase) -> Self {
+        Self {
+            time_base,
+            msip: false,
+            // No timer interrupt until software sets a compare value
+            mtimecmp: u64::MAX,
+            mtime: 0,
+            epoch: Instant::now(),
+            epoch_mtime: 0,
+            cycles: 0,
+        }
+    }
+
+    fn wall_clock(&self) -> u64 {
+        let elapsed = self.epoch.elapsed().as_nanos() * TIMEBASE_FREQUENCY as u128 / 1_000_000_000;
+        self.epoch_mtime.wrapping_add(elapsed as u64)
+    }
+
+    fn set_mtime(&mut self, value: u64) {
+        self.mtime = value;
+        self.epoch = Instant::now();
+        self.epoch_mtime = value;
+    }
+}
+
+/// Read `size` bytes at `offset` into a 64-bit register
+fn read_part(register: u64, offset: u64, size: usize) -> u64 {
+    let value = register >> (8 * offset);
+    if size == 8 {
+        value
+    } else {
+        value & ((1 << (8 * size)) - 1)
+    }
+}
+
+/// Replace `size` bytes at `offset` into a 64-bit register
+fn write_part(register: u64, offset: u64, size: usize, value: u64) -> u64 {
+    let mask = if size == 8 {
+        u64::MAX
+    } else {
+        ((1 << (8 * size)) - 1) << (8 * offset)
+    };
+    register & !mask | (value << (8 * offset)) & mask
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+        if size < 4 || !offset.is_multiple_of(size as u64) {
+            return None;
+        }
+        match offset {
+            MSIP if size == 4 => Some(self.msip as u64),
+            MTIMECMP..=0x4007 => Some(read_part(self.mtimecmp, offset - MTIMECMP, size)),
+            MTIME..=0xbfff => Some(read_part(self.mtime, offset - MTIME, size)),
+            // Registers of harts that do not exist
+            _ => Some(0),
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if size < 4 || !offset.is_multiple_of(size as u64) {
+            return None;
+        }
+        match offset {
+            MSIP if size == 4 => self.msip = value & 1 != 0,
+            MTIMECMP..=0x4007 => {
+                self.mtimecmp = write_part(self.mtimecmp, offset - MTIMECMP, size, value)
+            }
+            MTIME..=0xbfff => {
+                let mtime = write_part(self.mtime, offset - MTIME, size, value);
+                self.set_mtime(mtime);
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn tick(&mut self) {
+        self.cycles += 1;
+        match self.time_base {
+            TimeBase::Instructions => self.mtime = self.mtime.wrapping_add(1),
+            TimeBase::WallClock => {
+                if self.cycles.is_multiple_of(WALL_CLOCK_INTERVAL) {
+                    self.mtime = self.wall_clock();
+                }
+            }
+        }
+    }
+
+    fn hart_interrupts(&self) -> u64 {
+        let software = if self.msip { MIP_MSIP } else { 0 };
+        let timer = if self.mtime >= self.mtimecmp {
+            MIP_MTIP
+        } else {
+            0
+        };
+        software | timer
+    }
+
+    fn time(&self) -> Option<u64> {
+        Some(self.mtime)
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, _irq: Option<u32>) {
+        fdt.begin_node(&format!("clint@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_u64s("reg", &[base, size]);
+        fdt.property_cells(
+            "interrupts-extended",
+            &[CPU_INTC_PHANDLE, 3, CPU_INTC_PHANDLE, 7],
+        );
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::csr;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_registers() {
+        let mut clint = Clint::new(TimeBase::Instructions);
+        assert_eq!(clint.hart_interrupts(), 0);
+        clint.write(MSIP, 4, 3).unwrap();
+        assert_eq!(clint.read(MSIP, 4), Some(1));
+        assert_eq!(clint.hart_interrupts(), MIP_MSIP);
+        clint.write(MSIP, 4, 0).unwrap();
+
+        // mtimecmp may be written in halves
+        clint.write(MTIMECMP, 4, 0x10).unwrap();
+        assert_eq!(clint.read(MTIMECMP, 8), Some(0xffffffff_00000010));
+        clint.write(MTIMECMP + 4, 4, 0).unwrap();
+        assert_eq!(clint.read(MTIMECMP, 8), Some(0x10));
+
+        clint.write(MTIME, 8, 0xe).unwrap();
+        clint.tick();
+        assert_eq!(clint.read(MTIME, 4), Some(0xf));
+        assert_eq!(clint.hart_interrupts(), 0);
+        clint.tick();
+        assert_eq!(clint.hart_interrupts(), MIP_MTIP);
+        assert_eq!(clint.time(), Some(0x10));
+
+        assert_eq!(clint.read(MTIME, 2), None);
+        assert_eq!(clint.read(MTIMECMP + 4, 8), None);
+        // Other harts' registers read as zero
+        assert_eq!(clint.read(MSIP + 4, 4), Some(0));
+    }
+
+    #[test]
+    fn test_wall_clock() {
+        let mut clint = Clint::new(TimeBase::WallClock);
+        clint.write(MTIME, 8, 1000).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        for _ in 0..WALL_CLOCK_INTERVAL {
+            clint.tick();
+        }
+        // At least 2ms at 10MHz
+        assert!(clint.read(MTIME, 8).unwrap() >= 1000 + 20_000);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let code = vec![
+            0x97, 0x02, 0x00, 0x00, // auipc t0, 0
+            0x93, 0x82, 0x02, 0x04, // addi t0, t0, 64
+            0x73, 0x90, 0x52, 0x30, // csrw mtvec, t0
+            0xb7, 0x42, 0x00, 0x02, // lui t0, 0x2004
+            0x13, 0x03, 0x40, 0x06, // li t1, 100
+            0x23, 0xb0, 0x62, 0x00, // sd t1, 0(t0)
+            0x13, 0x03, 0x00, 0x08, // li t1, 128
+            0x73, 0x20, 0x43, 0x30, // csrs mie, t1
+            0x73, 0x60, 0x04, 0x30, // csrsi mstatus, 8
+            0x6f, 0x00, 0x00, 0x00, // j .
+        ];
+        let mut memory = vec![0; 0x1000];
+        memory[..code.len()].copy_from_slice(&code);
+        // The handler at 64 loops
+        memory[64..68].copy_from_slice(&[0x6f, 0x00, 0x00, 0x00]);
+        let mut emu = Emulator::new(memory);
+        emu.bus.add_device(
+            CLINT_BASE,
+            CLINT_SIZE,
+            Box::new(Clint::new(TimeBase::Instructions)),
+        );
+
+        emu.run_for(99).unwrap();
+        assert_eq!(emu.pc, 36);
+        assert_eq!(emu.csr.get(csr::TIME), 99);
+        emu.run_for(1).unwrap();
+        assert_eq!(emu.pc, 64);
+        assert_eq!(emu.csr.get(csr::MCAUSE), 1 << 63 | 7);
+        assert_eq!(emu.csr.get(csr::MEPC), 36);
+        assert_eq!(emu.csr.mip() & csr::MIP_MTIP, csr::MIP_MTIP);
+        // MIE is cleared in the handler, so the interrupt stays pending
+        emu.run_for(10).unwrap();
+        assert_eq!(emu.pc, 64);
+    }
+}
@@ -0,0 +1,616 @@
+use crate::pmp::{PMP_A, PMP_L, PMP_R, PMP_W, PMP_X};
+
+// Floating-point CSRs
+pub const FFLAGS: u16 = 0x001;
+pub const FRM: u16 = 0x002;
+pub const FCSR: u16 = 0x003;
+
+// Unprivileged counters
+pub const CYCLE: u16 = 0xc00;
+pub const TIME: u16 = 0xc01;
+pub const INSTRET: u16 = 0xc02;
+
+// Machine information registers
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+pub const MHARTID: u16 = 0xf14;
+
+// Supervisor trap setup and handling
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+
+// Supervisor protection and translation
+pub const SATP: u16 = 0x180;
+
+// Machine trap setup and handling
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// Machine memory protection. Only the even pmpcfg registers exist on RV64,
+// each holding the configuration bytes of eight entries.
+pub const PMPCFG0: u16 = 0x3a0;
+pub const PMPCFG14: u16 = 0x3ae;
+pub const PMPADDR0: u16 = 0x3b0;
+pub const PMPADDR63: u16 = 0x3ef;
+
+// Machine counters
+pub const MCYCLE: u16 = 0xb00;
+pub const MINSTRET: u16 = 0xb02;
+
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+/// mstatus.FS = Initial, so that floating-point instructions can be used
+pub const MSTATUS_FS_INITIAL: u64 = 1 << 13;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM: u64 = 1 << 18;
+pub const MSTATUS_MXR: u64 = 1 << 19;
+pub const MSTATUS_TVM: u64 = 1 << 20;
+pub const MSTATUS_TW: u64 = 1 << 21;
+pub const MSTATUS_TSR: u64 = 1 << 22;
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+// Interrupt bits of mip and mie, numbered by interrupt code
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+/// Fields of mstatus that software can write
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+/// Fields of mstatus visible through sstatus
+const SSTATUS_MASK: u64 = SSTATUS_WRITABLE | MSTATUS_UXL | MSTATUS_SD;
+
+/// Fields of mstatus that software can write through sstatus
+const SSTATUS_WRITABLE: u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR;
+
+/// Exceptions that can be delegated to S-mode: everything except
+/// environment calls from M-mode and the reserved codes
+const MEDELEG_WRITABLE: u64 = 0xb3ff;
+
+/// Interrupts that can be delegated to S-mode: SSIP, STIP and SEIP
+const MIDELEG_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// The supervisor interrupts are pending in mip as written by M-mode
+/// software; the machine interrupts only follow their devices
+const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Every interrupt can be enabled
+const MIE_WRITABLE: u64 = MIP_WRITABLE | MIP_MSIP | MIP_MTIP | MIP_MEIP;
+
+/// satp translation modes: Bare, Sv39, Sv48 and Sv57
+const SATP_MODES: [u64; 4] = [0, 8, 9, 10];
+
+/// pmpaddr holds bits 55:2 of a physical address
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+/// Privilege levels, numbered as in the MPP and SPP fields
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decode a legal MPP or SPP value
+    pub fn from_bits(bits: u64) -> Self {
+        match bits {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+/// misa bit for the extension named by `letter`
+pub(crate) const fn extension(letter: u8) -> u64 {
+    1 << (letter - b'A')
+}
+
+/// MXL = 64, the IMAFDC extensions, and supervisor and user modes
+const MISA_VALUE: u64 = 2 << 62
+    | extension(b'A')
+    | extension(b'C')
+    | extension(b'D')
+    | extension(b'F')
+    | extension(b'I')
+    | extension(b'M')
+    | extension(b'S')
+    | extension(b'U');
+
+#[derive(Debug, PartialEq)]
+pub enum CsrError {
+    /// No CSR is implemented at this address
+    Unimplemented(u16),
+    /// Write to a CSR whose address marks it read-only
+    ReadOnly(u16),
+    /// Access from a privilege level that is not allowed to make it
+    Privileged(u16),
+    /// Access to a floating-point CSR while mstatus.FS is Off
+    FloatDisabled(u16),
+}
+
+pub struct CsrFile {
+    csrs: [u64; 4096],
+    /// Floating-point control and status: frm in bits 7:5, fflags in 4:0.
+    /// Kept separately so the soft-float routines can accrue flags into it.
+    pub fcsr: u32,
+    /// Interrupt-pending bits driven by devices, which mip reads as ORed
+    /// with the bits software has written
+    interrupt_lines: u64,
+    /// Whether mtvec and stvec have been written since reset: until they
+    /// are, traps to M-mode and S-mode have no handler to go to
+    mtvec_set: bool,
+    stvec_set: bool,
+}
+
+impl CsrFile {
+    pub fn new(hartid: u64) -> Self {
+        let mut csrs = [0; 4096];
+        csrs[MISA as usize] = MISA_VALUE;
+        csrs[MHARTID as usize] = hartid;
+        // UXL and SXL are fixed at 64 bits
+        csrs[MSTATUS as usize] = 2 << 32 | 2 << 34;
+        Self {
+            csrs,
+            fcsr: 0,
+            interrupt_lines: 0,
+            mtvec_set: false,
+            stvec_set: false,
+        }
+    }
+
+    /// Check that `privilege` may access the CSR at `address`
+    fn check_access(&self, address: u16, privilege: Privilege) -> Result<(), CsrError> {
+        // Bits 9:8 of the address give the lowest privilege level allowed
+        if (address >> 8 & 0b11) as u8 > privilege as u8 {
+            return Err(CsrError::Privileged(address));
+        }
+        match address {
+            CYCLE | TIME | INSTRET => {
+                let bit = 1 << (address - CYCLE);
+                let mcounteren = self.csrs[MCOUNTEREN as usize];
+                let scounteren = self.csrs[SCOUNTEREN as usize];
+                if privilege < Privilege::Machine && mcounteren & bit == 0
+                    || privilege < Privilege::Supervisor && scounteren & bit == 0
+                {
+                    return Err(CsrError::Privileged(address));
+                }
+            }
+            SATP => {
+                let mstatus = self.csrs[MSTATUS as usize];
+                if privilege == Privilege::Supervisor && mstatus & MSTATUS_TVM != 0 {
+                    return Err(CsrError::Privileged(address));
+                }
+            }
+            FFLAGS | FRM | FCSR if !self.float_enabled() => {
+                return Err(CsrError::FloatDisabled(address));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, address: u16, privilege: Privilege) -> Result<u64, CsrError> {
+        self.check_access(address, privilege)?;
+        match address {
+            FFLAGS => Ok((self.fcsr & 0x1f) as u64),
+            FRM => Ok((self.fcsr >> 5 & 0x7) as u64),
+            FCSR => Ok((self.fcsr & 0xff) as u64),
+            CYCLE => Ok(self.csrs[MCYCLE as usize]),
+            INSTRET => Ok(self.csrs[MINSTRET as usize]),
+            MSTATUS => Ok(self.mstatus()),
+            SSTATUS => Ok(self.mstatus() & SSTATUS_MASK),
+            MIP => Ok(self.mip()),
+            // S-mode sees the interrupts delegated to it
+            SIP => Ok(self.mip() & self.csrs[MIDELEG as usize]),
+            SIE => Ok(self.csrs[MIE as usize] & self.csrs[MIDELEG as usize]),
+            TIME | STVEC | SCOUNTEREN | SSCRATCH | SEPC | SCAUSE | STVAL | SATP | MVENDORID
+            | MARCHID | MIMPID | MHARTID | MISA | MEDELEG | MIDELEG | MTVEC | MCOUNTEREN | MIE
+            | MSCRATCH | MEPC | MCAUSE | MTVAL | MCYCLE | MINSTRET => {
+                Ok(self.csrs[address as usize])
+            }
+            PMPCFG0..=PMPCFG14 if address.is_multiple_of(2) => Ok(self.csrs[address as usize]),
+            PMPADDR0..=PMPADDR63 => Ok(self.csrs[address as usize]),
+            _ => Err(CsrError::Unimplemented(address)),
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        address: u16,
+        value: u64,
+        privilege: Privilege,
+    ) -> Result<(), CsrError> {
+        // The top two address bits being set marks a CSR read-only
+        if address >> 10 == 0b11 {
+            return match self.read(address, privilege) {
+                Ok(_) => Err(CsrError::ReadOnly(address)),
+                Err(error) => Err(error),
+            };
+        }
+        self.check_access(address, privilege)?;
+        match address {
+            FFLAGS => {
+                self.fcsr = self.fcsr & !0x1f | (value & 0x1f) as u32;
+                self.mark_fs_dirty();
+            }
+            FRM => {
+                self.fcsr = self.fcsr & 0x1f | ((value & 0x7) as u32) << 5;
+                self.mark_fs_dirty();
+            }
+            FCSR => {
+                self.fcsr = (value & 0xff) as u32;
+                self.mark_fs_dirty();
+            }
+            MSTATUS => self.write_mstatus(value, MSTATUS_WRITABLE),
+            SSTATUS => self.write_mstatus(value, SSTATUS_WRITABLE),
+            // WARL: the set of extensions cannot be changed
+            MISA => {}
+            MEDELEG => self.csrs[MEDELEG as usize] = value & MEDELEG_WRITABLE,
+            MIDELEG => self.csrs[MIDELEG as usize] = value & MIDELEG_WRITABLE,
+            MIE => self.csrs[MIE as usize] = value & MIE_WRITABLE,
+            MIP => self.csrs[MIP as usize] = value & MIP_WRITABLE,
+            // Through sie, S-mode enables only its delegated interrupts, and
+            // through sip it can only clear or raise a delegated SSIP
+            SIE => {
+                let mask = self.csrs[MIDELEG as usize];
+                let mie = self.csrs[MIE as usize];
+                self.csrs[MIE as usize] = mie & !mask | value & mask;
+            }
+            SIP => {
+                let mask = self.csrs[MIDELEG as usize] & MIP_SSIP;
+                let mip = self.csrs[MIP as usize];
+                self.csrs[MIP as usize] = mip & !mask | value & mask;
+            }
+            // WARL: only the direct (0) and vectored (1) modes exist
+            MTVEC | STVEC => self.set(address, value & !0b10),
+            // Only the CY, TM and IR bits exist; there are no hpmcounters
+            MCOUNTEREN | SCOUNTEREN => self.csrs[address as usize] = value & 0b111,
+            // IALIGN is 16, so only bit 0 is always zero
+            MEPC | SEPC => self.csrs[address as usize] = value & !1,
+            // WARL: writes selecting an unsupported mode have no effect
+            SATP => {
+                if SATP_MODES.contains(&(value >> 60)) {
+                    self.csrs[SATP as usize] = value;
+                }
+            }
+            PMPCFG0..=PMPCFG14 if address.is_multiple_of(2) => self.write_pmpcfg(address, value),
+            PMPADDR0..=PMPADDR63 => {
+                let entry = (address - PMPADDR0) as usize;
+                if !self.pmpaddr_locked(entry) {
+                    self.csrs[address as usize] = value & PMPADDR_MASK;
+                }
+            }
+            SSCRATCH | SCAUSE | STVAL | MSCRATCH | MCAUSE | MTVAL | MCYCLE | MINSTRET => {
+                self.csrs[address as usize] = value
+            }
+            _ => return Err(CsrError::Unimplemented(address)),
+        }
+        Ok(())
+    }
+
+    /// mstatus with the SD summary bit filled in
+    fn mstatus(&self) -> u64 {
+        let mstatus = self.csrs[MSTATUS as usize];
+        if mstatus & MSTATUS_FS == MSTATUS_FS {
+            mstatus | MSTATUS_SD
+        } else {
+            mstatus
+        }
+    }
+
+    /// Update the fields of mstatus selected by `mask`
+    fn write_mstatus(&mut self, value: u64, mask: u64) {
+        let mstatus = self.csrs[MSTATUS as usize];
+        let mut value = mstatus & !mask | value & mask;
+        // WARL: MPP keeps its old value if written with the reserved mode 2
+        if value & MSTATUS_MPP == 2 << 11 {
+            value = value & !MSTATUS_MPP | mstatus & MSTATUS_MPP;
+        }
+        self.csrs[MSTATUS as usize] = value;
+    }
+
+    /// Update the configuration bytes held in a pmpcfg register, leaving
+    /// those of locked entries unchanged
+    fn write_pmpcfg(&mut self, address: u16, value: u64) {
+        let first = (address - PMPCFG0) as usize * 4;
+        let mut updated = self.csrs[address as usize];
+        for byte in 0..8 {
+            if self.pmpcfg(first + byte) & PMP_L != 0 {
+                continue;
+            }
+            let mut cfg = (value >> (8 * byte)) as u8 & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R);
+            // WARL: the reserved write-only combination reads back as no access
+            if cfg & (PMP_R | PMP_W) == PMP_W {
+                cfg &= !PMP_W;
+            }
+            updated = updated & !(0xff << (8 * byte)) | (cfg as u64) << (8 * byte);
+        }
+        self.csrs[address as usize] = updated;
+    }
+
+    /// Read a CSR without access checks, for use by the hart itself
+    pub fn get(&self, address: u16) -> u64 {
+        self.csrs[address as usize]
+    }
+
+    /// Write a CSR without access checks or legalization, for use by the
+    /// hart itself with values that are already legal
+    pub fn set(&mut self, address: u16, value: u64) {
+        match address {
+            MTVEC => self.mtvec_set = true,
+            STVEC => self.stvec_set = true,
+            _ => {}
+        }
+        self.csrs[address as usize] = value;
+    }
+
+    /// Whether software has set the trap vector of `privilege`, so that
+    /// traps taken to it have a handler to run
+    pub fn has_trap_handler(&self, privilege: Privilege) -> bool {
+        match privilege {
+            Privilege::Machine => self.mtvec_set,
+            Privilege::Supervisor => self.stvec_set,
+            Privilege::User => false,
+        }
+    }
+
+    /// Pending interrupts: those written by software and those whose
+    /// devices are asserting them
+    pub fn mip(&self) -> u64 {
+        self.csrs[MIP as usize] | self.interrupt_lines
+    }
+
+    /// Set the interrupt-pending bits driven by devices
+    pub fn set_interrupt_lines(&mut self, lines: u64) {
+        self.interrupt_lines = lines;
+    }
+
+    /// Whether the floating-point unit is on, mstatus.FS not being Off
+    pub fn float_enabled(&self) -> bool {
+        self.csrs[MSTATUS as usize] & MSTATUS_FS != 0
+    }
+
+    /// Record a change to the floating-point state, unless it is switched off
+    pub fn mark_fs_dirty(&mut self) {
+        if self.float_enabled() {
+            self.csrs[MSTATUS as usize] |= MSTATUS_FS;
+        }
+    }
+
+    /// Advance the cycle and time counters by one step
+    pub fn tick(&mut self) {
+        for counter in [MCYCLE, TIME] {
+            self.csrs[counter as usize] = self.csrs[counter as usize].wrapping_add(1);
+        }
+    }
+
+    /// Count one retired instruction
+    pub fn retire(&mut self) {
+        self.csrs[MINSTRET as usize] = self.csrs[MINSTRET as usize].wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fcsr_views() {
+        let mut csr = CsrFile::new(0);
+        assert_eq!(
+            csr.read(FCSR, Privilege::Machine),
+            Err(CsrError::FloatDisabled(FCSR))
+        );
+        csr.write(MSTATUS, MSTATUS_FS_INITIAL, Privilege::Machine)
+            .unwrap();
+        csr.write(FCSR, 0xfff, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(FCSR, Privilege::Machine).unwrap(), 0xff);
+        assert_eq!(csr.read(FFLAGS, Privilege::Machine).unwrap(), 0x1f);
+        assert_eq!(csr.read(FRM, Privilege::Machine).unwrap(), 0x7);
+
+        csr.write(FRM, 0b001, Privilege::Machine).unwrap();
+        csr.write(FFLAGS, 0b00100, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(FCSR, Privilege::Machine).unwrap(), 0b001_00100);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut csr = CsrFile::new(3);
+        assert_eq!(csr.read(MHARTID, Privilege::Machine).unwrap(), 3);
+        assert_eq!(
+            csr.write(MHARTID, 0, Privilege::Machine),
+            Err(CsrError::ReadOnly(MHARTID))
+        );
+        assert_eq!(
+            csr.write(CYCLE, 0, Privilege::Machine),
+            Err(CsrError::ReadOnly(CYCLE))
+        );
+        assert_eq!(
+            csr.write(0xfff, 0, Privilege::Machine),
+            Err(CsrError::Unimplemented(0xfff))
+        );
+        assert_eq!(
+            csr.read(0x7c0, Privilege::Machine),
+            Err(CsrError::Unimplemented(0x7c0))
+        );
+    }
+
+    #[test]
+    fn test_warl() {
+        let mut csr = CsrFile::new(0);
+        csr.write(MISA, 0, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(MISA, Privilege::Machine).unwrap(), MISA_VALUE);
+
+        let xl = 2 << 32 | 2 << 34;
+        csr.write(MSTATUS, u64::MAX, Privilege::Machine).unwrap();
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Machine).unwrap(),
+            MSTATUS_WRITABLE | MSTATUS_SD | xl
+        );
+        csr.write(MSTATUS, 2 << 11, Privilege::Machine).unwrap();
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Machine).unwrap(),
+            MSTATUS_MPP | xl
+        );
+        csr.write(MSTATUS, 0, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(MSTATUS, Privilege::Machine).unwrap(), xl);
+
+        csr.write(MEDELEG, u64::MAX, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(MEDELEG, Privilege::Machine).unwrap() >> 11 & 1, 0);
+        csr.write(SATP, 8 << 60 | 1, Privilege::Machine).unwrap();
+        csr.write(SATP, 1 << 60, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(SATP, Privilege::Machine).unwrap(), 8 << 60 | 1);
+
+        csr.write(MTVEC, 0x1003, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(MTVEC, Privilege::Machine).unwrap(), 0x1001);
+        csr.write(MEPC, 0x1003, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(MEPC, Privilege::Machine).unwrap(), 0x1002);
+    }
+
+    #[test]
+    fn test_privilege() {
+        let mut csr = CsrFile::new(0);
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Supervisor),
+            Err(CsrError::Privileged(MSTATUS))
+        );
+        assert_eq!(
+            csr.read(SSTATUS, Privilege::User),
+            Err(CsrError::Privileged(SSTATUS))
+        );
+
+        csr.write(SSTATUS, u64::MAX, Privilege::Supervisor).unwrap();
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Machine).unwrap() & MSTATUS_WRITABLE,
+            SSTATUS_WRITABLE
+        );
+        assert_eq!(
+            csr.read(SSTATUS, Privilege::Supervisor).unwrap() & MSTATUS_MPP,
+            0
+        );
+
+        csr.write(MSTATUS, MSTATUS_TVM, Privilege::Machine).unwrap();
+        assert_eq!(
+            csr.read(SATP, Privilege::Supervisor),
+            Err(CsrError::Privileged(SATP))
+        );
+        assert!(csr.read(SATP, Privilege::Machine).is_ok());
+    }
+
+    #[test]
+    fn test_counter_enable() {
+        let mut csr = CsrFile::new(0);
+        assert_eq!(
+            csr.read(TIME, Privilege::Supervisor),
+            Err(CsrError::Privileged(TIME))
+        );
+        csr.write(MCOUNTEREN, 0b010, Privilege::Machine).unwrap();
+        assert!(csr.read(TIME, Privilege::Supervisor).is_ok());
+        assert_eq!(
+            csr.read(TIME, Privilege::User),
+            Err(CsrError::Privileged(TIME))
+        );
+        csr.write(SCOUNTEREN, 0b010, Privilege::Supervisor).unwrap();
+        assert!(csr.read(TIME, Privilege::User).is_ok());
+        assert!(csr.read(CYCLE, Privilege::User).is_err());
+    }
+
+    #[test]
+    fn test_fs_dirty() {
+        let mut csr = CsrFile::new(0);
+        csr.mark_fs_dirty();
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Machine).unwrap() & MSTATUS_FS,
+            0
+        );
+
+        csr.write(MSTATUS, 1 << 13, Privilege::Machine).unwrap(); // FS = Initial
+        csr.write(FFLAGS, 1, Privilege::Machine).unwrap();
+        assert_eq!(
+            csr.read(MSTATUS, Privilege::Machine).unwrap() & MSTATUS_FS,
+            MSTATUS_FS
+        );
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut csr = CsrFile::new(0);
+        csr.tick();
+        csr.tick();
+        csr.retire();
+        assert_eq!(csr.read(CYCLE, Privilege::Machine).unwrap(), 2);
+        assert_eq!(csr.read(INSTRET, Privilege::Machine).unwrap(), 1);
+        csr.write(MINSTRET, 10, Privilege::Machine).unwrap();
+        assert_eq!(csr.read(INSTRET, Privilege::Machine).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_interrupt_registers() {
+        let mut csr = CsrFile::new(0);
+        let m = Privilege::Machine;
+        let s = Privilege::Supervisor;
+        csr.write(MIE, u64::MAX, m).unwrap();
+        assert_eq!(csr.read(MIE, m).unwrap(), 0xaaa);
+        // Only the supervisor bits of mip are writable, and device lines
+        // read as pending alongside them
+        csr.write(MIP, u64::MAX, m).unwrap();
+        csr.set_interrupt_lines(MIP_MTIP | MIP_SEIP);
+        assert_eq!(csr.read(MIP, m).unwrap(), 0x2a2);
+        csr.write(MIP, 0, m).unwrap();
+        assert_eq!(csr.read(MIP, m).unwrap(), MIP_MTIP | MIP_SEIP);
+
+        // sie and sip are restricted to the delegated interrupts
+        assert_eq!(csr.read(SIE, s).unwrap(), 0);
+        assert_eq!(csr.read(SIP, s).unwrap(), 0);
+        csr.write(MIDELEG, MIP_SSIP | MIP_SEIP, m).unwrap();
+        assert_eq!(csr.read(SIE, s).unwrap(), MIP_SSIP | MIP_SEIP);
+        assert_eq!(csr.read(SIP, s).unwrap(), MIP_SEIP);
+        csr.write(SIE, 0, s).unwrap();
+        assert_eq!(csr.read(MIE, m).unwrap(), 0x8a8);
+        csr.write(SIP, u64::MAX, s).unwrap();
+        assert_eq!(csr.read(MIP, m).unwrap(), MIP_SSIP | MIP_MTIP | MIP_SEIP);
+        assert!(csr.read(MIP, s).is_err());
+    }
+}
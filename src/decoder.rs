@@ -1,11 +1,17 @@
 use crate::instruction::Instruction;
-use crate::types::{Btype, Itype, Jtype, Rtype, Stype, Utype};
+use crate::types::{Atype, Btype, Itype, Jtype, R4type, Rtype, Stype, Utype};
 
 #[derive(Debug)]
 pub enum DecodingError {
     Unsupported,
 }
 
+/// The rm encodings 0b101 and 0b110 are reserved and must be rejected at
+/// decode time; 0b111 (dynamic) is checked against frm at execution time.
+fn valid_rounding_mode(rm: u32) -> bool {
+    rm != 0b101 && rm != 0b110
+}
+
 pub fn decode_instruction(inst: u32) -> Result<Instruction, DecodingError> {
     let opcode = inst & 0x7f;
     match opcode & 0b11 {
@@ -17,20 +23,29 @@ pub fn decode_instruction(inst: u32) -> Result<Instruction, DecodingError> {
                     0b000 => Ok(Instruction::Lb(inst)),
                     0b001 => Ok(Instruction::Lh(inst)),
                     0b010 => Ok(Instruction::Lw(inst)),
+                    0b011 => Ok(Instruction::Ld(inst)),
                     0b100 => Ok(Instruction::Lbu(inst)),
                     0b101 => Ok(Instruction::Lhu(inst)),
+                    0b110 => Ok(Instruction::Lwu(inst)),
                     _ => Err(DecodingError::Unsupported),
                 }
             }
             0b00001 => {
                 // LOAD-FP
-                println!("unsupported opcode LOAD-FP 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = Itype::from(inst);
+                match inst.funct3 {
+                    0b010 => Ok(Instruction::Flw(inst)),
+                    0b011 => Ok(Instruction::Fld(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b00011 => {
                 // MISC-MEM
-                println!("unsupported opcode MISC-MEM 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                match (inst >> 12) & 0b111 {
+                    0b000 => Ok(Instruction::Fence),
+                    0b001 => Ok(Instruction::FenceI),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b00100 => {
                 // OP-IMM
@@ -60,19 +75,17 @@ pub fn decode_instruction(inst: u32) -> Result<Instruction, DecodingError> {
                         // ANDI
                         Ok(Instruction::Andi(inst))
                     }
-                    0b001 => {
-                        // SLLI
-                        Ok(Instruction::Slli(inst))
-                    }
-                    0b101 => match (inst.imm >> 10) & 0b1 {
-                        0 => Ok(Instruction::Srli(inst)),
-                        1 => Ok(Instruction::Srai(inst)),
-                        _ => unreachable!(),
+                    0b001 => match (inst.imm >> 6) & 0b111111 {
+                        // SLLI (RV64 uses a 6-bit shamt and funct6)
+                        0b000000 => Ok(Instruction::Slli(inst)),
+                        _ => Err(DecodingError::Unsupported),
                     },
-                    _ => {
-                        println!("Unsupported funct3 {:#02x} in OP-IMM inst", inst.funct3);
-                        Err(DecodingError::Unsupported)
-                    }
+                    0b101 => match (inst.imm >> 6) & 0b111111 {
+                        0b000000 => Ok(Instruction::Srli(inst)),
+                        0b010000 => Ok(Instruction::Srai(inst)),
+                        _ => Err(DecodingError::Unsupported),
+                    },
+                    _ => Err(DecodingError::Unsupported),
                 }
             }
             0b00101 => {
@@ -82,8 +95,20 @@ pub fn decode_instruction(inst: u32) -> Result<Instruction, DecodingError> {
             }
             0b00110 => {
                 // OP-IMM-32
-                println!("unsupported opcode OP-IMM-32 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = Itype::from(inst);
+                match inst.funct3 {
+                    0b000 => Ok(Instruction::Addiw(inst)),
+                    0b001 => match (inst.imm >> 5) & 0b1111111 {
+                        0b0000000 => Ok(Instruction::Slliw(inst)),
+                        _ => Err(DecodingError::Unsupported),
+                    },
+                    0b101 => match (inst.imm >> 5) & 0b1111111 {
+                        0b0000000 => Ok(Instruction::Srliw(inst)),
+                        0b0100000 => Ok(Instruction::Sraiw(inst)),
+                        _ => Err(DecodingError::Unsupported),
+                    },
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b01000 => {
                 // STORE
@@ -92,43 +117,72 @@ pub fn decode_instruction(inst: u32) -> Result<Instruction, DecodingError> {
                     0b000 => Ok(Instruction::Sb(inst)),
                     0b001 => Ok(Instruction::Sh(inst)),
                     0b010 => Ok(Instruction::Sw(inst)),
+                    0b011 => Ok(Instruction::Sd(inst)),
                     _ => Err(DecodingError::Unsupported),
                 }
             }
             0b01001 => {
                 // STORE-FP
-                println!("unsupported opcode STORE-FP 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = Stype::from(inst);
+                match inst.funct3 {
+                    0b010 => Ok(Instruction::Fsw(inst)),
+                    0b011 => Ok(Instruction::Fsd(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b01011 => {
                 // AMO
-                println!("unsupported opcode AMO 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = Atype::from(inst);
+                match (inst.funct3, inst.funct5) {
+                    (0b010, 0b00010) if inst.rs2 == 0 => Ok(Instruction::LrW(inst)),
+                    (0b010, 0b00011) => Ok(Instruction::ScW(inst)),
+                    (0b010, 0b00001) => Ok(Instruction::AmoswapW(inst)),
+                    (0b010, 0b00000) => Ok(Instruction::AmoaddW(inst)),
+                    (0b010, 0b00100) => Ok(Instruction::AmoxorW(inst)),
+                    (0b010, 0b01100) => Ok(Instruction::AmoandW(inst)),
+                    (0b010, 0b01000) => Ok(Instruction::AmoorW(inst)),
+                    (0b010, 0b10000) => Ok(Instruction::AmominW(inst)),
+                    (0b010, 0b10100) => Ok(Instruction::AmomaxW(inst)),
+                    (0b010, 0b11000) => Ok(Instruction::AmominuW(inst)),
+                    (0b010, 0b11100) => Ok(Instruction::AmomaxuW(inst)),
+                    (0b011, 0b00010) if inst.rs2 == 0 => Ok(Instruction::LrD(inst)),
+                    (0b011, 0b00011) => Ok(Instruction::ScD(inst)),
+                    (0b011, 0b00001) => Ok(Instruction::AmoswapD(inst)),
+                    (0b011, 0b00000) => Ok(Instruction::AmoaddD(inst)),
+                    (0b011, 0b00100) => Ok(Instruction::AmoxorD(inst)),
+                    (0b011, 0b01100) => Ok(Instruction::AmoandD(inst)),
+                    (0b011, 0b01000) => Ok(Instruction::AmoorD(inst)),
+                    (0b011, 0b10000) => Ok(Instruction::AmominD(inst)),
+                    (0b011, 0b10100) => Ok(Instruction::AmomaxD(inst)),
+                    (0b011, 0b11000) => Ok(Instruction::AmominuD(inst)),
+                    (0b011, 0b11100) => Ok(Instruction::AmomaxuD(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b01100 => {
                 // OP
                 let inst = Rtype::from(inst);
-                match inst.funct3 {
-                    0b000 => match (inst.funct7 >> 6) & 0b1 {
-                        0 => Ok(Instruction::Add(inst)),
-                        1 => Ok(Instruction::Sub(inst)),
-                        _ => unreachable!(),
-                    },
-                    0b001 => Ok(Instruction::Sll(inst)),
-                    0b010 => Ok(Instruction::Slt(inst)),
-                    0b011 => Ok(Instruction::Sltu(inst)),
-                    0b100 => Ok(Instruction::Xor(inst)),
-                    0b101 => match (inst.funct7 >> 6) & 0b1 {
-                        0 => Ok(Instruction::Srl(inst)),
-                        1 => Ok(Instruction::Sra(inst)),
-                        _ => unreachable!(),
-                    },
-                    0b110 => Ok(Instruction::Or(inst)),
-                    0b111 => Ok(Instruction::And(inst)),
-                    _ => {
-                        println!("Unsupported funct3 {:#02x} in OP inst", inst.funct3);
-                        Err(DecodingError::Unsupported)
-                    }
+                match (inst.funct3, inst.funct7) {
+                    (0b000, 0b0000000) => Ok(Instruction::Add(inst)),
+                    (0b000, 0b0100000) => Ok(Instruction::Sub(inst)),
+                    (0b001, 0b0000000) => Ok(Instruction::Sll(inst)),
+                    (0b010, 0b0000000) => Ok(Instruction::Slt(inst)),
+                    (0b011, 0b0000000) => Ok(Instruction::Sltu(inst)),
+                    (0b100, 0b0000000) => Ok(Instruction::Xor(inst)),
+                    (0b101, 0b0000000) => Ok(Instruction::Srl(inst)),
+                    (0b101, 0b0100000) => Ok(Instruction::Sra(inst)),
+                    (0b110, 0b0000000) => Ok(Instruction::Or(inst)),
+                    (0b111, 0b0000000) => Ok(Instruction::And(inst)),
+                    // RV64M
+                    (0b000, 0b0000001) => Ok(Instruction::Mul(inst)),
+                    (0b001, 0b0000001) => Ok(Instruction::Mulh(inst)),
+                    (0b010, 0b0000001) => Ok(Instruction::Mulhsu(inst)),
+                    (0b011, 0b0000001) => Ok(Instruction::Mulhu(inst)),
+                    (0b100, 0b0000001) => Ok(Instruction::Div(inst)),
+                    (0b101, 0b0000001) => Ok(Instruction::Divu(inst)),
+                    (0b110, 0b0000001) => Ok(Instruction::Rem(inst)),
+                    (0b111, 0b0000001) => Ok(Instruction::Remu(inst)),
+                    _ => Err(DecodingError::Unsupported),
                 }
             }
             0b01101 => {
@@ -138,33 +192,115 @@ pub fn decode_instruction(inst: u32) -> Result<Instruction, DecodingError> {
             }
             0b01110 => {
                 // OP-32
-                println!("unsupported opcode OP-32 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = Rtype::from(inst);
+                match (inst.funct3, inst.funct7) {
+                    (0b000, 0b0000000) => Ok(Instruction::Addw(inst)),
+                    (0b000, 0b0100000) => Ok(Instruction::Subw(inst)),
+                    (0b001, 0b0000000) => Ok(Instruction::Sllw(inst)),
+                    (0b101, 0b0000000) => Ok(Instruction::Srlw(inst)),
+                    (0b101, 0b0100000) => Ok(Instruction::Sraw(inst)),
+                    // RV64M
+                    (0b000, 0b0000001) => Ok(Instruction::Mulw(inst)),
+                    (0b100, 0b0000001) => Ok(Instruction::Divw(inst)),
+                    (0b101, 0b0000001) => Ok(Instruction::Divuw(inst)),
+                    (0b110, 0b0000001) => Ok(Instruction::Remw(inst)),
+                    (0b111, 0b0000001) => Ok(Instruction::Remuw(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b10000 => {
                 // MADD
-                println!("unsupported opcode MADD 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = R4type::from(inst);
+                match inst.funct2 {
+                    0b00 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FmaddS(inst)),
+                    0b01 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FmaddD(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b10001 => {
                 // MSUB
-                println!("unsupported opcode MSUB 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = R4type::from(inst);
+                match inst.funct2 {
+                    0b00 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FmsubS(inst)),
+                    0b01 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FmsubD(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b10010 => {
                 // NMSUB
-                println!("unsupported opcode NMSUB 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = R4type::from(inst);
+                match inst.funct2 {
+                    0b00 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FnmsubS(inst)),
+                    0b01 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FnmsubD(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b10011 => {
                 // NMADD
-                println!("unsupported opcode NMADD 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = R4type::from(inst);
+                match inst.funct2 {
+                    0b00 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FnmaddS(inst)),
+                    0b01 if valid_rounding_mode(inst.funct3) => Ok(Instruction::FnmaddD(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b10100 => {
                 // OP-FP
-                println!("unsupported opcode OP-FP 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let inst = Rtype::from(inst);
+                let rm_ok = valid_rounding_mode(inst.funct3);
+                match (inst.funct7, inst.rs2, inst.funct3) {
+                    (0b0000000, _, _) if rm_ok => Ok(Instruction::FaddS(inst)),
+                    (0b0000001, _, _) if rm_ok => Ok(Instruction::FaddD(inst)),
+                    (0b0000100, _, _) if rm_ok => Ok(Instruction::FsubS(inst)),
+                    (0b0000101, _, _) if rm_ok => Ok(Instruction::FsubD(inst)),
+                    (0b0001000, _, _) if rm_ok => Ok(Instruction::FmulS(inst)),
+                    (0b0001001, _, _) if rm_ok => Ok(Instruction::FmulD(inst)),
+                    (0b0001100, _, _) if rm_ok => Ok(Instruction::FdivS(inst)),
+                    (0b0001101, _, _) if rm_ok => Ok(Instruction::FdivD(inst)),
+                    (0b0101100, 0, _) if rm_ok => Ok(Instruction::FsqrtS(inst)),
+                    (0b0101101, 0, _) if rm_ok => Ok(Instruction::FsqrtD(inst)),
+                    (0b0010000, _, 0b000) => Ok(Instruction::FsgnjS(inst)),
+                    (0b0010000, _, 0b001) => Ok(Instruction::FsgnjnS(inst)),
+                    (0b0010000, _, 0b010) => Ok(Instruction::FsgnjxS(inst)),
+                    (0b0010001, _, 0b000) => Ok(Instruction::FsgnjD(inst)),
+                    (0b0010001, _, 0b001) => Ok(Instruction::FsgnjnD(inst)),
+                    (0b0010001, _, 0b010) => Ok(Instruction::FsgnjxD(inst)),
+                    (0b0010100, _, 0b000) => Ok(Instruction::FminS(inst)),
+                    (0b0010100, _, 0b001) => Ok(Instruction::FmaxS(inst)),
+                    (0b0010101, _, 0b000) => Ok(Instruction::FminD(inst)),
+                    (0b0010101, _, 0b001) => Ok(Instruction::FmaxD(inst)),
+                    (0b0100000, 1, _) if rm_ok => Ok(Instruction::FcvtSD(inst)),
+                    (0b0100001, 0, _) if rm_ok => Ok(Instruction::FcvtDS(inst)),
+                    (0b1010000, _, 0b010) => Ok(Instruction::FeqS(inst)),
+                    (0b1010000, _, 0b001) => Ok(Instruction::FltS(inst)),
+                    (0b1010000, _, 0b000) => Ok(Instruction::FleS(inst)),
+                    (0b1010001, _, 0b010) => Ok(Instruction::FeqD(inst)),
+                    (0b1010001, _, 0b001) => Ok(Instruction::FltD(inst)),
+                    (0b1010001, _, 0b000) => Ok(Instruction::FleD(inst)),
+                    (0b1100000, 0, _) if rm_ok => Ok(Instruction::FcvtWS(inst)),
+                    (0b1100000, 1, _) if rm_ok => Ok(Instruction::FcvtWuS(inst)),
+                    (0b1100000, 2, _) if rm_ok => Ok(Instruction::FcvtLS(inst)),
+                    (0b1100000, 3, _) if rm_ok => Ok(Instruction::FcvtLuS(inst)),
+                    (0b1100001, 0, _) if rm_ok => Ok(Instruction::FcvtWD(inst)),
+                    (0b1100001, 1, _) if rm_ok => Ok(Instruction::FcvtWuD(inst)),
+                    (0b1100001, 2, _) if rm_ok => Ok(Instruction::FcvtLD(inst)),
+                    (0b1100001, 3, _) if rm_ok => Ok(Instruction::FcvtLuD(inst)),
+                    (0b1101000, 0, _) if rm_ok => Ok(Instruction::FcvtSW(inst)),
+                    (0b1101000, 1, _) if rm_ok => Ok(Instruction::FcvtSWu(inst)),
+                    (0b1101000, 2, _) if rm_ok => Ok(Instruction::FcvtSL(inst)),
+                    (0b1101000, 3, _) if rm_ok => Ok(Instruction::FcvtSLu(inst)),
+                    (0b1101001, 0, _) if rm_ok => Ok(Instruction::FcvtDW(inst)),
+                    (0b1101001, 1, _) if rm_ok => Ok(Instruction::FcvtDWu(inst)),
+                    (0b1101001, 2, _) if rm_ok => Ok(Instruction::FcvtDL(inst)),
+                    (0b1101001, 3, _) if rm_ok => Ok(Instruction::FcvtDLu(inst)),
+                    (0b1110000, 0, 0b000) => Ok(Instruction::FmvXW(inst)),
+                    (0b1110000, 0, 0b001) => Ok(Instruction::FclassS(inst)),
+                    (0b1110001, 0, 0b000) => Ok(Instruction::FmvXD(inst)),
+                    (0b1110001, 0, 0b001) => Ok(Instruction::FclassD(inst)),
+                    (0b1111000, 0, 0b000) => Ok(Instruction::FmvWX(inst)),
+                    (0b1111001, 0, 0b000) => Ok(Instruction::FmvDX(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
             0b11000 => {
                 // BRANCH
@@ -196,18 +332,276 @@ pub fn decode_instruction(inst: u32) -> Result<Instruction, DecodingError> {
             }
             0b11100 => {
                 // SYSTEM
-                println!("unsupported opcode SYSTEM 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+                let raw = inst;
+                let inst = Itype::from(inst);
+                match inst.funct3 {
+                    0b000 if raw >> 25 == 0b0001001 && inst.rd == 0 => {
+                        Ok(Instruction::SfenceVma(Rtype::from(raw)))
+                    }
+                    0b000 => match raw {
+                        0x00000073 => Ok(Instruction::Ecall),
+                        0x00100073 => Ok(Instruction::Ebreak),
+                        0x10200073 => Ok(Instruction::Sret),
+                        0x10500073 => Ok(Instruction::Wfi),
+                        0x30200073 => Ok(Instruction::Mret),
+                        _ => Err(DecodingError::Unsupported),
+                    },
+                    0b001 => Ok(Instruction::Csrrw(inst)),
+                    0b010 => Ok(Instruction::Csrrs(inst)),
+                    0b011 => Ok(Instruction::Csrrc(inst)),
+                    0b101 => Ok(Instruction::Csrrwi(inst)),
+                    0b110 => Ok(Instruction::Csrrsi(inst)),
+                    0b111 => Ok(Instruction::Csrrci(inst)),
+                    _ => Err(DecodingError::Unsupported),
+                }
             }
-            _ => {
-                println!("unsupported instruction 0x{:08x}", inst);
-                Err(DecodingError::Unsupported)
+            _ => Err(DecodingError::Unsupported),
+        },
+        _ => decode_compressed(inst & 0xffff),
+    }
+}
+
+/// Extract bits `hi..=lo` of `inst`, shifted down to bit 0
+fn bits(inst: u32, hi: u32, lo: u32) -> u32 {
+    (inst >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Sign-extend the low `width` bits of `value`
+fn sign_extend(value: u32, width: u32) -> i32 {
+    ((value << (32 - width)) as i32) >> (32 - width)
+}
+
+/// Map a 3-bit compressed register field to x8-x15
+fn creg(field: u32) -> usize {
+    (field + 8) as usize
+}
+
+/// Expand a 16-bit RVC instruction into its 32-bit equivalent
+fn decode_compressed(inst: u32) -> Result<Instruction, DecodingError> {
+    let funct3 = bits(inst, 15, 13);
+    // CI-format immediate shared by c.addi, c.li, c.andi and the shifts
+    let imm6 = bits(inst, 12, 12) << 5 | bits(inst, 6, 2);
+    let rd = bits(inst, 11, 7) as usize;
+    let rs2 = bits(inst, 6, 2) as usize;
+    let rd_prime = creg(bits(inst, 4, 2));
+    let rs1_prime = creg(bits(inst, 9, 7));
+    // Offsets of the CL/CS formats, scaled for word and doubleword accesses
+    let offset_w = (bits(inst, 12, 10) << 3 | bits(inst, 6, 6) << 2 | bits(inst, 5, 5) << 6) as i32;
+    let offset_d = (bits(inst, 12, 10) << 3 | bits(inst, 6, 5) << 6) as i32;
+    // Offsets of the stack-pointer-relative CI/CSS formats
+    let lwsp = (bits(inst, 12, 12) << 5 | bits(inst, 6, 4) << 2 | bits(inst, 3, 2) << 6) as i32;
+    let ldsp = (bits(inst, 12, 12) << 5 | bits(inst, 6, 5) << 3 | bits(inst, 4, 2) << 6) as i32;
+    let swsp = (bits(inst, 12, 9) << 2 | bits(inst, 8, 7) << 6) as i32;
+    let sdsp = (bits(inst, 12, 10) << 3 | bits(inst, 9, 7) << 6) as i32;
+
+    let itype = |funct3, rd, rs1, imm| Itype {
+        rd,
+        funct3,
+        rs1,
+        imm,
+    };
+    let stype = |funct3, rs1, rs2, imm| Stype {
+        imm,
+        funct3,
+        rs1,
+        rs2,
+    };
+    let rtype = |funct3, funct7, rd, rs1, rs2| Rtype {
+        rd,
+        funct3,
+        rs1,
+        rs2,
+        funct7,
+    };
+
+    match (inst & 0b11, funct3) {
+        (0b00, 0b000) => {
+            // C.ADDI4SPN
+            let imm = bits(inst, 12, 11) << 4
+                | bits(inst, 10, 7) << 6
+                | bits(inst, 6, 6) << 2
+                | bits(inst, 5, 5) << 3;
+            if imm == 0 {
+                // Also covers the all-zero illegal instruction
+                return Err(DecodingError::Unsupported);
+            }
+            Ok(Instruction::Addi(itype(0b000, rd_prime, 2, imm as i32)))
+        }
+        (0b00, 0b001) => Ok(Instruction::Fld(itype(
+            0b011, rd_prime, rs1_prime, offset_d,
+        ))), // C.FLD
+        (0b00, 0b010) => Ok(Instruction::Lw(itype(0b010, rd_prime, rs1_prime, offset_w))), // C.LW
+        (0b00, 0b011) => Ok(Instruction::Ld(itype(0b011, rd_prime, rs1_prime, offset_d))), // C.LD
+        (0b00, 0b101) => Ok(Instruction::Fsd(stype(
+            0b011, rs1_prime, rd_prime, offset_d,
+        ))), // C.FSD
+        (0b00, 0b110) => Ok(Instruction::Sw(stype(0b010, rs1_prime, rd_prime, offset_w))), // C.SW
+        (0b00, 0b111) => Ok(Instruction::Sd(stype(0b011, rs1_prime, rd_prime, offset_d))), // C.SD
+        (0b01, 0b000) => {
+            // C.ADDI (C.NOP when rd is x0)
+            Ok(Instruction::Addi(itype(
+                0b000,
+                rd,
+                rd,
+                sign_extend(imm6, 6),
+            )))
+        }
+        (0b01, 0b001) if rd != 0 => {
+            // C.ADDIW
+            Ok(Instruction::Addiw(itype(
+                0b000,
+                rd,
+                rd,
+                sign_extend(imm6, 6),
+            )))
+        }
+        (0b01, 0b010) => {
+            // C.LI
+            Ok(Instruction::Addi(itype(0b000, rd, 0, sign_extend(imm6, 6))))
+        }
+        (0b01, 0b011) if rd == 2 => {
+            // C.ADDI16SP
+            let imm = bits(inst, 12, 12) << 9
+                | bits(inst, 6, 6) << 4
+                | bits(inst, 5, 5) << 6
+                | bits(inst, 4, 3) << 7
+                | bits(inst, 2, 2) << 5;
+            if imm == 0 {
+                return Err(DecodingError::Unsupported);
             }
+            Ok(Instruction::Addi(itype(0b000, 2, 2, sign_extend(imm, 10))))
+        }
+        (0b01, 0b011) if imm6 != 0 => {
+            // C.LUI
+            Ok(Instruction::Lui(Utype {
+                rd,
+                imm: sign_extend(imm6, 6) << 12,
+            }))
+        }
+        (0b01, 0b100) => {
+            let rd = rs1_prime;
+            match (bits(inst, 11, 10), bits(inst, 12, 12), bits(inst, 6, 5)) {
+                // C.SRLI
+                (0b00, _, _) => Ok(Instruction::Srli(itype(0b101, rd, rd, imm6 as i32))),
+                // C.SRAI
+                (0b01, _, _) => Ok(Instruction::Srai(itype(
+                    0b101,
+                    rd,
+                    rd,
+                    (0b010000 << 6 | imm6) as i32,
+                ))),
+                // C.ANDI
+                (0b10, _, _) => Ok(Instruction::Andi(itype(
+                    0b111,
+                    rd,
+                    rd,
+                    sign_extend(imm6, 6),
+                ))),
+                // C.SUB
+                (0b11, 0, 0b00) => Ok(Instruction::Sub(rtype(0b000, 0b0100000, rd, rd, rd_prime))),
+                // C.XOR
+                (0b11, 0, 0b01) => Ok(Instruction::Xor(rtype(0b100, 0, rd, rd, rd_prime))),
+                // C.OR
+                (0b11, 0, 0b10) => Ok(Instruction::Or(rtype(0b110, 0, rd, rd, rd_prime))),
+                // C.AND
+                (0b11, 0, 0b11) => Ok(Instruction::And(rtype(0b111, 0, rd, rd, rd_prime))),
+                // C.SUBW
+                (0b11, 1, 0b00) => Ok(Instruction::Subw(rtype(0b000, 0b0100000, rd, rd, rd_prime))),
+                // C.ADDW
+                (0b11, 1, 0b01) => Ok(Instruction::Addw(rtype(0b000, 0, rd, rd, rd_prime))),
+                _ => Err(DecodingError::Unsupported),
+            }
+        }
+        (0b01, 0b101) => {
+            // C.J
+            let imm = bits(inst, 12, 12) << 11
+                | bits(inst, 11, 11) << 4
+                | bits(inst, 10, 9) << 8
+                | bits(inst, 8, 8) << 10
+                | bits(inst, 7, 7) << 6
+                | bits(inst, 6, 6) << 7
+                | bits(inst, 5, 3) << 1
+                | bits(inst, 2, 2) << 5;
+            Ok(Instruction::Jal(Jtype {
+                rd: 0,
+                imm: sign_extend(imm, 12),
+            }))
+        }
+        (0b01, 0b110 | 0b111) => {
+            // C.BEQZ, C.BNEZ
+            let imm = bits(inst, 12, 12) << 8
+                | bits(inst, 11, 10) << 3
+                | bits(inst, 6, 5) << 6
+                | bits(inst, 4, 3) << 1
+                | bits(inst, 2, 2) << 5;
+            let inst = Btype {
+                funct3: funct3 & 0b001,
+                rs1: rs1_prime,
+                rs2: 0,
+                imm: sign_extend(imm, 9),
+            };
+            if funct3 == 0b110 {
+                Ok(Instruction::Beq(inst))
+            } else {
+                Ok(Instruction::Bne(inst))
+            }
+        }
+        (0b10, 0b000) => {
+            // C.SLLI
+            Ok(Instruction::Slli(itype(0b001, rd, rd, imm6 as i32)))
+        }
+        (0b10, 0b001) => Ok(Instruction::Fld(itype(0b011, rd, 2, ldsp))), // C.FLDSP
+        (0b10, 0b010) if rd != 0 => Ok(Instruction::Lw(itype(0b010, rd, 2, lwsp))), // C.LWSP
+        (0b10, 0b011) if rd != 0 => Ok(Instruction::Ld(itype(0b011, rd, 2, ldsp))), // C.LDSP
+        (0b10, 0b100) => match (bits(inst, 12, 12), rd, rs2) {
+            // C.JR
+            (0, 1.., 0) => Ok(Instruction::Jalr(itype(0b000, 0, rd, 0))),
+            // C.MV
+            (0, _, 1..) => Ok(Instruction::Add(rtype(0b000, 0, rd, 0, rs2))),
+            // C.EBREAK
+            (1, 0, 0) => Ok(Instruction::Ebreak),
+            // C.JALR
+            (1, _, 0) => Ok(Instruction::Jalr(itype(0b000, 1, rd, 0))),
+            // C.ADD
+            (1, _, _) => Ok(Instruction::Add(rtype(0b000, 0, rd, rd, rs2))),
+            _ => Err(DecodingError::Unsupported),
         },
-        _ => Err(DecodingError::Unsupported), // Compressed instruction
+        (0b10, 0b101) => Ok(Instruction::Fsd(stype(0b011, 2, rs2, sdsp))), // C.FSDSP
+        (0b10, 0b110) => Ok(Instruction::Sw(stype(0b010, 2, rs2, swsp))),  // C.SWSP
+        (0b10, 0b111) => Ok(Instruction::Sd(stype(0b011, 2, rs2, sdsp))),  // C.SDSP
+        _ => Err(DecodingError::Unsupported),
     }
 }
 
+/// Extensions beyond RV64I, in canonical order, each with an instruction
+/// only it provides
+const EXTENSIONS: [(&str, u32); 7] = [
+    ("m", 0x02b50533),        // mul a0,a0,a1
+    ("a", 0x1005252f),        // lr.w a0,(a0)
+    ("f", 0x00052507),        // flw fa0,0(a0)
+    ("d", 0x00053507),        // fld fa0,0(a0)
+    ("c", 0x0505),            // c.addi a0,1
+    ("zicsr", 0x30002573),    // csrr a0,mstatus
+    ("zifencei", 0x0000100f), // fence.i
+];
+
+/// The extensions the decoder supports, by name
+pub(crate) fn extensions() -> impl Iterator<Item = &'static str> {
+    EXTENSIONS
+        .iter()
+        .filter(|(_, inst)| decode_instruction(*inst).is_ok())
+        .map(|(name, _)| *name)
+}
+
+/// The ISA string of the instructions the decoder supports, as in
+/// `rv64imafdc_zicsr_zifencei`
+pub(crate) fn isa_string() -> String {
+    extensions().fold("rv64i".to_string(), |isa, name| {
+        let separator = if name.len() > 1 { "_" } else { "" };
+        isa + separator + name
+    })
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -227,4 +621,162 @@ mod test {
             Instruction::Xori(Itype::from(0x00a54693))
         );
     }
+
+    #[test]
+    fn test_isa_string() {
+        assert_eq!(isa_string(), "rv64imafdc_zicsr_zifencei");
+    }
+
+    #[test]
+    fn test_decode_rv64i() {
+        assert_eq!(
+            decode_instruction(0x00813583).unwrap(), // ld a1,8(sp)
+            Instruction::Ld(Itype::from(0x00813583))
+        );
+        assert_eq!(
+            decode_instruction(0x00b13423).unwrap(), // sd a1,8(sp)
+            Instruction::Sd(Stype::from(0x00b13423))
+        );
+        assert_eq!(
+            decode_instruction(0xfff5059b).unwrap(), // addiw a1,a0,-1
+            Instruction::Addiw(Itype::from(0xfff5059b))
+        );
+        assert_eq!(
+            decode_instruction(0x40a006bb).unwrap(), // subw a3,x0,a0
+            Instruction::Subw(Rtype::from(0x40a006bb))
+        );
+        assert_eq!(
+            decode_instruction(0x42455613).unwrap(), // srai a2,a0,36
+            Instruction::Srai(Itype::from(0x42455613))
+        );
+        assert!(decode_instruction(0x0215159b).is_err()); // slliw with shamt[5] set
+    }
+
+    #[test]
+    fn test_decode_op() {
+        assert_eq!(
+            decode_instruction(0x40b50633).unwrap(), // sub a2,a0,a1
+            Instruction::Sub(Rtype::from(0x40b50633))
+        );
+        assert_eq!(
+            decode_instruction(0x02b50633).unwrap(), // mul a2,a0,a1
+            Instruction::Mul(Rtype::from(0x02b50633))
+        );
+        assert_eq!(
+            decode_instruction(0x02b5563b).unwrap(), // divuw a2,a0,a1
+            Instruction::Divuw(Rtype::from(0x02b5563b))
+        );
+        assert!(decode_instruction(0x02b5163b).is_err()); // no mulhw in RV64M
+    }
+
+    #[test]
+    fn test_decode_amo() {
+        assert_eq!(
+            decode_instruction(0x1005a52f).unwrap(), // lr.w a0,(a1)
+            Instruction::LrW(Atype::from(0x1005a52f))
+        );
+        assert_eq!(
+            decode_instruction(0x1ad5b62f).unwrap(), // sc.d.rl a2,a3,(a1)
+            Instruction::ScD(Atype::from(0x1ad5b62f))
+        );
+        assert_eq!(
+            decode_instruction(0xa6a035af).unwrap(), // amomax.d.aqrl a1,a0,(x0)
+            Instruction::AmomaxD(Atype::from(0xa6a035af))
+        );
+        assert!(decode_instruction(0x10d5a52f).is_err()); // lr.w with rs2 != 0
+    }
+
+    #[test]
+    fn test_decode_fp() {
+        assert_eq!(
+            decode_instruction(0x6ac5f543).unwrap(), // fmadd.d fa0,fa1,fa2,fa3
+            Instruction::FmaddD(R4type::from(0x6ac5f543))
+        );
+        assert_eq!(
+            decode_instruction(0x00c5f553).unwrap(), // fadd.s fa0,fa1,fa2
+            Instruction::FaddS(Rtype::from(0x00c5f553))
+        );
+        assert_eq!(
+            decode_instruction(0xc2259553).unwrap(), // fcvt.l.d a0,fa1,rtz
+            Instruction::FcvtLD(Rtype::from(0xc2259553))
+        );
+        assert_eq!(
+            decode_instruction(0xe2058553).unwrap(), // fmv.x.d a0,fa1
+            Instruction::FmvXD(Rtype::from(0xe2058553))
+        );
+        assert!(decode_instruction(0x00c5d553).is_err()); // fadd.s with reserved rm 0b101
+    }
+
+    #[test]
+    fn test_decode_compressed() {
+        // Each compressed encoding must decode to the same instruction as
+        // its 32-bit expansion
+        let pairs = [
+            (0x0808, 0x01010513), // c.addi4spn a0, sp, 16
+            (0x414c, 0x00452583), // c.lw a1, 4(a0)
+            (0xe50c, 0x00b53423), // c.sd a1, 8(a0)
+            (0x2588, 0x0085b507), // c.fld fa0, 8(a1)
+            (0x157d, 0xfff50513), // c.addi a0, -1
+            (0x2505, 0x0015051b), // c.addiw a0, 1
+            (0x757d, 0xfffff537), // c.lui a0, 0xfffff
+            (0x7139, 0xfc010113), // c.addi16sp sp, -64
+            (0x9505, 0x42155513), // c.srai a0, 33
+            (0x9961, 0xff857513), // c.andi a0, -8
+            (0x8d0d, 0x40b50533), // c.sub a0, a1
+            (0x9d0d, 0x40b5053b), // c.subw a0, a1
+            (0xbfed, 0xffbff06f), // c.j -6
+            (0xfd75, 0xfe051ee3), // c.bnez a0, -4
+            (0x1522, 0x02851513), // c.slli a0, 40
+            (0x60e2, 0x01813083), // c.ldsp ra, 24(sp)
+            (0xec06, 0x00113c23), // c.sdsp ra, 24(sp)
+            (0x8082, 0x00008067), // c.jr ra
+            (0x852e, 0x00b00533), // c.mv a0, a1
+            (0x9502, 0x000500e7), // c.jalr a0
+            (0x952e, 0x00b50533), // c.add a0, a1
+            (0xa82a, 0x00a13827), // c.fsdsp fa0, 16(sp)
+            (0x4532, 0x00c12503), // c.lwsp a0, 12(sp)
+        ];
+        for (compressed, expanded) in pairs {
+            assert_eq!(
+                decode_instruction(compressed).unwrap(),
+                decode_instruction(expanded).unwrap()
+            );
+        }
+        assert!(decode_instruction(0x0000).is_err()); // illegal all-zero instruction
+        assert!(decode_instruction(0x6101).is_err()); // c.addi16sp with zero immediate
+        assert!(decode_instruction(0x4002).is_err()); // c.lwsp with rd = x0
+    }
+
+    #[test]
+    fn test_decode_csr() {
+        assert_eq!(
+            decode_instruction(0x340515f3).unwrap(), // csrrw a1,mscratch,a0
+            Instruction::Csrrw(Itype::from(0x340515f3))
+        );
+        assert_eq!(
+            decode_instruction(0xc0202873).unwrap(), // csrrs a6,instret,x0
+            Instruction::Csrrs(Itype::from(0xc0202873))
+        );
+        assert_eq!(
+            decode_instruction(0x0021d073).unwrap(), // csrrwi x0,frm,3
+            Instruction::Csrrwi(Itype::from(0x0021d073))
+        );
+        assert!(decode_instruction(0x3401c073).is_err()); // reserved funct3 0b100
+        assert_eq!(decode_instruction(0x00000073).unwrap(), Instruction::Ecall);
+        assert_eq!(decode_instruction(0x30200073).unwrap(), Instruction::Mret);
+        assert_eq!(decode_instruction(0x10200073).unwrap(), Instruction::Sret);
+        assert_eq!(
+            decode_instruction(0x12b50073).unwrap(), // sfence.vma a0,a1
+            Instruction::SfenceVma(Rtype::from(0x12b50073))
+        );
+        assert_eq!(decode_instruction(0x9002).unwrap(), Instruction::Ebreak); // c.ebreak
+    }
+
+    #[test]
+    fn test_misc_mem() {
+        assert_eq!(decode_instruction(0x0ff0000f).unwrap(), Instruction::Fence); // fence
+        assert_eq!(decode_instruction(0x8330000f).unwrap(), Instruction::Fence); // fence.tso
+        assert_eq!(decode_instruction(0x0000100f).unwrap(), Instruction::FenceI); // fence.i
+        assert!(decode_instruction(0x0000200f).is_err());
+    }
 }
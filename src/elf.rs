@@ -0,0 +1,631 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use crate::emulator::Emulator;
+use crate::htif::Htif;
+use crate::mmu::PAGE_SIZE;
+use crate::symbols::{Symbol, SymbolTable};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+
+// e_ident fields
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_TLS: u32 = 7;
+const PT_GNU_STACK: u32 = 0x6474e551;
+
+/// Most memory the loadable segments may span, from the lowest address to
+/// the highest, since they are loaded into a single block of RAM
+const LOAD_SPAN_MAX: u64 = 1 << 30;
+
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+
+// Symbol types kept in the symbol table
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    /// The image does not start with the ELF magic number
+    NotElf,
+    /// EI_CLASS is neither ELFCLASS32 nor ELFCLASS64
+    InvalidClass(u8),
+    BigEndian,
+    /// e_type is not ET_EXEC
+    NotExecutable(u16),
+    /// e_machine is not EM_RISCV
+    NotRiscv(u16),
+    /// A header or segment extends past the end of the image
+    Truncated,
+    /// A PT_LOAD segment with more bytes in the file than in memory, or
+    /// that wraps around the address space
+    InvalidSegment(u64),
+    NoLoadableSegments,
+    /// The loadable segments span more than `LOAD_SPAN_MAX` bytes
+    SparseSegments(u64),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::InvalidClass(class) => write!(f, "invalid ELF class {}", class),
+            ElfError::BigEndian => write!(f, "big-endian ELF files are not supported"),
+            ElfError::NotExecutable(e_type) => {
+                write!(f, "ELF type {} is not an executable", e_type)
+            }
+            ElfError::NotRiscv(machine) => {
+                write!(f, "ELF machine {} is not RISC-V ({})", machine, EM_RISCV)
+            }
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::InvalidSegment(address) => {
+                write!(f, "invalid loadable segment at 0x{:x}", address)
+            }
+            ElfError::NoLoadableSegments => write!(f, "ELF file has no loadable segments"),
+            ElfError::SparseSegments(span) => write!(
+                f,
+                "loadable segments span 0x{:x} bytes, more than 0x{:x}",
+                span, LOAD_SPAN_MAX
+            ),
+        }
+    }
+}
+
+impl Error for ElfError {}
+
+/// A PT_LOAD segment
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    /// Physical address the segment is loaded at
+    pub address: u64,
+    /// Contents from the file, which are followed by zeroes up to `size`
+    pub data: Vec<u8>,
+    /// Size in memory
+    pub size: u64,
+}
+
+/// A parsed little-endian RISC-V executable, either ELF32 or ELF64
+#[derive(Debug)]
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    /// Virtual address of the program headers, if a PT_LOAD segment maps
+    /// them, with their size and number as passed to Linux programs in auxv
+    pub program_headers: Option<u64>,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+    /// Built by a hosted toolchain for Linux, as marked by the presence of a
+    /// PT_GNU_STACK or PT_TLS program header
+    pub linux: bool,
+    /// Functions, objects and labels from .symtab, empty if stripped
+    pub symbols: SymbolTable,
+}
+
+/// Little-endian reads of fields whose width depends on the ELF class
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+}
+
+impl Reader<'_> {
+    fn bytes(&self, offset: u64, size: u64) -> Result<&[u8], ElfError> {
+        let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+        let size = usize::try_from(size).map_err(|_| ElfError::Truncated)?;
+        let end = start.checked_add(size).ok_or(ElfError::Truncated)?;
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16, ElfError> {
+        let bytes = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32, ElfError> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.bytes(offset, 4)?);
+        Ok(u32::from_le_bytes(value))
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64, ElfError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(offset, 8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    /// An address or offset: 32 bits wide in ELF32 and 64 bits in ELF64
+    fn word(&self, offset: u64) -> Result<u64, ElfError> {
+        if self.is_64 {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        if !data.starts_with(&ELF_MAGIC) {
+            return Err(ElfError::NotElf);
+        }
+        let is_64 = match data.get(4) {
+            Some(&ELFCLASS64) => true,
+            Some(&ELFCLASS32) => false,
+            Some(&class) => return Err(ElfError::InvalidClass(class)),
+            None => return Err(ElfError::Truncated),
+        };
+        match data.get(5) {
+            Some(&ELFDATA2LSB) => {}
+            Some(&ELFDATA2MSB) => return Err(ElfError::BigEndian),
+            _ => return Err(ElfError::NotElf),
+        }
+        let reader = Reader { data, is_64 };
+
+        let machine = reader.u16(18)?;
+        if machine != EM_RISCV {
+            return Err(ElfError::NotRiscv(machine));
+        }
+        let e_type = reader.u16(16)?;
+        if e_type != ET_EXEC {
+            return Err(ElfError::NotExecutable(e_type));
+        }
+
+        let entry = reader.word(24)?;
+        let (phoff, phentsize, phnum) = if is_64 {
+            (reader.u64(32)?, reader.u16(54)?, reader.u16(56)?)
+        } else {
+            (reader.u32(28)? as u64, reader.u16(42)?, reader.u16(44)?)
+        };
+
+        let mut segments = Vec::new();
+        let mut program_headers = None;
+        let mut linux = false;
+        for index in 0..phnum as u64 {
+            let header = phoff
+                .checked_add(index * phentsize as u64)
+                .ok_or(ElfError::Truncated)?;
+            reader.bytes(header, phentsize as u64)?;
+            match reader.u32(header)? {
+                PT_LOAD => {}
+                PT_TLS | PT_GNU_STACK => {
+                    linux = true;
+                    continue;
+                }
+                _ => continue,
+            }
+            // p_offset, p_vaddr, p_paddr, p_filesz and p_memsz
+            let (offset, vaddr, address, file_size, size) = if is_64 {
+                (
+                    reader.u64(header + 8)?,
+                    reader.u64(header + 16)?,
+                    reader.u64(header + 24)?,
+                    reader.u64(header + 32)?,
+                    reader.u64(header + 40)?,
+                )
+            } else {
+                (
+                    reader.word(header + 4)?,
+                    reader.word(header + 8)?,
+                    reader.word(header + 12)?,
+                    reader.word(header + 16)?,
+                    reader.word(header + 20)?,
+                )
+            };
+            // The segment's pages must not wrap around the address space
+            let end = address
+                .checked_add(size)
+                .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE));
+            if file_size > size || end.is_none() {
+                return Err(ElfError::InvalidSegment(address));
+            }
+            if offset <= phoff && phoff - offset < file_size {
+                program_headers = Some(vaddr + (phoff - offset));
+            }
+            segments.push(Segment {
+                address,
+                data: reader.bytes(offset, file_size)?.to_vec(),
+                size,
+            });
+        }
+        if segments.iter().all(|segment| segment.size == 0) {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        let symbols = SymbolTable::new(Self::parse_symbols(&reader)?);
+        let elf = Self {
+            entry,
+            segments,
+            program_headers,
+            program_header_size: phentsize,
+            program_header_count: phnum,
+            linux,
+            symbols,
+        };
+        let (start, end) = elf.load_range();
+        if end - start > LOAD_SPAN_MAX {
+            return Err(ElfError::SparseSegments(end - start));
+        }
+        Ok(elf)
+    }
+
+    /// The pages the loadable segments occupy, from the start of the first
+    /// to the end of the last
+    fn load_range(&self) -> (u64, u64) {
+        let segments = self.segments.iter().filter(|segment| segment.size > 0);
+        let start = segments
+            .clone()
+            .map(|segment| segment.address)
+            .min()
+            .unwrap_or(0)
+            / PAGE_SIZE
+            * PAGE_SIZE;
+        let end = segments
+            .map(|segment| segment.address + segment.size)
+            .max()
+            .unwrap_or(0);
+        (start, start + (end - start).div_ceil(PAGE_SIZE) * PAGE_SIZE)
+    }
+
+    /// Read the symbols of the SHT_SYMTAB section, whose names are in the
+    /// string table section given by its sh_link
+    fn parse_symbols(reader: &Reader) -> Result<Vec<Symbol>, ElfError> {
+        let (shoff, shentsize, shnum) = if reader.is_64 {
+            (reader.u64(40)?, reader.u16(58)?, reader.u16(60)?)
+        } else {
+            (reader.word(32)?, reader.u16(46)?, reader.u16(48)?)
+        };
+        // sh_type, sh_offset, sh_size and sh_link of a section header
+        let section = |index: u64| -> Result<(u32, u64, u64, u64), ElfError> {
+            let header = shoff
+                .checked_add(index * shentsize as u64)
+                .ok_or(ElfError::Truncated)?;
+            reader.bytes(header, shentsize as u64)?;
+            let sh_type = reader.u32(header + 4)?;
+            if reader.is_64 {
+                Ok((
+                    sh_type,
+                    reader.u64(header + 24)?,
+                    reader.u64(header + 32)?,
+                    reader.u32(header + 40)? as u64,
+                ))
+            } else {
+                Ok((
+                    sh_type,
+                    reader.word(header + 16)?,
+                    reader.word(header + 20)?,
+                    reader.word(header + 24)?,
+                ))
+            }
+        };
+
+        let mut symbols = Vec::new();
+        for index in 0..shnum as u64 {
+            let (sh_type, offset, size, link) = section(index)?;
+            if sh_type != SHT_SYMTAB {
+                continue;
+            }
+            let (_, strtab_offset, strtab_size, _) = section(link)?;
+            let strtab = reader.bytes(strtab_offset, strtab_size)?;
+
+            let entsize = if reader.is_64 { 24 } else { 16 };
+            let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+            for entry in (offset..end).step_by(entsize) {
+                reader.bytes(entry, entsize as u64)?;
+                // st_name, st_info, st_shndx, st_value and st_size
+                let (name, info, shndx, address, size) = if reader.is_64 {
+                    (
+                        reader.u32(entry)?,
+                        reader.bytes(entry + 4, 1)?[0],
+                        reader.u16(entry + 6)?,
+                        reader.u64(entry + 8)?,
+                        reader.u64(entry + 16)?,
+                    )
+                } else {
+                    (
+                        reader.u32(entry)?,
+                        reader.bytes(entry + 12, 1)?[0],
+                        reader.u16(entry + 14)?,
+                        reader.word(entry + 4)?,
+                        reader.word(entry + 8)?,
+                    )
+                };
+                if shndx == SHN_UNDEF || ![STT_NOTYPE, STT_OBJECT, STT_FUNC].contains(&(info & 0xf))
+                {
+                    continue;
+                }
+                let name = strtab
+                    .get(name as usize..)
+                    .and_then(|name| name.split(|&byte| byte == 0).next())
+                    .ok_or(ElfError::Truncated)?;
+                // Skip unnamed symbols and the $x/$d mapping symbols
+                if name.is_empty() || name[0] == b'$' {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    address,
+                    size,
+                });
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+impl Emulator {
+    /// Create an emulator whose memory spans the pages covered by the
+    /// loadable segments of `elf`, with the PC at its entry point
+    pub fn from_elf(elf: &Elf) -> Self {
+        Self::from_elf_with_memory(elf, 0)
+    }
+
+    /// Load `elf` with `extra` zeroed bytes of memory after its last page
+    pub(crate) fn from_elf_with_memory(elf: &Elf, extra: u64) -> Self {
+        let (start, end) = elf.load_range();
+        let mut emu = Emulator::new(vec![0; (end - start + extra) as usize]);
+        emu.bus.ram_base = start;
+        for segment in elf.segments.iter().filter(|segment| segment.size > 0) {
+            let offset = (segment.address - start) as usize;
+            emu.bus.ram[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        emu.pc = elf.entry;
+        emu.symbols = elf.symbols.clone();
+        emu.htif = Htif::from_symbols(&elf.symbols);
+        emu
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build an executable with one PT_LOAD segment per (address, data,
+    /// size) triple, placed after the headers
+    fn build(is_64: bool, machine: u16, entry: u64, segments: &[(u64, &[u8], u64)]) -> Vec<u8> {
+        let (ehsize, phentsize) = if is_64 { (64, 56) } else { (52, 32) };
+        let mut image = vec![0; ehsize + phentsize * segments.len()];
+        image[..4].copy_from_slice(&ELF_MAGIC);
+        image[4] = if is_64 { ELFCLASS64 } else { ELFCLASS32 };
+        image[5] = ELFDATA2LSB;
+        image[6] = 1; // EI_VERSION
+        image[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        image[18..20].copy_from_slice(&machine.to_le_bytes());
+        let count = segments.len() as u16;
+        if is_64 {
+            image[24..32].copy_from_slice(&entry.to_le_bytes());
+            image[32..40].copy_from_slice(&(ehsize as u64).to_le_bytes());
+            image[54..56].copy_from_slice(&(phentsize as u16).to_le_bytes());
+            image[56..58].copy_from_slice(&count.to_le_bytes());
+        } else {
+            image[24..28].copy_from_slice(&(entry as u32).to_le_bytes());
+            image[28..32].copy_from_slice(&(ehsize as u32).to_le_bytes());
+            image[42..44].copy_from_slice(&(phentsize as u16).to_le_bytes());
+            image[44..46].copy_from_slice(&count.to_le_bytes());
+        }
+
+        for (index, (address, data, size)) in segments.iter().enumerate() {
+            let header = ehsize + index * phentsize;
+            let offset = image.len() as u64;
+            image[header..header + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            // p_offset, p_vaddr, p_paddr, p_filesz and p_memsz
+            let fields = [offset, *address, *address, data.len() as u64, *size];
+            for (i, field) in fields.iter().enumerate() {
+                if is_64 {
+                    let start = header + 8 + 8 * i;
+                    image[start..start + 8].copy_from_slice(&field.to_le_bytes());
+                } else {
+                    let start = header + 4 + 4 * i;
+                    image[start..start + 4].copy_from_slice(&(*field as u32).to_le_bytes());
+                }
+            }
+            image.extend_from_slice(data);
+        }
+        image
+    }
+
+    /// Append .symtab and .strtab sections holding (name, st_info, address,
+    /// size) symbols, after a null symbol, to an image from `build`
+    fn add_symbols(image: &mut Vec<u8>, is_64: bool, symbols: &[(&str, u8, u64, u64)]) {
+        let mut strtab = vec![0];
+        let entsize = if is_64 { 24 } else { 16 };
+        let mut symtab = vec![0; entsize];
+        for (name, info, address, size) in symbols {
+            let mut entry = vec![0; entsize];
+            entry[..4].copy_from_slice(&(strtab.len() as u32).to_le_bytes());
+            if is_64 {
+                entry[4] = *info;
+                entry[6] = 1; // st_shndx
+                entry[8..16].copy_from_slice(&address.to_le_bytes());
+                entry[16..24].copy_from_slice(&size.to_le_bytes());
+            } else {
+                entry[4..8].copy_from_slice(&(*address as u32).to_le_bytes());
+                entry[8..12].copy_from_slice(&(*size as u32).to_le_bytes());
+                entry[12] = *info;
+                entry[14] = 1;
+            }
+            symtab.extend_from_slice(&entry);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+
+        let symtab_offset = image.len() as u64;
+        image.extend_from_slice(&symtab);
+        let strtab_offset = image.len() as u64;
+        image.extend_from_slice(&strtab);
+
+        // Section headers: null, .symtab linked to .strtab, then .strtab
+        let shentsize = if is_64 { 64 } else { 40 };
+        let shoff = image.len() as u64;
+        let sections = [
+            (0, 0, 0, 0),
+            (SHT_SYMTAB, symtab_offset, symtab.len() as u64, 2),
+            (3, strtab_offset, strtab.len() as u64, 0),
+        ];
+        for (sh_type, offset, size, link) in sections {
+            let mut header = vec![0; shentsize];
+            header[4..8].copy_from_slice(&sh_type.to_le_bytes());
+            if is_64 {
+                header[24..32].copy_from_slice(&offset.to_le_bytes());
+                header[32..40].copy_from_slice(&size.to_le_bytes());
+                header[40..44].copy_from_slice(&(link as u32).to_le_bytes());
+            } else {
+                header[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+                header[20..24].copy_from_slice(&(size as u32).to_le_bytes());
+                header[24..28].copy_from_slice(&(link as u32).to_le_bytes());
+            }
+            image.extend_from_slice(&header);
+        }
+        if is_64 {
+            image[40..48].copy_from_slice(&shoff.to_le_bytes());
+            image[58..60].copy_from_slice(&(shentsize as u16).to_le_bytes());
+            image[60..62].copy_from_slice(&3u16.to_le_bytes());
+        } else {
+            image[32..36].copy_from_slice(&(shoff as u32).to_le_bytes());
+            image[46..48].copy_from_slice(&(shentsize as u16).to_le_bytes());
+            image[48..50].copy_from_slice(&3u16.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let code: &[u8] = &[0x13, 0x05, 0x60, 0x00]; // addi a0,zero,6
+        for is_64 in [true, false] {
+            let image = build(is_64, EM_RISCV, 0x80000000, &[(0x80000000, code, 4)]);
+            let elf = Elf::parse(&image).unwrap();
+            assert_eq!(elf.entry, 0x80000000);
+            assert_eq!(
+                elf.segments,
+                vec![Segment {
+                    address: 0x80000000,
+                    data: code.to_vec(),
+                    size: 4,
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let image = build(true, EM_RISCV, 0, &[(0, &[0; 4], 4)]);
+        assert_eq!(
+            Elf::parse(&[0x13, 0x05, 0x60, 0x00]).unwrap_err(),
+            ElfError::NotElf
+        );
+
+        let mut bad = image.clone();
+        bad[4] = 3;
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::InvalidClass(3));
+        let mut bad = image.clone();
+        bad[5] = ELFDATA2MSB;
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::BigEndian);
+        let mut bad = image.clone();
+        bad[16] = 1; // ET_REL
+        assert_eq!(Elf::parse(&bad).unwrap_err(), ElfError::NotExecutable(1));
+
+        let x86_64 = build(true, 62, 0, &[(0, &[0; 4], 4)]);
+        assert_eq!(Elf::parse(&x86_64).unwrap_err(), ElfError::NotRiscv(62));
+        assert_eq!(
+            Elf::parse(&image[..image.len() - 1]).unwrap_err(),
+            ElfError::Truncated
+        );
+        let wrapping = build(true, EM_RISCV, 0, &[(u64::MAX - 0xfff, &[], 0x800)]);
+        assert_eq!(
+            Elf::parse(&wrapping).unwrap_err(),
+            ElfError::InvalidSegment(u64::MAX - 0xfff)
+        );
+        let oversized = build(true, EM_RISCV, 0, &[(0x1000, &[0; 8], 4)]);
+        assert_eq!(
+            Elf::parse(&oversized).unwrap_err(),
+            ElfError::InvalidSegment(0x1000)
+        );
+        let sparse = build(
+            true,
+            EM_RISCV,
+            0,
+            &[(0x1000, &[0; 4], 4), (0x8000_0000, &[0; 4], 4)],
+        );
+        assert_eq!(
+            Elf::parse(&sparse).unwrap_err(),
+            ElfError::SparseSegments(0x8000_0000)
+        );
+        let empty = build(true, EM_RISCV, 0, &[]);
+        assert_eq!(
+            Elf::parse(&empty).unwrap_err(),
+            ElfError::NoLoadableSegments
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        for is_64 in [true, false] {
+            let mut image = build(is_64, EM_RISCV, 0x1000, &[(0x1000, &[0; 0x40], 0x40)]);
+            add_symbols(
+                &mut image,
+                is_64,
+                &[
+                    ("_start", STT_NOTYPE, 0x1000, 0),
+                    ("main", STT_FUNC, 0x1010, 0x20),
+                    ("counter", STT_OBJECT, 0x1030, 8),
+                    ("$x", STT_NOTYPE, 0x1000, 0),
+                    ("example.c", 4, 0, 0), // STT_FILE
+                ],
+            );
+            let elf = Elf::parse(&image).unwrap();
+
+            assert_eq!(elf.symbols.get("$x"), None);
+            assert_eq!(elf.symbols.get("example.c"), None);
+            assert_eq!(elf.symbols.get("counter").unwrap().size, 8);
+            assert_eq!(
+                elf.symbols.describe(0x1008).to_string(),
+                "0x00001008 <_start+0x8>"
+            );
+            assert_eq!(
+                elf.symbols.describe(0x1014).to_string(),
+                "0x00001014 <main+0x4>"
+            );
+
+            let emu = Emulator::from_elf(&elf);
+            assert_eq!(emu.symbols.lookup(0x1034).unwrap().0.name, "counter");
+        }
+
+        // Stripped executables have no symbols
+        let image = build(true, EM_RISCV, 0x1000, &[(0x1000, &[0; 4], 4)]);
+        assert_eq!(Elf::parse(&image).unwrap().symbols.lookup(0x1000), None);
+    }
+
+    #[test]
+    fn test_from_elf() {
+        let text: &[u8] = &[
+            0x37, 0x15, 0x01, 0x00, // lui  a0,0x11
+            0x83, 0x35, 0x05, 0x00, // ld   a1,0(a0)
+            0x03, 0x36, 0x85, 0x00, // ld   a2,8(a0)
+        ];
+        let data: &[u8] = &[0x2a, 0, 0, 0, 0, 0, 0, 0];
+        // .data is followed by 8 bytes of .bss
+        let image = build(
+            true,
+            EM_RISCV,
+            0x10000,
+            &[(0x10000, text, 12), (0x11000, data, 16)],
+        );
+        let elf = Elf::parse(&image).unwrap();
+
+        let mut emu = Emulator::from_elf(&elf);
+        assert_eq!(emu.bus.ram_base, 0x10000);
+        assert_eq!(emu.bus.ram.len(), 0x2000);
+        assert_eq!(emu.pc, 0x10000);
+        emu.regs[12] = 0xff;
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.getreg(11), 42);
+        assert_eq!(emu.getreg(12), 0);
+    }
+}
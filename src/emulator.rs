@@ -1,18 +1,55 @@
+use crate::bus::Bus;
+use crate::csr::{self, CsrFile, Privilege};
 use crate::decoder::decode_instruction;
+use crate::float::{self, RoundingMode};
+use crate::htif::Htif;
 use crate::instruction::Instruction;
+use crate::mmu::{AccessType, Tlb, PAGE_SIZE};
+use crate::symbols::SymbolTable;
+use crate::syscall::Syscalls;
+use crate::trap::Exception;
+use crate::types::{Atype, Itype};
+
+/// Size in bytes of the naturally aligned block covered by an LR reservation
+const RESERVATION_GRANULE: u64 = 8;
+
+/// Interrupt codes from highest to lowest priority: external, software
+/// and timer interrupts, machine before supervisor
+const INTERRUPT_PRIORITY: [u64; 6] = [11, 3, 7, 9, 1, 5];
 
 pub struct Emulator {
     pub regs: [u64; 32],
+    pub fregs: [u64; 32],
     pub pc: u64,
-    pub memory: Vec<u8>,
+    pub privilege: Privilege,
+    pub csr: CsrFile,
+    pub bus: Bus,
+    /// Reservation set registered by the most recent LR, if still valid
+    pub reservation: Option<u64>,
+    pub tlb: Tlb,
+    /// Symbols of the loaded program, used to describe addresses
+    pub symbols: SymbolTable,
+    /// Host-side syscall layer servicing `ecall`, for programs running
+    /// without a guest kernel
+    pub syscalls: Option<Syscalls>,
+    /// `tohost`/`fromhost` interface of programs written for Spike
+    pub htif: Option<Htif>,
 }
 
 impl Emulator {
     pub fn new(memory: Vec<u8>) -> Self {
         Self {
             regs: [0; 32],
+            fregs: [0; 32],
             pc: 0,
-            memory: memory,
+            privilege: Privilege::Machine,
+            csr: CsrFile::new(0),
+            bus: Bus::new(0, memory),
+            reservation: None,
+            tlb: Tlb::default(),
+            symbols: SymbolTable::default(),
+            syscalls: None,
+            htif: None,
         }
     }
 
@@ -30,14 +67,255 @@ impl Emulator {
         }
     }
 
-    pub fn fetch_instruction(&self) -> u32 {
-        self.memory[self.pc as usize] as u32
-            | (self.memory[(self.pc + 1) as usize] as u32) << 8
-            | (self.memory[(self.pc + 2) as usize] as u32) << 16
-            | (self.memory[(self.pc + 3) as usize] as u32) << 24
+    /// Read a single-precision register, unboxing the value. A value that
+    /// is not properly NaN-boxed reads as the canonical NaN.
+    pub fn getfreg_s(&self, reg: usize) -> f32 {
+        let value = self.fregs[reg];
+        if value >> 32 == 0xffffffff {
+            f32::from_bits(value as u32)
+        } else {
+            f32::from_bits(0x7fc00000)
+        }
+    }
+
+    /// Write a single-precision register, NaN-boxing the value
+    pub fn setfreg_s(&mut self, reg: usize, val: f32) {
+        self.setfreg(reg, 0xffffffff00000000 | val.to_bits() as u64);
+    }
+
+    pub fn getfreg_d(&self, reg: usize) -> f64 {
+        f64::from_bits(self.fregs[reg])
+    }
+
+    pub fn setfreg_d(&mut self, reg: usize, val: f64) {
+        self.setfreg(reg, val.to_bits());
+    }
+
+    /// Write the raw bits of a floating-point register
+    pub fn setfreg(&mut self, reg: usize, val: u64) {
+        self.fregs[reg] = val;
+        self.csr.mark_fs_dirty();
+    }
+
+    /// Resolve the rm field of an instruction, using frm for dynamic rounding
+    fn rounding_mode(&self, rm: u32) -> Result<RoundingMode, Exception> {
+        let rm = if rm == 0b111 { self.csr.fcsr >> 5 } else { rm };
+        RoundingMode::from_bits(rm).ok_or(Exception::IllegalInstruction(0))
+    }
+
+    /// Fetch the instruction at the PC. Compressed instructions are returned
+    /// in the low 16 bits; the upper half is only read for 32-bit encodings,
+    /// and may lie on a different page.
+    pub fn fetch_instruction(&mut self) -> Result<u32, Exception> {
+        let low = self.fetch_parcel(self.pc)?;
+        if low & 0b11 != 0b11 {
+            return Ok(low);
+        }
+        let high = self.fetch_parcel(self.pc.wrapping_add(2))?;
+        Ok(low | high << 16)
+    }
+
+    /// Fetch 16 bits of an instruction
+    fn fetch_parcel(&mut self, address: u64) -> Result<u32, Exception> {
+        let paddr = self.physical_address(address, 2, AccessType::Instruction)?;
+        let parcel = self
+            .read_memory(paddr, 2)
+            .ok_or(Exception::InstructionAccessFault(address))?;
+        Ok(parcel as u32)
+    }
+
+    /// Translate the virtual address of an access of `size` bytes, and
+    /// check the physical access against PMP
+    fn physical_address(
+        &mut self,
+        address: u64,
+        size: usize,
+        access: AccessType,
+    ) -> Result<u64, Exception> {
+        let paddr = self.translate(address, access)?;
+        let privilege = self.effective_privilege(access);
+        if self.csr.pmp_permits(paddr, size, access, privilege) {
+            Ok(paddr)
+        } else {
+            Err(access.access_fault(address))
+        }
+    }
+
+    /// Read `size` bytes of little-endian data at a physical address,
+    /// zero-extended to 64 bits
+    pub(crate) fn read_memory(&mut self, address: u64, size: usize) -> Option<u64> {
+        self.bus.read(address, size)
+    }
+
+    /// Write the low `size` bytes of `value` at a physical address,
+    /// invalidating any reservation that overlaps the written bytes
+    pub(crate) fn write_memory(&mut self, address: u64, size: usize, value: u64) -> Option<()> {
+        self.bus.write(address, size, value)?;
+        if let Some(reserved) = self.reservation {
+            if address < reserved + RESERVATION_GRANULE && reserved < address + size as u64 {
+                self.reservation = None;
+            }
+        }
+        Some(())
+    }
+
+    /// Number of bytes of an access at `address` that fall on its first page
+    fn bytes_on_first_page(address: u64, size: usize) -> usize {
+        let remaining = (PAGE_SIZE - address % PAGE_SIZE) as usize;
+        size.min(remaining)
+    }
+
+    /// Load `size` bytes from a virtual address, zero-extended to 64 bits
+    fn load(&mut self, address: u64, size: usize) -> Result<u64, Exception> {
+        let first = Self::bytes_on_first_page(address, size);
+        if first < size {
+            // Split an access that crosses a page boundary
+            let low = self.load(address, first)?;
+            let high = self.load(address.wrapping_add(first as u64), size - first)?;
+            return Ok(low | high << (8 * first));
+        }
+        let paddr = self.physical_address(address, size, AccessType::Load)?;
+        self.read_memory(paddr, size)
+            .ok_or(Exception::LoadAccessFault(address))
+    }
+
+    /// Store the low `size` bytes of `value` at a virtual address
+    fn store(&mut self, address: u64, size: usize, value: u64) -> Result<(), Exception> {
+        let first = Self::bytes_on_first_page(address, size);
+        if first < size {
+            // Check both pages before writing either, so that a fault on
+            // the second page leaves memory untouched
+            let second = address.wrapping_add(first as u64);
+            let first_paddr = self.physical_address(address, first, AccessType::Store)?;
+            let second_paddr = self.physical_address(second, size - first, AccessType::Store)?;
+            if !self.bus.is_mapped(first_paddr, first) {
+                return Err(Exception::StoreAccessFault(address));
+            }
+            if !self.bus.is_mapped(second_paddr, size - first) {
+                return Err(Exception::StoreAccessFault(second));
+            }
+            self.write_memory(first_paddr, first, value)
+                .ok_or(Exception::StoreAccessFault(address))?;
+            self.write_memory(second_paddr, size - first, value >> (8 * first))
+                .ok_or(Exception::StoreAccessFault(second))?;
+            return Ok(());
+        }
+        let paddr = self.physical_address(address, size, AccessType::Store)?;
+        self.write_memory(paddr, size, value)
+            .ok_or(Exception::StoreAccessFault(address))
+    }
+
+    /// Perform an LR, registering a reservation on the physical address
+    fn load_reserved(&mut self, address: u64, size: usize) -> Result<u64, Exception> {
+        if !address.is_multiple_of(size as u64) {
+            return Err(Exception::LoadAddressMisaligned(address));
+        }
+        let paddr = self.physical_address(address, size, AccessType::Load)?;
+        let value = self
+            .read_memory(paddr, size)
+            .ok_or(Exception::LoadAccessFault(address))?;
+        self.reservation = Some(paddr & !(RESERVATION_GRANULE - 1));
+        Ok(value)
+    }
+
+    /// Perform the store half of an SC, returning whether it succeeded.
+    /// The reservation is consumed whether or not the store takes place.
+    fn store_conditional(
+        &mut self,
+        address: u64,
+        size: usize,
+        value: u64,
+    ) -> Result<bool, Exception> {
+        if !address.is_multiple_of(size as u64) {
+            return Err(Exception::StoreAddressMisaligned(address));
+        }
+        let paddr = self.physical_address(address, size, AccessType::Store)?;
+        let reserved = self.reservation.take();
+        if reserved == Some(paddr & !(RESERVATION_GRANULE - 1)) {
+            self.write_memory(paddr, size, value)
+                .ok_or(Exception::StoreAccessFault(address))?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Execute an AMO: rd receives the original memory value (sign-extended
+    /// for word operations) and memory receives `op(original, rs2)`.
+    fn amo<F>(&mut self, inst: Atype, size: usize, op: F) -> Result<(), Exception>
+    where
+        F: Fn(u64, u64) -> u64,
+    {
+        // Single hart, so every AMO is trivially ordered; aq/rl are decoded
+        // into `Atype` for a future multi-hart memory model.
+        let address = self.getreg(inst.rs1);
+        if !address.is_multiple_of(size as u64) {
+            return Err(Exception::StoreAddressMisaligned(address));
+        }
+        // AMOs report faults as stores, even for the read half
+        let paddr = self.physical_address(address, size, AccessType::Store)?;
+        let mut value = self
+            .read_memory(paddr, size)
+            .ok_or(Exception::StoreAccessFault(address))?;
+        if size == 4 {
+            value = value as i32 as i64 as u64;
+        }
+        let result = op(value, self.getreg(inst.rs2));
+        self.write_memory(paddr, size, result)
+            .ok_or(Exception::StoreAccessFault(address))?;
+        self.setreg(inst.rd, value);
+        Ok(())
     }
 
-    pub fn execute_instruction(&mut self, inst: Instruction) {
+    /// Shared body of the Zicsr instructions. The CSR is only read if `read`
+    /// is set and only written if `write` is set, so that side effects and
+    /// read-only checks apply exactly as the ISA specifies; the old value is
+    /// written to rd.
+    fn csr_access<F>(
+        &mut self,
+        inst: &Itype,
+        read: bool,
+        write: bool,
+        update: F,
+    ) -> Result<(), Exception>
+    where
+        F: Fn(u64) -> u64,
+    {
+        let address = (inst.imm & 0xfff) as u16;
+        let old = if read {
+            self.csr
+                .read(address, self.privilege)
+                .map_err(|_| Exception::IllegalInstruction(0))?
+        } else {
+            0
+        };
+        if write {
+            self.csr
+                .write(address, update(old), self.privilege)
+                .map_err(|_| Exception::IllegalInstruction(0))?;
+        }
+        self.setreg(inst.rd, old);
+        Ok(())
+    }
+
+    /// Execute `inst`, located at address `pc`. The PC register already
+    /// points at the following instruction. Illegal instructions are reported
+    /// with a zero trap value, since only the decoded form is available here.
+    pub fn execute_instruction(&mut self, inst: Instruction, pc: u64) -> Result<(), Exception> {
+        if inst.is_float() && !self.csr.float_enabled() {
+            return Err(Exception::IllegalInstruction(0));
+        }
+        let fcsr = self.csr.fcsr;
+        let result = self.execute(inst, pc);
+        // Accrued exception flags are floating-point state too, even for
+        // instructions writing no floating-point register
+        if self.csr.fcsr != fcsr {
+            self.csr.mark_fs_dirty();
+        }
+        result
+    }
+
+    fn execute(&mut self, inst: Instruction, pc: u64) -> Result<(), Exception> {
         match inst {
             Instruction::Add(inst) => {
                 self.setreg(
@@ -51,122 +329,722 @@ impl Emulator {
                     self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64),
                 );
             }
+            Instruction::Addiw(inst) => {
+                self.setreg(
+                    inst.rd,
+                    (self.getreg(inst.rs1) as i32).wrapping_add(inst.imm) as i64 as u64,
+                );
+            }
+            Instruction::Addw(inst) => {
+                self.setreg(
+                    inst.rd,
+                    (self.getreg(inst.rs1) as i32).wrapping_add(self.getreg(inst.rs2) as i32) as i64
+                        as u64,
+                );
+            }
             Instruction::And(inst) => {
                 self.setreg(inst.rd, self.getreg(inst.rs1) & self.getreg(inst.rs2))
             }
             Instruction::Andi(inst) => {
                 self.setreg(inst.rd, (inst.imm as i64 as u64) & self.getreg(inst.rs1))
             }
+            Instruction::AmoaddD(inst) => self.amo(inst, 8, |a, b| a.wrapping_add(b))?,
+            Instruction::AmoaddW(inst) => self.amo(inst, 4, |a, b| a.wrapping_add(b))?,
+            Instruction::AmoandD(inst) => self.amo(inst, 8, |a, b| a & b)?,
+            Instruction::AmoandW(inst) => self.amo(inst, 4, |a, b| a & b)?,
+            Instruction::AmomaxD(inst) => {
+                self.amo(inst, 8, |a, b| (a as i64).max(b as i64) as u64)?
+            }
+            Instruction::AmomaxW(inst) => {
+                self.amo(inst, 4, |a, b| (a as i32).max(b as i32) as u64)?
+            }
+            Instruction::AmomaxuD(inst) => self.amo(inst, 8, |a, b| a.max(b))?,
+            Instruction::AmomaxuW(inst) => {
+                self.amo(inst, 4, |a, b| (a as u32).max(b as u32) as u64)?
+            }
+            Instruction::AmominD(inst) => {
+                self.amo(inst, 8, |a, b| (a as i64).min(b as i64) as u64)?
+            }
+            Instruction::AmominW(inst) => {
+                self.amo(inst, 4, |a, b| (a as i32).min(b as i32) as u64)?
+            }
+            Instruction::AmominuD(inst) => self.amo(inst, 8, |a, b| a.min(b))?,
+            Instruction::AmominuW(inst) => {
+                self.amo(inst, 4, |a, b| (a as u32).min(b as u32) as u64)?
+            }
+            Instruction::AmoorD(inst) => self.amo(inst, 8, |a, b| a | b)?,
+            Instruction::AmoorW(inst) => self.amo(inst, 4, |a, b| a | b)?,
+            Instruction::AmoswapD(inst) => self.amo(inst, 8, |_, b| b)?,
+            Instruction::AmoswapW(inst) => self.amo(inst, 4, |_, b| b)?,
+            Instruction::AmoxorD(inst) => self.amo(inst, 8, |a, b| a ^ b)?,
+            Instruction::AmoxorW(inst) => self.amo(inst, 4, |a, b| a ^ b)?,
             Instruction::Auipc(inst) => {
-                self.setreg(
-                    inst.rd,
-                    // PC has already been increased
-                    self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64),
-                );
+                self.setreg(inst.rd, pc.wrapping_add(inst.imm as i64 as u64));
             }
             Instruction::Beq(inst) => {
                 if self.getreg(inst.rs1) == self.getreg(inst.rs2) {
-                    // PC has already been increased
-                    self.pc = self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64);
+                    self.pc = pc.wrapping_add(inst.imm as i64 as u64);
                 }
             }
             Instruction::Bne(inst) => {
                 if self.getreg(inst.rs1) != self.getreg(inst.rs2) {
-                    // PC has already been increased
-                    self.pc = self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64);
+                    self.pc = pc.wrapping_add(inst.imm as i64 as u64);
                 }
             }
             Instruction::Blt(inst) => {
                 if (self.getreg(inst.rs1) as i64) < (self.getreg(inst.rs2) as i64) {
-                    // PC has already been increased
-                    self.pc = self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64);
+                    self.pc = pc.wrapping_add(inst.imm as i64 as u64);
                 }
             }
             Instruction::Bge(inst) => {
                 if (self.getreg(inst.rs1) as i64) >= (self.getreg(inst.rs2) as i64) {
-                    // PC has already been increased
-                    self.pc = self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64);
+                    self.pc = pc.wrapping_add(inst.imm as i64 as u64);
                 }
             }
             Instruction::Bltu(inst) => {
                 if self.getreg(inst.rs1) < self.getreg(inst.rs2) {
-                    // PC has already been increased
-                    self.pc = self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64);
+                    self.pc = pc.wrapping_add(inst.imm as i64 as u64);
                 }
             }
             Instruction::Bgeu(inst) => {
                 if self.getreg(inst.rs1) >= self.getreg(inst.rs2) {
-                    // PC has already been increased
-                    self.pc = self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64);
+                    self.pc = pc.wrapping_add(inst.imm as i64 as u64);
                 }
             }
+            Instruction::Csrrc(inst) => {
+                let mask = self.getreg(inst.rs1);
+                self.csr_access(&inst, true, inst.rs1 != 0, |old| old & !mask)?;
+            }
+            Instruction::Csrrci(inst) => {
+                let mask = inst.rs1 as u64;
+                self.csr_access(&inst, true, mask != 0, |old| old & !mask)?;
+            }
+            Instruction::Csrrs(inst) => {
+                let mask = self.getreg(inst.rs1);
+                self.csr_access(&inst, true, inst.rs1 != 0, |old| old | mask)?;
+            }
+            Instruction::Csrrsi(inst) => {
+                let mask = inst.rs1 as u64;
+                self.csr_access(&inst, true, mask != 0, |old| old | mask)?;
+            }
+            Instruction::Csrrw(inst) => {
+                let value = self.getreg(inst.rs1);
+                self.csr_access(&inst, inst.rd != 0, true, |_| value)?;
+            }
+            Instruction::Csrrwi(inst) => {
+                let value = inst.rs1 as u64;
+                self.csr_access(&inst, inst.rd != 0, true, |_| value)?;
+            }
+            Instruction::Div(inst) => {
+                let dividend = self.getreg(inst.rs1) as i64;
+                let divisor = self.getreg(inst.rs2) as i64;
+                let value = if divisor == 0 {
+                    -1
+                } else {
+                    // Overflow (MIN / -1) wraps to MIN as required by the spec
+                    dividend.wrapping_div(divisor)
+                };
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::Divu(inst) => {
+                let dividend = self.getreg(inst.rs1);
+                let divisor = self.getreg(inst.rs2);
+                let value = dividend.checked_div(divisor).unwrap_or(u64::MAX);
+                self.setreg(inst.rd, value);
+            }
+            Instruction::Divuw(inst) => {
+                let dividend = self.getreg(inst.rs1) as u32;
+                let divisor = self.getreg(inst.rs2) as u32;
+                let value = dividend.checked_div(divisor).unwrap_or(u32::MAX);
+                self.setreg(inst.rd, value as i32 as i64 as u64);
+            }
+            Instruction::Divw(inst) => {
+                let dividend = self.getreg(inst.rs1) as i32;
+                let divisor = self.getreg(inst.rs2) as i32;
+                let value = if divisor == 0 {
+                    -1
+                } else {
+                    dividend.wrapping_div(divisor)
+                };
+                self.setreg(inst.rd, value as i64 as u64);
+            }
+            Instruction::Ebreak => return Err(Exception::Breakpoint(pc)),
+            Instruction::Ecall if self.syscalls.is_some() => self.syscall(),
+            Instruction::Ecall => {
+                return Err(match self.privilege {
+                    Privilege::User => Exception::EnvironmentCallFromUMode,
+                    Privilege::Supervisor => Exception::EnvironmentCallFromSMode,
+                    Privilege::Machine => Exception::EnvironmentCallFromMMode,
+                });
+            }
+            Instruction::FaddD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::add(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FaddS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::add(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FclassD(inst) => {
+                self.setreg(inst.rd, float::classify(self.getfreg_d(inst.rs1)));
+            }
+            Instruction::FclassS(inst) => {
+                self.setreg(inst.rd, float::classify(self.getfreg_s(inst.rs1)));
+            }
+            Instruction::FcvtDL(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as i64 as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FcvtDLu(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FcvtDS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = float::convert(self.getfreg_s(inst.rs1), rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FcvtDW(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as i32 as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FcvtDWu(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as u32 as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FcvtLD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (min, max) = (i64::MIN as i128, i64::MAX as i128);
+                let value =
+                    float::to_int(self.getfreg_d(inst.rs1), min, max, rm, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FcvtLS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (min, max) = (i64::MIN as i128, i64::MAX as i128);
+                let value =
+                    float::to_int(self.getfreg_s(inst.rs1), min, max, rm, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FcvtLuD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let max = u64::MAX as i128;
+                let value = float::to_int(self.getfreg_d(inst.rs1), 0, max, rm, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FcvtLuS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let max = u64::MAX as i128;
+                let value = float::to_int(self.getfreg_s(inst.rs1), 0, max, rm, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FcvtSD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = float::convert(self.getfreg_d(inst.rs1), rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FcvtSL(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as i64 as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FcvtSLu(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FcvtSW(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as i32 as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FcvtSWu(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = self.getreg(inst.rs1) as u32 as i128;
+                let value = float::from_int(value, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FcvtWD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (min, max) = (i32::MIN as i128, i32::MAX as i128);
+                let value =
+                    float::to_int(self.getfreg_d(inst.rs1), min, max, rm, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as i64 as u64);
+            }
+            Instruction::FcvtWS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (min, max) = (i32::MIN as i128, i32::MAX as i128);
+                let value =
+                    float::to_int(self.getfreg_s(inst.rs1), min, max, rm, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as i64 as u64);
+            }
+            Instruction::FcvtWuD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let max = u32::MAX as i128;
+                let value = float::to_int(self.getfreg_d(inst.rs1), 0, max, rm, &mut self.csr.fcsr);
+                // The 32-bit result is sign-extended, even for unsigned conversions
+                self.setreg(inst.rd, value as u32 as i32 as i64 as u64);
+            }
+            Instruction::FcvtWuS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let max = u32::MAX as i128;
+                let value = float::to_int(self.getfreg_s(inst.rs1), 0, max, rm, &mut self.csr.fcsr);
+                // The 32-bit result is sign-extended, even for unsigned conversions
+                self.setreg(inst.rd, value as u32 as i32 as i64 as u64);
+            }
+            Instruction::FdivD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::div(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FdivS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::div(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FeqD(inst) => {
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::eq(a, b, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FeqS(inst) => {
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::eq(a, b, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            // A single hart executing in order observes its own accesses,
+            // including stores to the instructions it fetches, in order
+            Instruction::Fence | Instruction::FenceI => {}
+            Instruction::Fld(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 8)?;
+                self.setfreg(inst.rd, value);
+            }
+            Instruction::FleD(inst) => {
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::le(a, b, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FleS(inst) => {
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::le(a, b, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FltD(inst) => {
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::lt(a, b, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::FltS(inst) => {
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::lt(a, b, &mut self.csr.fcsr);
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::Flw(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 4)?;
+                self.setfreg(inst.rd, 0xffffffff00000000 | value);
+            }
+            Instruction::FmaddD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_d(inst.rs1);
+                let (b, c) = (self.getfreg_d(inst.rs2), self.getfreg_d(inst.rs3));
+                let value = float::mul_add(a, b, c, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FmaddS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_s(inst.rs1);
+                let (b, c) = (self.getfreg_s(inst.rs2), self.getfreg_s(inst.rs3));
+                let value = float::mul_add(a, b, c, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FmaxD(inst) => {
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::max(a, b, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FmaxS(inst) => {
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::max(a, b, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FminD(inst) => {
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::min(a, b, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FminS(inst) => {
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::min(a, b, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FmsubD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_d(inst.rs1);
+                let (b, c) = (self.getfreg_d(inst.rs2), self.getfreg_d(inst.rs3));
+                let value = float::mul_add(a, b, -c, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FmsubS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_s(inst.rs1);
+                let (b, c) = (self.getfreg_s(inst.rs2), self.getfreg_s(inst.rs3));
+                let value = float::mul_add(a, b, -c, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FmulD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::mul(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FmulS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::mul(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FmvDX(inst) => {
+                self.setfreg(inst.rd, self.getreg(inst.rs1));
+            }
+            Instruction::FmvWX(inst) => {
+                self.setfreg(
+                    inst.rd,
+                    0xffffffff00000000 | (self.getreg(inst.rs1) & 0xffffffff),
+                );
+            }
+            Instruction::FmvXD(inst) => {
+                self.setreg(inst.rd, self.fregs[inst.rs1]);
+            }
+            Instruction::FmvXW(inst) => {
+                // Moves the raw low 32 bits, without checking the NaN-boxing
+                self.setreg(inst.rd, self.fregs[inst.rs1] as u32 as i32 as i64 as u64);
+            }
+            Instruction::FnmaddD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_d(inst.rs1);
+                let (b, c) = (self.getfreg_d(inst.rs2), self.getfreg_d(inst.rs3));
+                let value = float::mul_add(-a, b, -c, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FnmaddS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_s(inst.rs1);
+                let (b, c) = (self.getfreg_s(inst.rs2), self.getfreg_s(inst.rs3));
+                let value = float::mul_add(-a, b, -c, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FnmsubD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_d(inst.rs1);
+                let (b, c) = (self.getfreg_d(inst.rs2), self.getfreg_d(inst.rs3));
+                let value = float::mul_add(-a, b, c, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FnmsubS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let a = self.getfreg_s(inst.rs1);
+                let (b, c) = (self.getfreg_s(inst.rs2), self.getfreg_s(inst.rs3));
+                let value = float::mul_add(-a, b, c, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::Fsd(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                self.store(address, 8, self.fregs[inst.rs2])?;
+            }
+            Instruction::FsgnjD(inst) => {
+                let (a, b) = (self.fregs[inst.rs1], self.fregs[inst.rs2]);
+                let sign = 1 << 63;
+                self.setfreg_d(inst.rd, f64::from_bits((a & !sign) | (b & sign)));
+            }
+            Instruction::FsgnjS(inst) => {
+                let a = self.getfreg_s(inst.rs1).to_bits();
+                let b = self.getfreg_s(inst.rs2).to_bits();
+                let sign = 1 << 31;
+                self.setfreg_s(inst.rd, f32::from_bits((a & !sign) | (b & sign)));
+            }
+            Instruction::FsgnjnD(inst) => {
+                let (a, b) = (self.fregs[inst.rs1], self.fregs[inst.rs2]);
+                let sign = 1 << 63;
+                self.setfreg_d(inst.rd, f64::from_bits((a & !sign) | (!b & sign)));
+            }
+            Instruction::FsgnjnS(inst) => {
+                let a = self.getfreg_s(inst.rs1).to_bits();
+                let b = self.getfreg_s(inst.rs2).to_bits();
+                let sign = 1 << 31;
+                self.setfreg_s(inst.rd, f32::from_bits((a & !sign) | (!b & sign)));
+            }
+            Instruction::FsgnjxD(inst) => {
+                let (a, b) = (self.fregs[inst.rs1], self.fregs[inst.rs2]);
+                let sign = 1 << 63;
+                self.setfreg_d(inst.rd, f64::from_bits(a ^ (b & sign)));
+            }
+            Instruction::FsgnjxS(inst) => {
+                let a = self.getfreg_s(inst.rs1).to_bits();
+                let b = self.getfreg_s(inst.rs2).to_bits();
+                let sign = 1 << 31;
+                self.setfreg_s(inst.rd, f32::from_bits(a ^ (b & sign)));
+            }
+            Instruction::FsqrtD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = float::sqrt(self.getfreg_d(inst.rs1), rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FsqrtS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let value = float::sqrt(self.getfreg_s(inst.rs1), rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::FsubD(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_d(inst.rs1), self.getfreg_d(inst.rs2));
+                let value = float::sub(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_d(inst.rd, value);
+            }
+            Instruction::FsubS(inst) => {
+                let rm = self.rounding_mode(inst.funct3)?;
+                let (a, b) = (self.getfreg_s(inst.rs1), self.getfreg_s(inst.rs2));
+                let value = float::sub(a, b, rm, &mut self.csr.fcsr);
+                self.setfreg_s(inst.rd, value);
+            }
+            Instruction::Fsw(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                self.store(address, 4, self.fregs[inst.rs2])?;
+            }
             Instruction::Jal(inst) => {
-                // TODO: instruction-address-misaligned exception if address is not aligned
-                // PC has already been increased
+                // With RVC, targets only need 2-byte alignment, which the
+                // encoding of the offset already guarantees
                 self.setreg(inst.rd, self.pc);
-                self.pc = self.pc.wrapping_sub(4).wrapping_add(inst.imm as i64 as u64);
+                self.pc = pc.wrapping_add(inst.imm as i64 as u64);
             }
             Instruction::Jalr(inst) => {
-                // TODO: instruction-address-misaligned exception if address is not aligned
-                // PC has already been increased
+                // The target's least-significant bit is cleared, so with RVC
+                // it is always sufficiently aligned
+                let target = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) & !1;
                 self.setreg(inst.rd, self.pc);
-                self.pc = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                self.pc = target;
             }
             Instruction::Lui(inst) => {
                 self.setreg(inst.rd, inst.imm as i64 as u64);
             }
             Instruction::Lb(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
-                let value = self.memory[address] as i8 as i64 as u64;
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 1)? as i8 as i64 as u64;
                 self.setreg(inst.rd, value);
             }
             Instruction::Lbu(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
-                let value = self.memory[address] as u64;
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 1)?;
                 self.setreg(inst.rd, value);
             }
             Instruction::Lh(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
-                let value: [u8; 2] = [self.memory[address], self.memory[address + 1]];
-                self.setreg(inst.rd, i16::from_le_bytes(value) as i64 as u64);
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 2)? as i16 as i64 as u64;
+                self.setreg(inst.rd, value);
             }
             Instruction::Lhu(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
-                let value = self.memory[address] as u64 | ((self.memory[address + 1] as u64) << 8);
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 2)?;
                 self.setreg(inst.rd, value);
             }
             Instruction::Lw(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
-                let value = self.memory[address] as u64
-                    | ((self.memory[address + 1] as u64) << 8)
-                    | ((self.memory[address + 2] as u64) << 16)
-                    | ((self.memory[address + 3] as u64) << 24);
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 4)? as i32 as i64 as u64;
+                self.setreg(inst.rd, value);
+            }
+            Instruction::Lwu(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 4)?;
                 self.setreg(inst.rd, value);
             }
+            Instruction::Ld(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                let value = self.load(address, 8)?;
+                self.setreg(inst.rd, value);
+            }
+            Instruction::LrD(inst) => {
+                let value = self.load_reserved(self.getreg(inst.rs1), 8)?;
+                self.setreg(inst.rd, value);
+            }
+            Instruction::LrW(inst) => {
+                let value = self.load_reserved(self.getreg(inst.rs1), 4)? as i32 as i64 as u64;
+                self.setreg(inst.rd, value);
+            }
+            Instruction::Mret => {
+                if self.privilege != Privilege::Machine {
+                    return Err(Exception::IllegalInstruction(0));
+                }
+                // Restore MIE from MPIE and return to the mode in MPP, which
+                // drops to U
+                let mstatus = self.csr.get(csr::MSTATUS);
+                let mpie = mstatus & csr::MSTATUS_MPIE;
+                self.privilege = Privilege::from_bits((mstatus & csr::MSTATUS_MPP) >> 11);
+                let mut mstatus = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP)
+                    | mpie >> 4
+                    | csr::MSTATUS_MPIE;
+                if self.privilege != Privilege::Machine {
+                    mstatus &= !csr::MSTATUS_MPRV;
+                }
+                self.csr.set(csr::MSTATUS, mstatus);
+                self.pc = self.csr.get(csr::MEPC);
+            }
+            Instruction::Mul(inst) => {
+                self.setreg(
+                    inst.rd,
+                    self.getreg(inst.rs1).wrapping_mul(self.getreg(inst.rs2)),
+                );
+            }
+            Instruction::Mulh(inst) => {
+                let value =
+                    (self.getreg(inst.rs1) as i64 as i128) * (self.getreg(inst.rs2) as i64 as i128);
+                self.setreg(inst.rd, (value >> 64) as u64);
+            }
+            Instruction::Mulhsu(inst) => {
+                let value =
+                    (self.getreg(inst.rs1) as i64 as i128) * (self.getreg(inst.rs2) as i128);
+                self.setreg(inst.rd, (value >> 64) as u64);
+            }
+            Instruction::Mulhu(inst) => {
+                let value = (self.getreg(inst.rs1) as u128) * (self.getreg(inst.rs2) as u128);
+                self.setreg(inst.rd, (value >> 64) as u64);
+            }
+            Instruction::Mulw(inst) => {
+                self.setreg(
+                    inst.rd,
+                    (self.getreg(inst.rs1) as i32).wrapping_mul(self.getreg(inst.rs2) as i32) as i64
+                        as u64,
+                );
+            }
             Instruction::Or(inst) => {
                 self.setreg(inst.rd, self.getreg(inst.rs1) | self.getreg(inst.rs2))
             }
             Instruction::Ori(inst) => {
                 self.setreg(inst.rd, (inst.imm as i64 as u64) | self.getreg(inst.rs1))
             }
+            Instruction::Rem(inst) => {
+                let dividend = self.getreg(inst.rs1) as i64;
+                let divisor = self.getreg(inst.rs2) as i64;
+                let value = if divisor == 0 {
+                    dividend
+                } else {
+                    // Overflow (MIN % -1) yields 0 as required by the spec
+                    dividend.wrapping_rem(divisor)
+                };
+                self.setreg(inst.rd, value as u64);
+            }
+            Instruction::Remu(inst) => {
+                let dividend = self.getreg(inst.rs1);
+                let divisor = self.getreg(inst.rs2);
+                let value = if divisor == 0 {
+                    dividend
+                } else {
+                    dividend % divisor
+                };
+                self.setreg(inst.rd, value);
+            }
+            Instruction::Remuw(inst) => {
+                let dividend = self.getreg(inst.rs1) as u32;
+                let divisor = self.getreg(inst.rs2) as u32;
+                let value = if divisor == 0 {
+                    dividend
+                } else {
+                    dividend % divisor
+                };
+                self.setreg(inst.rd, value as i32 as i64 as u64);
+            }
+            Instruction::Remw(inst) => {
+                let dividend = self.getreg(inst.rs1) as i32;
+                let divisor = self.getreg(inst.rs2) as i32;
+                let value = if divisor == 0 {
+                    dividend
+                } else {
+                    dividend.wrapping_rem(divisor)
+                };
+                self.setreg(inst.rd, value as i64 as u64);
+            }
             Instruction::Sb(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
-                self.memory[address] = self.getreg(inst.rs2) as u8;
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                self.store(address, 1, self.getreg(inst.rs2))?;
             }
-            Instruction::Sh(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
+            Instruction::ScD(inst) => {
+                let address = self.getreg(inst.rs1);
+                let value = self.getreg(inst.rs2);
+                let success = self.store_conditional(address, 8, value)?;
+                self.setreg(inst.rd, !success as u64);
+            }
+            Instruction::ScW(inst) => {
+                let address = self.getreg(inst.rs1);
                 let value = self.getreg(inst.rs2);
-                self.memory[address] = value as u8;
-                self.memory[address + 1] = (value >> 8) as u8;
+                let success = self.store_conditional(address, 4, value)?;
+                self.setreg(inst.rd, !success as u64);
+            }
+            Instruction::Sd(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                self.store(address, 8, self.getreg(inst.rs2))?;
+            }
+            Instruction::SfenceVma(inst) => {
+                let mstatus = self.csr.get(csr::MSTATUS);
+                if self.privilege == Privilege::User
+                    || self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_TVM != 0
+                {
+                    return Err(Exception::IllegalInstruction(0));
+                }
+                let vaddr = if inst.rs1 == 0 {
+                    None
+                } else {
+                    Some(self.getreg(inst.rs1))
+                };
+                let asid = if inst.rs2 == 0 {
+                    None
+                } else {
+                    Some(self.getreg(inst.rs2) as u16)
+                };
+                self.tlb.flush(vaddr, asid);
+            }
+            Instruction::Sh(inst) => {
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                self.store(address, 2, self.getreg(inst.rs2))?;
             }
             Instruction::Sll(inst) => {
-                let shamt = self.getreg(inst.rs2) & 0b11111;
+                let shamt = self.getreg(inst.rs2) & 0b111111;
                 self.setreg(inst.rd, self.getreg(inst.rs1) << shamt);
             }
             Instruction::Slli(inst) => {
-                let shamt = inst.imm & 0b11111; // Shift amount
+                let shamt = inst.imm & 0b111111; // Shift amount
                 self.setreg(inst.rd, self.getreg(inst.rs1) << shamt);
             }
+            Instruction::Slliw(inst) => {
+                let shamt = inst.imm & 0b11111; // Shift amount
+                self.setreg(
+                    inst.rd,
+                    ((self.getreg(inst.rs1) as u32) << shamt) as i32 as i64 as u64,
+                );
+            }
+            Instruction::Sllw(inst) => {
+                let shamt = self.getreg(inst.rs2) & 0b11111; // Shift amount
+                self.setreg(
+                    inst.rd,
+                    ((self.getreg(inst.rs1) as u32) << shamt) as i32 as i64 as u64,
+                );
+            }
             Instruction::Slt(inst) => {
                 self.setreg(
                     inst.rd,
@@ -192,34 +1070,92 @@ impl Emulator {
                 );
             }
             Instruction::Sra(inst) => {
-                let shamt = self.getreg(inst.rs2) & 0b11111; // Shift amount
+                let shamt = self.getreg(inst.rs2) & 0b111111; // Shift amount
                 self.setreg(inst.rd, ((self.getreg(inst.rs1) as i64) >> shamt) as u64);
             }
+            Instruction::Sraw(inst) => {
+                let shamt = self.getreg(inst.rs2) & 0b11111; // Shift amount
+                self.setreg(
+                    inst.rd,
+                    ((self.getreg(inst.rs1) as i32) >> shamt) as i64 as u64,
+                );
+            }
             Instruction::Srai(inst) => {
-                let shamt = inst.imm & 0b11111; // Shift amount
+                let shamt = inst.imm & 0b111111; // Shift amount
                 self.setreg(inst.rd, ((self.getreg(inst.rs1) as i64) >> shamt) as u64);
             }
+            Instruction::Sraiw(inst) => {
+                let shamt = inst.imm & 0b11111; // Shift amount
+                self.setreg(
+                    inst.rd,
+                    ((self.getreg(inst.rs1) as i32) >> shamt) as i64 as u64,
+                );
+            }
             Instruction::Srl(inst) => {
-                let shamt = self.getreg(inst.rs2) & 0b11111; // Shift amount
+                let shamt = self.getreg(inst.rs2) & 0b111111; // Shift amount
                 self.setreg(inst.rd, self.getreg(inst.rs1) >> shamt);
             }
+            Instruction::Srlw(inst) => {
+                let shamt = self.getreg(inst.rs2) & 0b11111; // Shift amount
+                self.setreg(
+                    inst.rd,
+                    ((self.getreg(inst.rs1) as u32) >> shamt) as i32 as i64 as u64,
+                );
+            }
             Instruction::Srli(inst) => {
-                let shamt = inst.imm & 0b11111; // Shift amount
+                let shamt = inst.imm & 0b111111; // Shift amount
                 self.setreg(inst.rd, self.getreg(inst.rs1) >> shamt);
             }
+            Instruction::Srliw(inst) => {
+                let shamt = inst.imm & 0b11111; // Shift amount
+                self.setreg(
+                    inst.rd,
+                    ((self.getreg(inst.rs1) as u32) >> shamt) as i32 as i64 as u64,
+                );
+            }
             Instruction::Sub(inst) => {
                 self.setreg(
                     inst.rd,
                     self.getreg(inst.rs1).wrapping_sub(self.getreg(inst.rs2)),
                 );
             }
+            Instruction::Subw(inst) => {
+                self.setreg(
+                    inst.rd,
+                    (self.getreg(inst.rs1) as i32).wrapping_sub(self.getreg(inst.rs2) as i32) as i64
+                        as u64,
+                );
+            }
+            Instruction::Sret => {
+                let mstatus = self.csr.get(csr::MSTATUS);
+                if self.privilege == Privilege::User
+                    || self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_TSR != 0
+                {
+                    return Err(Exception::IllegalInstruction(0));
+                }
+                // Restore SIE from SPIE and return to the mode in SPP, which
+                // drops to U
+                let spie = mstatus & csr::MSTATUS_SPIE;
+                self.privilege = Privilege::from_bits((mstatus & csr::MSTATUS_SPP) >> 8);
+                // Returning below M-mode also clears MPRV
+                let mstatus = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV)
+                    | spie >> 4
+                    | csr::MSTATUS_SPIE;
+                self.csr.set(csr::MSTATUS, mstatus);
+                self.pc = self.csr.get(csr::SEPC);
+            }
             Instruction::Sw(inst) => {
-                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64) as usize;
-                let value = self.getreg(inst.rs2);
-                self.memory[address] = value as u8;
-                self.memory[address + 1] = (value >> 8) as u8;
-                self.memory[address + 2] = (value >> 16) as u8;
-                self.memory[address + 3] = (value >> 24) as u8;
+                let address = self.getreg(inst.rs1).wrapping_add(inst.imm as i64 as u64);
+                self.store(address, 4, self.getreg(inst.rs2))?;
+            }
+            Instruction::Wfi => {
+                // WFI is a hint, and completes immediately with any interrupt
+                // taken at the next instruction, unless TW forbids it
+                // outside M-mode
+                let mstatus = self.csr.get(csr::MSTATUS);
+                if self.privilege != Privilege::Machine && mstatus & csr::MSTATUS_TW != 0 {
+                    return Err(Exception::IllegalInstruction(0));
+                }
             }
             Instruction::Xor(inst) => {
                 self.setreg(inst.rd, self.getreg(inst.rs1) ^ self.getreg(inst.rs2))
@@ -228,18 +1164,163 @@ impl Emulator {
                 self.setreg(inst.rd, (inst.imm as i64 as u64) ^ self.getreg(inst.rs1))
             }
         }
+        Ok(())
     }
 
-    pub fn run(&mut self) {
-        loop {
-            if self.pc >= self.memory.len() as u64 {
-                break;
+    /// Enter the trap handler for a trap with the given mcause value, raised
+    /// by the instruction at `pc`. Traps taken in S-mode or U-mode go to
+    /// S-mode if medeleg (or mideleg, for interrupts) delegates them.
+    fn take_trap(&mut self, cause: u64, value: u64, pc: u64) {
+        let interrupt = cause >> 63 == 1;
+        let code = cause & !(1 << 63);
+        let delegation = if interrupt {
+            self.csr.get(csr::MIDELEG)
+        } else {
+            self.csr.get(csr::MEDELEG)
+        };
+        let previous = self.privilege;
+        let mstatus = self.csr.get(csr::MSTATUS);
+
+        let tvec = if previous <= Privilege::Supervisor && delegation >> code & 1 == 1 {
+            self.csr.set(csr::SEPC, pc);
+            self.csr.set(csr::SCAUSE, cause);
+            self.csr.set(csr::STVAL, value);
+            // Stack SIE into SPIE and record the previous mode in SPP
+            let sie = mstatus & csr::MSTATUS_SIE;
+            let spp = (previous as u64) << 8;
+            let mstatus = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPIE | csr::MSTATUS_SPP)
+                | sie << 4
+                | spp;
+            self.csr.set(csr::MSTATUS, mstatus);
+            self.privilege = Privilege::Supervisor;
+            self.csr.get(csr::STVEC)
+        } else {
+            self.csr.set(csr::MEPC, pc);
+            self.csr.set(csr::MCAUSE, cause);
+            self.csr.set(csr::MTVAL, value);
+            // Stack MIE into MPIE and record the previous mode in MPP
+            let mie = mstatus & csr::MSTATUS_MIE;
+            let mpp = (previous as u64) << 11;
+            let mstatus = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE | csr::MSTATUS_MPP)
+                | mie << 4
+                | mpp;
+            self.csr.set(csr::MSTATUS, mstatus);
+            self.privilege = Privilege::Machine;
+            self.csr.get(csr::MTVEC)
+        };
+
+        let base = tvec & !0b11;
+        self.pc = if interrupt && tvec & 0b11 == 1 {
+            // Vectored mode: interrupts jump to BASE + 4 * cause
+            base.wrapping_add(4 * code)
+        } else {
+            base
+        };
+    }
+
+    /// Advance devices by a cycle, and bring the state they drive in the
+    /// hart up to date: the `time` CSR follows the platform timer, if any,
+    /// and mip the interrupt lines
+    fn sync_devices(&mut self) {
+        self.bus.tick();
+        if let Some(time) = self.bus.time() {
+            self.csr.set(csr::TIME, time);
+        }
+        self.csr.set_interrupt_lines(self.bus.hart_interrupts());
+    }
+
+    /// The highest-priority interrupt that is pending and enabled in mie,
+    /// and not masked by the current privilege level. Interrupts going to
+    /// a more privileged mode are always taken; those going to the current
+    /// mode only if its global interrupt enable in mstatus is set.
+    fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.csr.mip() & self.csr.get(csr::MIE);
+        if pending == 0 {
+            return None;
+        }
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let mideleg = self.csr.get(csr::MIDELEG);
+        let mut enabled = 0;
+        if self.privilege < Privilege::Machine || mstatus & csr::MSTATUS_MIE != 0 {
+            enabled |= pending & !mideleg;
+        }
+        if self.privilege < Privilege::Supervisor
+            || self.privilege == Privilege::Supervisor && mstatus & csr::MSTATUS_SIE != 0
+        {
+            enabled |= pending & mideleg;
+        }
+        INTERRUPT_PRIORITY
+            .iter()
+            .copied()
+            .find(|code| enabled >> code & 1 == 1)
+    }
+
+    /// Fetch, decode and execute the instruction at the PC
+    pub fn step(&mut self) -> Result<(), Exception> {
+        let pc = self.pc;
+        let inst = self.fetch_instruction()?;
+        let decoded_inst =
+            decode_instruction(inst).map_err(|_| Exception::IllegalInstruction(inst as u64))?;
+        self.pc = pc.wrapping_add(if inst & 0b11 == 0b11 { 4 } else { 2 });
+        self.execute_instruction(decoded_inst, pc)
+            .map_err(|exception| match exception {
+                Exception::IllegalInstruction(_) => Exception::IllegalInstruction(inst as u64),
+                exception => exception,
+            })
+    }
+
+    /// Run until the program exits or an untranslated PC leaves RAM, taking
+    /// pending interrupts between instructions and delivering exceptions to
+    /// the guest's trap handler. An exception with no handler to go to, or
+    /// raised by the first instruction of the handler itself, would trap
+    /// forever, so it is returned instead, as are all exceptions of
+    /// programs whose syscalls are serviced by the host.
+    pub fn run(&mut self) -> Result<(), Exception> {
+        self.run_for(u64::MAX)
+    }
+
+    /// Like `run`, but stop after at most `limit` instructions, for
+    /// programs that may never finish
+    pub fn run_for(&mut self, limit: u64) -> Result<(), Exception> {
+        for _ in 0..limit {
+            if self.outside_ram() || self.exit_status().is_some() {
+                return Ok(());
+            }
+            self.csr.tick();
+            self.sync_devices();
+            if let Some(code) = self.pending_interrupt() {
+                self.take_trap(1 << 63 | code, 0, self.pc);
+            }
+            let pc = self.pc;
+            match self.step() {
+                Ok(()) => {
+                    self.csr.retire();
+                    self.poll_htif();
+                }
+                Err(exception) if self.syscalls.is_some() => {
+                    self.pc = pc;
+                    return Err(exception);
+                }
+                Err(exception) => {
+                    self.take_trap(exception.code(), exception.value(), pc);
+                    if self.pc == pc
+                        || !self.csr.has_trap_handler(self.privilege)
+                        || self.outside_ram()
+                    {
+                        self.pc = pc;
+                        return Err(exception);
+                    }
+                }
             }
-            let inst = self.fetch_instruction();
-            let decoded_inst = decode_instruction(inst).unwrap();
-            self.pc += 4;
-            self.execute_instruction(decoded_inst);
         }
+        Ok(())
+    }
+
+    /// Whether the PC is a physical address outside RAM. Under translation
+    /// the PC is a virtual address, which need not lie in RAM; fetching
+    /// where nothing is mapped traps instead.
+    fn outside_ram(&self) -> bool {
+        !self.translates(AccessType::Instruction) && self.bus.ram_range(self.pc, 1).is_none()
     }
 
     pub fn print_state(&self) {
@@ -247,13 +1328,28 @@ impl Emulator {
             print!("{:>3} = 0x{:08x} ", format!("x{}", i), self.regs[i]);
             print!("{:>3} = 0x{:08x} ", format!("x{}", i + 1), self.regs[i + 1]);
             print!("{:>3} = 0x{:08x} ", format!("x{}", i + 2), self.regs[i + 2]);
+            println!("{:>3} = 0x{:08x}", format!("x{}", i + 3), self.regs[i + 3]);
+        }
+        for i in (0..32).step_by(4) {
+            print!("{:>3} = 0x{:016x} ", format!("f{}", i), self.fregs[i]);
+            print!(
+                "{:>3} = 0x{:016x} ",
+                format!("f{}", i + 1),
+                self.fregs[i + 1]
+            );
             print!(
-                "{:>3} = 0x{:08x}\n",
-                format!("x{}", i + 3),
-                self.regs[i + 3]
+                "{:>3} = 0x{:016x} ",
+                format!("f{}", i + 2),
+                self.fregs[i + 2]
+            );
+            println!(
+                "{:>3} = 0x{:016x}",
+                format!("f{}", i + 3),
+                self.fregs[i + 3]
             );
         }
-        println!(" pc = 0x{:08x}", self.pc);
+        println!("fcsr = 0x{:02x}", self.csr.fcsr);
+        println!(" pc = {}", self.symbols.describe(self.pc));
     }
 }
 
@@ -261,6 +1357,14 @@ impl Emulator {
 mod test {
     use super::*;
 
+    /// An emulator running `code` with the floating-point unit switched on
+    fn float_emulator(code: Vec<u8>) -> Emulator {
+        let mut emu = Emulator::new(code);
+        let mstatus = emu.csr.get(csr::MSTATUS);
+        emu.csr.set(csr::MSTATUS, mstatus | csr::MSTATUS_FS_INITIAL);
+        emu
+    }
+
     #[test]
     fn test_addi_add() {
         let code = vec![
@@ -270,7 +1374,7 @@ mod test {
         ];
 
         let mut emu = Emulator::new(code);
-        emu.run();
+        emu.run().unwrap();
 
         assert_eq!(emu.getreg(10), 0x0a);
     }
@@ -283,7 +1387,7 @@ mod test {
         ];
 
         let mut emu = Emulator::new(code);
-        emu.run();
+        emu.run().unwrap();
 
         assert_eq!(emu.getreg(10), 0x00000517);
     }
@@ -298,11 +1402,477 @@ mod test {
         ];
 
         let mut emu = Emulator::new(code);
-        emu.run();
+        emu.run().unwrap();
 
         assert_eq!(emu.getreg(10), 0x00000005);
         assert_eq!(emu.getreg(11), 0x00000004);
         assert_eq!(emu.getreg(12), 0x00000007);
         assert_eq!(emu.getreg(13), 0x0000000f);
     }
+
+    #[test]
+    fn test_64bit_shifts() {
+        let code = vec![
+            0x13, 0x05, 0xf0, 0xff, // addi a0,x0,-1
+            0x13, 0x15, 0x85, 0x02, // slli a0,a0,40
+            0x93, 0x55, 0x45, 0x02, // srli a1,a0,36
+            0x13, 0x56, 0x45, 0x42, // srai a2,a0,36
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(10), 0xffffff0000000000);
+        assert_eq!(emu.getreg(11), 0x000000000ffffff0);
+        assert_eq!(emu.getreg(12), 0xfffffffffffffff0);
+    }
+
+    #[test]
+    fn test_word_arithmetic() {
+        let code = vec![
+            0x37, 0x05, 0x00, 0x80, // lui   a0,0x80000
+            0x9b, 0x05, 0xf5, 0xff, // addiw a1,a0,-1
+            0x3b, 0x06, 0xa5, 0x00, // addw  a2,a0,a0
+            0xbb, 0x06, 0xa0, 0x40, // subw  a3,x0,a0
+            0x1b, 0x57, 0x45, 0x40, // sraiw a4,a0,4
+            0x9b, 0x57, 0x45, 0x00, // srliw a5,a0,4
+            0x1b, 0x98, 0x15, 0x00, // slliw a6,a1,1
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(10), 0xffffffff80000000);
+        assert_eq!(emu.getreg(11), 0x000000007fffffff);
+        assert_eq!(emu.getreg(12), 0x0000000000000000);
+        assert_eq!(emu.getreg(13), 0xffffffff80000000);
+        assert_eq!(emu.getreg(14), 0xfffffffff8000000);
+        assert_eq!(emu.getreg(15), 0x0000000008000000);
+        assert_eq!(emu.getreg(16), 0xfffffffffffffffe);
+    }
+
+    #[test]
+    fn test_word_shifts() {
+        let code = vec![
+            0x13, 0x05, 0x10, 0x02, // li   a0,33
+            0x93, 0x05, 0xf0, 0xff, // li   a1,-1
+            0x3b, 0x96, 0xa5, 0x00, // sllw a2,a1,a0
+            0xbb, 0xd6, 0xa5, 0x00, // srlw a3,a1,a0
+            0x3b, 0xd7, 0xa5, 0x40, // sraw a4,a1,a0
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(12), 0xfffffffffffffffe);
+        assert_eq!(emu.getreg(13), 0x000000007fffffff);
+        assert_eq!(emu.getreg(14), 0xffffffffffffffff);
+    }
+
+    #[test]
+    fn test_sd_ld_lwu() {
+        let code = vec![
+            0x13, 0x05, 0xd0, 0xff, // li  a0,-3
+            0x23, 0x30, 0xa0, 0x00, // sd  a0,0(x0)
+            0x83, 0x35, 0x00, 0x00, // ld  a1,0(x0)
+            0x03, 0x26, 0x00, 0x00, // lw  a2,0(x0)
+            0x83, 0x66, 0x00, 0x00, // lwu a3,0(x0)
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(11), 0xfffffffffffffffd);
+        assert_eq!(emu.getreg(12), 0xfffffffffffffffd);
+        assert_eq!(emu.getreg(13), 0x00000000fffffffd);
+    }
+
+    /// Run a single R-type instruction `op a2,a0,a1` and return the value of a2
+    fn run_rtype(inst: [u8; 4], rs1: u64, rs2: u64) -> u64 {
+        let mut emu = Emulator::new(inst.to_vec());
+        emu.setreg(10, rs1);
+        emu.setreg(11, rs2);
+        emu.run().unwrap();
+        emu.getreg(12)
+    }
+
+    #[test]
+    fn test_mul() {
+        let mul = [0x33, 0x06, 0xb5, 0x02]; // mul    a2,a0,a1
+        let mulh = [0x33, 0x16, 0xb5, 0x02]; // mulh   a2,a0,a1
+        let mulhsu = [0x33, 0x26, 0xb5, 0x02]; // mulhsu a2,a0,a1
+        let mulhu = [0x33, 0x36, 0xb5, 0x02]; // mulhu  a2,a0,a1
+        let mulw = [0x3b, 0x06, 0xb5, 0x02]; // mulw   a2,a0,a1
+
+        assert_eq!(run_rtype(mul, -1i64 as u64, 3), -3i64 as u64);
+        assert_eq!(run_rtype(mul, 1 << 63, 2), 0);
+
+        assert_eq!(run_rtype(mulh, -1i64 as u64, -1i64 as u64), 0);
+        assert_eq!(run_rtype(mulh, -1i64 as u64, 1), u64::MAX);
+        assert_eq!(run_rtype(mulh, 1 << 63, 1 << 63), 0x4000000000000000);
+
+        assert_eq!(run_rtype(mulhsu, -1i64 as u64, u64::MAX), u64::MAX);
+        assert_eq!(run_rtype(mulhsu, 2, u64::MAX), 1);
+        assert_eq!(run_rtype(mulhsu, 1 << 63, u64::MAX), 0x8000000000000000);
+
+        assert_eq!(run_rtype(mulhu, u64::MAX, u64::MAX), 0xfffffffffffffffe);
+        assert_eq!(run_rtype(mulhu, u64::MAX, 2), 1);
+
+        assert_eq!(run_rtype(mulw, 0x7fffffff, 2), 0xfffffffffffffffe);
+        assert_eq!(run_rtype(mulw, 0xffffffff00000003, 3), 9);
+    }
+
+    #[test]
+    fn test_div_rem() {
+        let div = [0x33, 0x46, 0xb5, 0x02]; // div  a2,a0,a1
+        let divu = [0x33, 0x56, 0xb5, 0x02]; // divu a2,a0,a1
+        let rem = [0x33, 0x66, 0xb5, 0x02]; // rem  a2,a0,a1
+        let remu = [0x33, 0x76, 0xb5, 0x02]; // remu a2,a0,a1
+        let min = i64::MIN as u64;
+
+        assert_eq!(run_rtype(div, 7, -2i64 as u64), -3i64 as u64);
+        assert_eq!(run_rtype(div, 7, 0), u64::MAX);
+        assert_eq!(run_rtype(div, min, -1i64 as u64), min);
+
+        assert_eq!(run_rtype(divu, u64::MAX, 2), 0x7fffffffffffffff);
+        assert_eq!(run_rtype(divu, 7, 0), u64::MAX);
+
+        assert_eq!(run_rtype(rem, -7i64 as u64, 2), -1i64 as u64);
+        assert_eq!(run_rtype(rem, -7i64 as u64, 0), -7i64 as u64);
+        assert_eq!(run_rtype(rem, min, -1i64 as u64), 0);
+
+        assert_eq!(run_rtype(remu, u64::MAX, 10), 5);
+        assert_eq!(run_rtype(remu, 7, 0), 7);
+    }
+
+    #[test]
+    fn test_div_rem_word() {
+        let divw = [0x3b, 0x46, 0xb5, 0x02]; // divw  a2,a0,a1
+        let divuw = [0x3b, 0x56, 0xb5, 0x02]; // divuw a2,a0,a1
+        let remw = [0x3b, 0x66, 0xb5, 0x02]; // remw  a2,a0,a1
+        let remuw = [0x3b, 0x76, 0xb5, 0x02]; // remuw a2,a0,a1
+        let min = i32::MIN as u32 as u64;
+
+        assert_eq!(run_rtype(divw, -7i64 as u64, 2), -3i64 as u64);
+        assert_eq!(run_rtype(divw, 7, 0), u64::MAX);
+        assert_eq!(run_rtype(divw, min, u64::MAX), 0xffffffff80000000);
+        assert_eq!(run_rtype(divw, 0x100000007, 0x100000000), u64::MAX);
+
+        assert_eq!(run_rtype(divuw, min, 1), 0xffffffff80000000);
+        assert_eq!(run_rtype(divuw, 7, 0), u64::MAX);
+
+        assert_eq!(run_rtype(remw, -7i64 as u64, 2), -1i64 as u64);
+        assert_eq!(run_rtype(remw, min, u64::MAX), 0);
+        assert_eq!(run_rtype(remw, 0x1fffffff9, 0), -7i64 as u64);
+
+        assert_eq!(run_rtype(remuw, min, 0), 0xffffffff80000000);
+        assert_eq!(run_rtype(remuw, 0xffffffff, 10), 5);
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        let code = vec![
+            0x93, 0x06, 0xa0, 0x02, // li   a3,42
+            0x2f, 0x35, 0x00, 0x10, // lr.d a0,(x0)
+            0x2f, 0x36, 0xd0, 0x18, // sc.d a2,a3,(x0)
+            0x2f, 0x37, 0xd0, 0x18, // sc.d a4,a3,(x0)
+            0x2f, 0x25, 0x00, 0x10, // lr.w a0,(x0)
+            0x23, 0x22, 0x00, 0x00, // sw   x0,4(x0)
+            0xaf, 0x27, 0xd0, 0x18, // sc.w a5,a3,(x0)
+            0x2f, 0x25, 0x00, 0x10, // lr.w a0,(x0)
+            0x23, 0x24, 0x00, 0x00, // sw   x0,8(x0)
+            0x2f, 0x28, 0xd0, 0x18, // sc.w a6,a3,(x0)
+            0x83, 0x38, 0x00, 0x00, // ld   a7,0(x0)
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(12), 0); // reserved, succeeds
+        assert_eq!(emu.getreg(14), 1); // reservation consumed by previous sc
+        assert_eq!(emu.getreg(15), 1); // invalidated by overlapping store
+        assert_eq!(emu.getreg(16), 0); // store outside the reservation set
+        assert_eq!(emu.getreg(17), 42);
+        assert_eq!(emu.reservation, None);
+    }
+
+    #[test]
+    fn test_amo() {
+        let code = vec![
+            0x13, 0x05, 0xf0, 0xff, // li        a0,-1
+            0xaf, 0x25, 0xa0, 0x00, // amoadd.w  a1,a0,(x0)
+            0x03, 0x26, 0x00, 0x00, // lw        a2,0(x0)
+            0xaf, 0x36, 0xa0, 0x08, // amoswap.d a3,a0,(x0)
+            0x2f, 0x27, 0x00, 0xc0, // amominu.w a4,x0,(x0)
+            0x83, 0x37, 0x00, 0x00, // ld        a5,0(x0)
+            0x2f, 0x38, 0xa0, 0xa0, // amomax.d  a6,a0,(x0)
+            0x83, 0x38, 0x00, 0x00, // ld        a7,0(x0)
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(11), 0xfffffffffff00513);
+        assert_eq!(emu.getreg(12), 0xfffffffffff00512);
+        assert_eq!(emu.getreg(13), 0x00a025affff00512);
+        assert_eq!(emu.getreg(14), 0xffffffffffffffff);
+        assert_eq!(emu.getreg(15), 0xffffffff00000000);
+        assert_eq!(emu.getreg(16), 0xffffffff00000000);
+        assert_eq!(emu.getreg(17), 0xffffffffffffffff);
+    }
+
+    #[test]
+    fn test_double_precision() {
+        let code = vec![
+            0x13, 0x05, 0x30, 0x00, // li       a0,3
+            0x53, 0x75, 0x25, 0xd2, // fcvt.d.l fa0,a0
+            0x93, 0x05, 0x20, 0x00, // li       a1,2
+            0xd3, 0xf5, 0x05, 0xd0, // fcvt.s.w fa1,a1
+            0x53, 0x86, 0x05, 0x42, // fcvt.d.s fa2,fa1
+            0xd3, 0x76, 0xc5, 0x1a, // fdiv.d   fa3,fa0,fa2
+            0x53, 0x86, 0x06, 0xe2, // fmv.x.d  a2,fa3
+            0x43, 0xf7, 0xd6, 0x52, // fmadd.d  fa4,fa3,fa3,fa0
+            0xd3, 0x76, 0x07, 0xc2, // fcvt.w.d a3,fa4
+            0x53, 0x87, 0x05, 0xe0, // fmv.x.w  a4,fa1
+            0xd3, 0x07, 0x05, 0xf2, // fmv.d.x  fa5,a0
+            0x53, 0xf8, 0xb7, 0x00, // fadd.s   fa6,fa5,fa1
+            0xd3, 0x07, 0x08, 0xe0, // fmv.x.w  a5,fa6
+        ];
+
+        let mut emu = float_emulator(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getfreg_d(14), 5.25);
+        assert_eq!(emu.getreg(12), 0x3ff8000000000000); // 1.5
+        assert_eq!(emu.getreg(13), 5);
+        assert_eq!(emu.getreg(14), 0x40000000); // 2.0f
+        assert_eq!(emu.getreg(15), 0x7fc00000); // fa5 is not NaN-boxed
+        assert_eq!(emu.csr.fcsr, float::FLAG_NX);
+    }
+
+    #[test]
+    fn test_float_disabled() {
+        // fcvt.d.l fa0,a0
+        let mut emu = Emulator::new(vec![0x53, 0x75, 0x25, 0xd2]);
+        emu.regs[10] = 3;
+        assert_eq!(emu.step(), Err(Exception::IllegalInstruction(0xd2257553)));
+        assert_eq!(emu.fregs[10], 0);
+        assert_eq!(emu.csr.get(csr::MSTATUS) & csr::MSTATUS_FS, 0);
+    }
+
+    #[test]
+    fn test_fflags_dirty() {
+        // fcvt.w.s a0,fa0 raises only the inexact flag
+        let mut emu = float_emulator(vec![0x53, 0x75, 0x05, 0xc0]);
+        emu.fregs[10] = 0xffffffff_3fc00000; // 1.5f
+        emu.step().unwrap();
+        assert_eq!(emu.getreg(10), 2);
+        assert_eq!(emu.csr.fcsr, float::FLAG_NX);
+        assert_eq!(emu.csr.get(csr::MSTATUS) & csr::MSTATUS_FS, csr::MSTATUS_FS);
+    }
+
+    #[test]
+    fn test_single_precision() {
+        let code = vec![
+            0x13, 0x05, 0x90, 0xff, // li        a0,-7
+            0x53, 0x75, 0x05, 0xd0, // fcvt.s.w  fa0,a0
+            0x27, 0x20, 0xa0, 0x00, // fsw       fa0,0(x0)
+            0x87, 0x25, 0x00, 0x00, // flw       fa1,0(x0)
+            0x53, 0x96, 0xb5, 0x20, // fneg.s    fa2,fa1
+            0xd3, 0x15, 0x06, 0xe0, // fclass.s  a1,fa2
+            0xd3, 0x76, 0x06, 0x58, // fsqrt.s   fa3,fa2
+            0x53, 0x86, 0x06, 0xe0, // fmv.x.w   a2,fa3
+            0xd3, 0x16, 0x15, 0xc0, // fcvt.wu.s a3,fa0,rtz
+        ];
+
+        let mut emu = float_emulator(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.fregs[11], 0xffffffffc0e00000); // NaN-boxed -7.0f
+        assert_eq!(emu.getreg(11), 1 << 6); // positive normal
+        assert_eq!(emu.getreg(12), 0x402953fd); // sqrt(7)
+        assert_eq!(emu.getreg(13), 0);
+        assert_eq!(emu.csr.fcsr, float::FLAG_NV | float::FLAG_NX);
+    }
+
+    #[test]
+    fn test_compressed() {
+        let code = vec![
+            0x01, 0x45, //             c.li   a0,0
+            0x95, 0x45, //             c.li   a1,5
+            0x0d, 0x05, //             c.addi a0,3
+            0x93, 0x85, 0xf5, 0xff, // addi   a1,a1,-1
+            0xed, 0xfd, //             c.bnez a1,-6
+            0x17, 0x06, 0x00, 0x00, // auipc  a2,0x0
+            0x11, 0xa0, //             c.j    4
+            0x85, 0x46, //             c.li   a3,1
+            0xef, 0x00, 0x40, 0x00, // jal    ra,4
+            0x06, 0x87, //             c.mv   a4,ra
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(10), 15);
+        assert_eq!(emu.getreg(11), 0);
+        assert_eq!(emu.getreg(12), 12);
+        assert_eq!(emu.getreg(13), 0);
+        assert_eq!(emu.getreg(14), 24);
+    }
+
+    #[test]
+    fn test_csr() {
+        let code = vec![
+            0x13, 0x05, 0x50, 0x00, // addi   a0,x0,5
+            0xf3, 0x15, 0x05, 0x34, // csrrw  a1,mscratch,a0
+            0x73, 0x66, 0x01, 0x34, // csrrsi a2,mscratch,2
+            0xf3, 0xf6, 0x00, 0x34, // csrrci a3,mscratch,1
+            0x73, 0x27, 0x00, 0x34, // csrrs  a4,mscratch,x0
+            0x73, 0xd0, 0x21, 0x00, // csrrwi x0,frm,3
+            0xf3, 0x37, 0x30, 0x00, // csrrc  a5,fcsr,x0
+            0x73, 0x28, 0x20, 0xc0, // csrrs  a6,instret,x0
+            0xf3, 0x28, 0x40, 0xf1, // csrrs  a7,mhartid,x0
+        ];
+
+        let mut emu = float_emulator(code);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(11), 0);
+        assert_eq!(emu.getreg(12), 5);
+        assert_eq!(emu.getreg(13), 7);
+        assert_eq!(emu.getreg(14), 6);
+        assert_eq!(emu.getreg(15), 0x60);
+        assert_eq!(emu.getreg(16), 7);
+        assert_eq!(emu.getreg(17), 0);
+    }
+
+    #[test]
+    fn test_trap_and_mret() {
+        let code = vec![
+            0x97, 0x02, 0x00, 0x00, // auipc t0,0
+            0x93, 0x82, 0x92, 0x01, // addi  t0,t0,25
+            0x73, 0x90, 0x52, 0x30, // csrrw x0,mtvec,t0 (vectored, base 24)
+            0xff, 0xff, 0xff, 0xff, // illegal instruction
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x6f, 0x00, 0x40, 0x02, // jal   x0,36
+            // trap handler
+            0x73, 0x25, 0x20, 0x34, // csrrs a0,mcause,x0
+            0x33, 0x04, 0xa4, 0x00, // add   s0,s0,a0
+            0xf3, 0x25, 0x30, 0x34, // csrrs a1,mtval,x0
+            0xb3, 0xe4, 0xb4, 0x00, // or    s1,s1,a1
+            0xf3, 0x25, 0x10, 0x34, // csrrs a1,mepc,x0
+            0x93, 0x85, 0x45, 0x00, // addi  a1,a1,4
+            0x73, 0x90, 0x15, 0x34, // csrrw x0,mepc,a1
+            0x73, 0x00, 0x20, 0x30, // mret
+        ];
+
+        let mut emu = Emulator::new(code);
+        let mstatus = emu.csr.get(csr::MSTATUS);
+        emu.csr.set(csr::MSTATUS, mstatus | csr::MSTATUS_MIE);
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(8), 2 + 11); // illegal instruction + ecall
+        assert_eq!(emu.getreg(9), 0xffffffff);
+        assert_eq!(emu.csr.get(csr::MEPC), 20);
+        // mret restored MIE and left MPP at U
+        assert_eq!(
+            emu.csr.get(csr::MSTATUS),
+            mstatus | csr::MSTATUS_MPIE | csr::MSTATUS_MIE
+        );
+        assert_eq!(emu.privilege, Privilege::Machine);
+        // Neither the illegal instruction nor the ecall retires
+        assert_eq!(emu.csr.get(csr::MINSTRET), 20);
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let code = vec![
+            0x37, 0x05, 0x00, 0x10, // lui  a0,0x10000
+            0x83, 0x35, 0x05, 0x00, // ld   a1,0(a0)
+            0x2f, 0xa5, 0x05, 0x10, // lr.w a0,(a1)
+        ];
+
+        let mut emu = Emulator::new(code);
+        emu.step().unwrap();
+        assert_eq!(emu.step(), Err(Exception::LoadAccessFault(0x10000000)));
+
+        emu.pc = 8;
+        emu.regs[11] = 2;
+        assert_eq!(emu.step(), Err(Exception::LoadAddressMisaligned(2)));
+
+        // The handler at mtvec = 0 faults on its own first instruction
+        let mut emu = Emulator::new(vec![0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(emu.run(), Err(Exception::IllegalInstruction(0xffffffff)));
+        assert_eq!(emu.csr.get(csr::MCAUSE), 2);
+
+        // Without a handler, a fault anywhere stops the program, rather
+        // than restarting it at mtvec = 0
+        let code = vec![
+            0x13, 0x05, 0x10, 0x00, // li a0,1
+            0x83, 0x35, 0xf0, 0xff, // ld a1,-1(zero)
+        ];
+        let mut emu = Emulator::new(code);
+        assert_eq!(emu.run(), Err(Exception::LoadAccessFault(u64::MAX)));
+        assert_eq!(emu.pc, 4);
+
+        // Nor is a trap to a vector outside RAM a normal halt
+        let mut emu = Emulator::new(vec![0x03, 0x35, 0x00, 0x00]); // ld a0,0(zero)
+        emu.bus.ram_base = 0x8000_0000;
+        emu.pc = 0x8000_0000;
+        emu.csr.set(csr::MTVEC, 0x100);
+        assert_eq!(emu.run(), Err(Exception::LoadAccessFault(0)));
+        assert_eq!(emu.pc, 0x8000_0000);
+    }
+
+    #[test]
+    fn test_privilege_modes() {
+        let code = vec![
+            0x97, 0x02, 0x00, 0x00, // auipc t0, 0
+            0x13, 0x83, 0x82, 0x02, // addi t1, t0, 40
+            0x73, 0x10, 0x13, 0x34, // csrrw x0, mepc, t1
+            0x13, 0x83, 0x82, 0x03, // addi t1, t0, 56
+            0x73, 0x10, 0x53, 0x10, // csrrw x0, stvec, t1
+            0x13, 0x03, 0x00, 0x10, // addi t1, x0, 256
+            0x73, 0x10, 0x23, 0x30, // csrrw x0, medeleg, t1
+            0x13, 0x83, 0x02, 0x05, // addi t1, t0, 80
+            0x73, 0x10, 0x53, 0x30, // csrrw x0, mtvec, t1
+            0x73, 0x00, 0x20, 0x30, // mret
+            // U-mode
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x73, 0x25, 0x00, 0x10, // csrrs a0, sstatus, x0
+            0x93, 0x05, 0x10, 0x00, // addi a1, x0, 1
+            0x6f, 0x00, 0x40, 0x03, // jal x0, 0x68 <end>
+            // S-mode handler
+            0x73, 0x24, 0x20, 0x14, // csrrs s0, scause, x0
+            0xf3, 0x24, 0x00, 0x10, // csrrs s1, sstatus, x0
+            0xf3, 0x23, 0x10, 0x14, // csrrs t2, sepc, x0
+            0x93, 0x83, 0x43, 0x00, // addi t2, t2, 4
+            0x73, 0x90, 0x13, 0x14, // csrrw x0, sepc, t2
+            0x73, 0x00, 0x20, 0x10, // sret
+            // M-mode handler
+            0x73, 0x29, 0x20, 0x34, // csrrs s2, mcause, x0
+            0xf3, 0x29, 0x00, 0x30, // csrrs s3, mstatus, x0
+            0xf3, 0x23, 0x10, 0x34, // csrrs t2, mepc, x0
+            0x93, 0x83, 0x43, 0x00, // addi t2, t2, 4
+            0x73, 0x90, 0x13, 0x34, // csrrw x0, mepc, t2
+            0x73, 0x00, 0x20, 0x30, // mret
+        ];
+
+        let mut emu = Emulator::new(code);
+        // Allow S-mode and U-mode access to all of memory
+        emu.csr.set(csr::PMPADDR0, u64::MAX >> 10);
+        emu.csr.set(csr::PMPCFG0, 0x1f);
+        emu.run().unwrap();
+
+        // The ecall from U-mode was delegated to S-mode
+        assert_eq!(emu.getreg(8), 8);
+        assert_eq!(emu.getreg(9) & csr::MSTATUS_SPP, 0);
+        // Reading sstatus from U-mode traps to M-mode
+        assert_eq!(emu.getreg(18), 2);
+        assert_eq!(emu.getreg(19) & csr::MSTATUS_MPP, 0);
+        assert_eq!(emu.csr.get(csr::MTVAL), 0x10002573);
+        assert_eq!(emu.getreg(10), 0);
+        assert_eq!(emu.getreg(11), 1);
+        assert_eq!(emu.privilege, Privilege::User);
+    }
 }
@@ -0,0 +1,398 @@
+use crate::clint::TIMEBASE_FREQUENCY;
+use crate::decoder;
+use crate::emulator::Emulator;
+use crate::mmu::PAGE_SIZE;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const HEADER_SIZE: usize = 40;
+/// An empty memory reservation block: just its terminating entry
+const RESERVATION_BLOCK_SIZE: usize = 16;
+
+/// Phandles of the nodes that others refer to: the hart's local interrupt
+/// controller and the PLIC
+pub const CPU_INTC_PHANDLE: u32 = 1;
+pub const PLIC_PHANDLE: u32 = 2;
+
+/// Builder of a flattened device tree blob, as nodes are opened, given
+/// properties and closed in order
+#[derive(Default)]
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// Names of the open nodes, from the root
+    path: Vec<String>,
+    stdout_path: Option<String>,
+}
+
+impl Fdt {
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// Append `bytes` to the structure block, padded to a whole token
+    fn padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        let padding = (4 - self.structure.len() % 4) % 4;
+        self.structure.resize(self.structure.len() + padding, 0);
+    }
+
+    /// Offset of a property name in the strings block, adding it if new
+    fn string(&mut self, name: &str) -> u32 {
+        let mut entry = name.as_bytes().to_vec();
+        entry.push(0);
+        let mut offset = 0;
+        for string in self.strings.split_inclusive(|&byte| byte == 0) {
+            if string == entry.as_slice() {
+                return offset as u32;
+            }
+            offset += string.len();
+        }
+        self.strings.extend_from_slice(&entry);
+        offset as u32
+    }
+
+    /// Open a node within the current one; the root node's name is empty
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        self.padded(&bytes);
+        self.path.push(name.to_string());
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+        self.path.pop();
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = self.string(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset);
+        self.padded(value);
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property_cells(name, &[value]);
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    /// A property of 64-bit values, two cells each, such as `reg` with
+    /// two address and two size cells
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let value: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// Make the open node the one `/chosen` names as the console
+    pub fn set_stdout(&mut self) {
+        let path = self.path[1..].join("/");
+        self.stdout_path = Some(format!("/{}", path));
+    }
+
+    /// The blob, once every node has been closed
+    pub fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        let structure = HEADER_SIZE + RESERVATION_BLOCK_SIZE;
+        let strings = structure + self.structure.len();
+        let size = strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            size as u32,
+            structure as u32,
+            strings as u32,
+            HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            // The boot hart
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.resize(structure, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+impl Emulator {
+    /// Where `load_device_tree` puts a blob of `size` bytes: in whole pages
+    /// after the page holding the end of RAM
+    fn device_tree_range(&self, size: usize) -> (u64, u64) {
+        let start = (self.bus.ram.len() as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let end = start + (size as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        (self.bus.ram_base + start, self.bus.ram_base + end)
+    }
+
+    /// A device tree describing the machine: its RAM, including the pages
+    /// `load_device_tree` adds for the tree itself, its single hart with
+    /// the extensions the decoder supports, and the devices on the bus.
+    /// `bootargs` is the kernel command line.
+    pub fn device_tree(&self, bootargs: Option<&str>) -> Vec<u8> {
+        // The size of the tree does not depend on the size of RAM it
+        // describes, so a first pass tells how much RAM there will be
+        let (_, end) = self.device_tree_range(self.build_device_tree(0, bootargs).len());
+        self.build_device_tree(end - self.bus.ram_base, bootargs)
+    }
+
+    fn build_device_tree(&self, ram_size: u64, bootargs: Option<&str>) -> Vec<u8> {
+        let mut fdt = Fdt::default();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "riscv-virtio");
+        fdt.property_string("model", "riscv-virtio,rvemu");
+
+        fdt.begin_node(&format!("memory@{:x}", self.bus.ram_base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_u64s("reg", &[self.bus.ram_base, ram_size]);
+        fdt.end_node();
+
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY as u32);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", 0);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &decoder::isa_string());
+        fdt.property_string("riscv,isa-base", "rv64i");
+        let extensions: Vec<&str> = Some("i").into_iter().chain(decoder::extensions()).collect();
+        fdt.property_strings("riscv,isa-extensions", &extensions);
+        fdt.property_string("mmu-type", "riscv,sv57");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_empty("ranges");
+        self.bus.device_tree(&mut fdt);
+        fdt.end_node();
+
+        fdt.begin_node("chosen");
+        if let Some(bootargs) = bootargs {
+            fdt.property_string("bootargs", bootargs);
+        }
+        if let Some(path) = fdt.stdout_path.clone() {
+            fdt.property_string("stdout-path", &path);
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    /// Add pages holding a device tree blob to the end of RAM, and pass it
+    /// to the program as boot loaders do: with its address in a1, and the
+    /// hart's ID in a0. Returns the address.
+    pub fn load_device_tree(&mut self, dtb: &[u8]) -> u64 {
+        let (start, end) = self.device_tree_range(dtb.len());
+        self.bus.ram.resize((end - self.bus.ram_base) as usize, 0);
+        let offset = (start - self.bus.ram_base) as usize;
+        self.bus.ram[offset..offset + dtb.len()].copy_from_slice(dtb);
+        self.regs[10] = 0;
+        self.regs[11] = start;
+        start
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clint::{Clint, TimeBase, CLINT_BASE, CLINT_SIZE};
+    use crate::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+    use crate::uart::{Uart, UART_BASE, UART_IRQ, UART_SIZE};
+    use std::convert::TryFrom;
+    use std::io;
+    use std::sync::mpsc;
+
+    fn word(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(<[u8; 4]>::try_from(&blob[offset..offset + 4]).unwrap())
+    }
+
+    /// The properties of a blob as their node's path, name and value
+    fn properties(blob: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        let structure = word(blob, 8) as usize;
+        let strings = word(blob, 12) as usize;
+        let mut path: Vec<String> = Vec::new();
+        let mut properties = Vec::new();
+        let mut offset = structure;
+        loop {
+            let token = word(blob, offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let length = blob[offset..].iter().position(|&byte| byte == 0).unwrap();
+                    path.push(String::from_utf8(blob[offset..offset + length].to_vec()).unwrap());
+                    offset += (length + 1).div_ceil(4) * 4;
+                }
+                FDT_END_NODE => {
+                    path.pop();
+                }
+                FDT_PROP => {
+                    let length = word(blob, offset) as usize;
+                    let name = strings + word(blob, offset + 4) as usize;
+                    let name_length = blob[name..].iter().position(|&byte| byte == 0).unwrap();
+                    let name = String::from_utf8(blob[name..name + name_length].to_vec()).unwrap();
+                    let value = blob[offset + 8..offset + 8 + length].to_vec();
+                    properties.push((path.join("/"), name, value));
+                    offset += 8 + length.div_ceil(4) * 4;
+                }
+                FDT_END => return properties,
+                _ => panic!("bad token {}", token),
+            }
+        }
+    }
+
+    fn property(blob: &[u8], path: &str, name: &str) -> Option<Vec<u8>> {
+        properties(blob)
+            .into_iter()
+            .find(|(node, property, _)| node == path && property == name)
+            .map(|(_, _, value)| value)
+    }
+
+    #[test]
+    fn test_fdt() {
+        let mut fdt = Fdt::default();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("node@1");
+        fdt.property_strings("compatible", &["a", "bc"]);
+        fdt.property_u32("#size-cells", 1);
+        fdt.set_stdout();
+        fdt.end_node();
+        fdt.end_node();
+        assert_eq!(fdt.stdout_path.as_deref(), Some("/node@1"));
+        let blob = fdt.finish();
+
+        assert_eq!(word(&blob, 0), FDT_MAGIC);
+        assert_eq!(word(&blob, 4) as usize, blob.len());
+        assert_eq!(word(&blob, 20), 17);
+        // Property names are stored once
+        assert_eq!(
+            &blob[word(&blob, 12) as usize..],
+            b"#size-cells\0compatible\0"
+        );
+        assert_eq!(
+            properties(&blob),
+            [
+                ("".to_string(), "#size-cells".to_string(), vec![0, 0, 0, 2]),
+                (
+                    "/node@1".to_string(),
+                    "compatible".to_string(),
+                    b"a\0bc\0".to_vec()
+                ),
+                (
+                    "/node@1".to_string(),
+                    "#size-cells".to_string(),
+                    vec![0, 0, 0, 1]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_device_tree() {
+        let mut emu = Emulator::new(vec![0; 0x2100]);
+        emu.bus.ram_base = 0x8000_0000;
+        emu.bus.add_device(
+            CLINT_BASE,
+            CLINT_SIZE,
+            Box::new(Clint::new(TimeBase::Instructions)),
+        );
+        emu.bus
+            .add_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(95)));
+        let (_, input) = mpsc::channel();
+        let uart = Uart::new(input, Box::new(io::sink()));
+        emu.bus
+            .add_device_with_irq(UART_BASE, UART_SIZE, UART_IRQ, Box::new(uart));
+
+        let blob = emu.device_tree(Some("console=ttyS0"));
+        // RAM includes the page the tree is loaded in
+        let reg = property(&blob, "/memory@80000000", "reg").unwrap();
+        assert_eq!(
+            reg,
+            [0x8000_0000u64.to_be_bytes(), 0x4000u64.to_be_bytes()].concat()
+        );
+        assert_eq!(
+            property(&blob, "/cpus/cpu@0", "riscv,isa").unwrap(),
+            b"rv64imafdc_zicsr_zifencei\0"
+        );
+        assert_eq!(
+            property(&blob, "/cpus", "timebase-frequency").unwrap(),
+            10_000_000u32.to_be_bytes()
+        );
+        assert_eq!(
+            property(&blob, "/soc/plic@c000000", "interrupts-extended").unwrap(),
+            [0, 0, 0, 1, 0, 0, 0, 11, 0, 0, 0, 1, 0, 0, 0, 9]
+        );
+        assert_eq!(
+            property(&blob, "/soc/serial@10000000", "interrupts").unwrap(),
+            [0, 0, 0, 10]
+        );
+        assert!(property(&blob, "/soc/clint@2000000", "reg").is_some());
+        assert_eq!(
+            property(&blob, "/chosen", "stdout-path").unwrap(),
+            b"/soc/serial@10000000\0"
+        );
+        assert_eq!(
+            property(&blob, "/chosen", "bootargs").unwrap(),
+            b"console=ttyS0\0"
+        );
+
+        let address = emu.load_device_tree(&blob);
+        assert_eq!(address, 0x8000_3000);
+        assert_eq!(emu.bus.ram.len(), 0x4000);
+        assert_eq!(emu.regs[11], address);
+        assert_eq!(
+            emu.bus.read(address, 4),
+            Some(FDT_MAGIC.swap_bytes() as u64)
+        );
+    }
+}
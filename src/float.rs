@@ -0,0 +1,828 @@
+//! IEEE 754 arithmetic for the F and D extensions.
+//!
+//! The host FPU only rounds to nearest-even and does not expose exception
+//! flags, so operations are carried out on unpacked integer significands
+//! (exact up to a sticky bit) and rounded once in the requested mode.
+
+// Accrued exception flags (fflags)
+pub const FLAG_NX: u32 = 1 << 0; // Inexact
+pub const FLAG_UF: u32 = 1 << 1; // Underflow
+pub const FLAG_OF: u32 = 1 << 2; // Overflow
+pub const FLAG_DZ: u32 = 1 << 3; // Divide by zero
+pub const FLAG_NV: u32 = 1 << 4; // Invalid operation
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoundingMode {
+    NearestEven,
+    TowardZero,
+    Down,
+    Up,
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Decode a static rm field or the frm CSR; the dynamic encoding 0b111
+    /// must be resolved by the caller.
+    pub fn from_bits(rm: u32) -> Option<Self> {
+        match rm {
+            0b000 => Some(RoundingMode::NearestEven),
+            0b001 => Some(RoundingMode::TowardZero),
+            0b010 => Some(RoundingMode::Down),
+            0b011 => Some(RoundingMode::Up),
+            0b100 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+pub trait Float: Copy + PartialEq + PartialOrd {
+    /// Number of explicitly stored significand bits
+    const SIG_BITS: u32;
+    const EXP_BITS: u32;
+    const CANONICAL_NAN: Self;
+
+    fn to_bits64(self) -> u64;
+    fn from_bits64(bits: u64) -> Self;
+    /// Exact widening used by the integer conversions
+    fn to_f64(self) -> f64;
+
+    fn bias() -> i32 {
+        (1 << (Self::EXP_BITS - 1)) - 1
+    }
+    fn is_sign_negative(self) -> bool {
+        self.to_bits64() >> (Self::SIG_BITS + Self::EXP_BITS) != 0
+    }
+    fn biased_exponent(self) -> u64 {
+        (self.to_bits64() >> Self::SIG_BITS) & ((1 << Self::EXP_BITS) - 1)
+    }
+    fn fraction(self) -> u64 {
+        self.to_bits64() & ((1 << Self::SIG_BITS) - 1)
+    }
+    fn is_nan(self) -> bool {
+        self.biased_exponent() == (1 << Self::EXP_BITS) - 1 && self.fraction() != 0
+    }
+    fn is_signaling(self) -> bool {
+        self.is_nan() && self.fraction() >> (Self::SIG_BITS - 1) == 0
+    }
+    fn is_infinite(self) -> bool {
+        self.biased_exponent() == (1 << Self::EXP_BITS) - 1 && self.fraction() == 0
+    }
+    fn is_zero(self) -> bool {
+        self.biased_exponent() == 0 && self.fraction() == 0
+    }
+    fn is_subnormal(self) -> bool {
+        self.biased_exponent() == 0 && self.fraction() != 0
+    }
+    fn infinity(negative: bool) -> Self {
+        Self::pack(negative, (1 << Self::EXP_BITS) - 1, 0)
+    }
+    fn zero(negative: bool) -> Self {
+        Self::pack(negative, 0, 0)
+    }
+    fn max_finite(negative: bool) -> Self {
+        Self::pack(
+            negative,
+            (1 << Self::EXP_BITS) - 2,
+            (1 << Self::SIG_BITS) - 1,
+        )
+    }
+    fn pack(negative: bool, biased_exponent: u64, fraction: u64) -> Self {
+        let sign = (negative as u64) << (Self::SIG_BITS + Self::EXP_BITS);
+        Self::from_bits64(sign | biased_exponent << Self::SIG_BITS | fraction)
+    }
+    fn negate(self) -> Self {
+        Self::from_bits64(self.to_bits64() ^ 1 << (Self::SIG_BITS + Self::EXP_BITS))
+    }
+}
+
+impl Float for f32 {
+    const SIG_BITS: u32 = 23;
+    const EXP_BITS: u32 = 8;
+    const CANONICAL_NAN: Self = f32::from_bits(0x7fc0_0000);
+
+    fn to_bits64(self) -> u64 {
+        self.to_bits() as u64
+    }
+    fn from_bits64(bits: u64) -> Self {
+        f32::from_bits(bits as u32)
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Float for f64 {
+    const SIG_BITS: u32 = 52;
+    const EXP_BITS: u32 = 11;
+    const CANONICAL_NAN: Self = f64::from_bits(0x7ff8_0000_0000_0000);
+
+    fn to_bits64(self) -> u64 {
+        self.to_bits()
+    }
+    fn from_bits64(bits: u64) -> Self {
+        f64::from_bits(bits)
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+/// A finite value `(-1)^negative * significand * 2^exponent`
+#[derive(Clone, Copy, Debug)]
+struct Unpacked {
+    negative: bool,
+    significand: u128,
+    exponent: i32,
+}
+
+impl Unpacked {
+    fn new<F: Float>(x: F) -> Self {
+        let biased = x.biased_exponent() as i32;
+        let (significand, biased) = if biased == 0 {
+            (x.fraction(), 1)
+        } else {
+            (x.fraction() | 1 << F::SIG_BITS, biased)
+        };
+        Self {
+            negative: x.is_sign_negative(),
+            significand: significand as u128,
+            exponent: biased - F::bias() - F::SIG_BITS as i32,
+        }
+    }
+
+    /// Shift the significand so that its leading one is at bit `position`
+    fn normalize(self, position: u32) -> Self {
+        let shift = position as i32 - (127 - self.significand.leading_zeros() as i32);
+        Self {
+            significand: self.significand << shift,
+            exponent: self.exponent - shift,
+            ..self
+        }
+    }
+
+    /// Shift right by `shift`, OR-ing any lost bits into the lowest bit
+    fn jam_right(self, shift: i32) -> Self {
+        let significand = if shift >= 128 {
+            (self.significand != 0) as u128
+        } else {
+            let lost = self.significand & ((1 << shift) - 1);
+            self.significand >> shift | (lost != 0) as u128
+        };
+        Self {
+            significand,
+            exponent: self.exponent + shift,
+            ..self
+        }
+    }
+}
+
+/// Round `significand >> shift` to an integer, returning it and whether any
+/// nonzero bits were discarded.
+fn round_shift(significand: u128, shift: i32, negative: bool, rm: RoundingMode) -> (u128, bool) {
+    if shift <= 0 {
+        return (significand << -shift, false);
+    }
+    let (kept, rest, half) = if shift > 128 {
+        (0, significand, None)
+    } else if shift == 128 {
+        (0, significand, Some(1 << 127))
+    } else {
+        (
+            significand >> shift,
+            significand & ((1 << shift) - 1),
+            Some(1 << (shift - 1)),
+        )
+    };
+    let inexact = rest != 0;
+    let round_up = match rm {
+        RoundingMode::NearestEven => match half {
+            Some(half) => rest > half || (rest == half && kept & 1 == 1),
+            None => false,
+        },
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => inexact && negative,
+        RoundingMode::Up => inexact && !negative,
+        RoundingMode::NearestMaxMagnitude => match half {
+            Some(half) => rest >= half,
+            None => false,
+        },
+    };
+    (kept + round_up as u128, inexact)
+}
+
+/// Round an exact (or sticky-jammed) value to the format, accruing
+/// NX/UF/OF. Tininess is detected after rounding, as RISC-V requires.
+fn round<F: Float>(value: Unpacked, rm: RoundingMode, flags: &mut u32) -> F {
+    let negative = value.negative;
+    if value.significand == 0 {
+        return F::zero(negative);
+    }
+    let precision = F::SIG_BITS as i32 + 1;
+    let min_exponent = 1 - F::bias();
+    let max_exponent = F::bias();
+    let msb = 127 - value.significand.leading_zeros() as i32;
+    let top = msb + value.exponent;
+
+    // Rounded as if the exponent range were unbounded
+    let shift = msb - (precision - 1);
+    let (unbounded, _) = round_shift(value.significand, shift, negative, rm);
+    let unbounded_top = top + (unbounded >> precision) as i32;
+
+    // Rounded to the representable precision at this exponent
+    let quantum = (value.exponent + shift).max(min_exponent - (precision - 1));
+    let (mut kept, inexact) =
+        round_shift(value.significand, quantum - value.exponent, negative, rm);
+    let mut quantum = quantum;
+    if kept >> precision != 0 {
+        kept >>= 1;
+        quantum += 1;
+    }
+
+    if inexact {
+        *flags |= FLAG_NX;
+        if unbounded_top < min_exponent {
+            *flags |= FLAG_UF;
+        }
+    }
+    if unbounded_top > max_exponent {
+        *flags |= FLAG_OF | FLAG_NX;
+        let to_infinity = match rm {
+            RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+            RoundingMode::TowardZero => false,
+            RoundingMode::Down => negative,
+            RoundingMode::Up => !negative,
+        };
+        return if to_infinity {
+            F::infinity(negative)
+        } else {
+            F::max_finite(negative)
+        };
+    }
+    if kept >> (precision - 1) == 0 {
+        // Subnormal (or rounded to zero)
+        F::pack(negative, 0, kept as u64)
+    } else {
+        let biased = (quantum + precision - 1 + F::bias()) as u64;
+        F::pack(negative, biased, kept as u64 & ((1 << F::SIG_BITS) - 1))
+    }
+}
+
+/// Result of an operation with at least one NaN operand
+fn propagate_nan<F: Float, T: Float>(operands: &[F], flags: &mut u32) -> T {
+    if operands.iter().any(|x| x.is_signaling()) {
+        *flags |= FLAG_NV;
+    }
+    T::CANONICAL_NAN
+}
+
+/// Canonicalize an invalid result such as `inf - inf` or `0 * inf`
+fn invalid<F: Float>(flags: &mut u32) -> F {
+    *flags |= FLAG_NV;
+    F::CANONICAL_NAN
+}
+
+/// Exact sum of two finite values, rounded once
+fn add_unpacked<F: Float>(a: Unpacked, b: Unpacked, rm: RoundingMode, flags: &mut u32) -> F {
+    if a.significand == 0 || b.significand == 0 {
+        if a.significand == 0 && b.significand == 0 {
+            // Zeros of the same sign keep it; otherwise +0, or -0 rounding down
+            let negative = if a.negative == b.negative {
+                a.negative
+            } else {
+                rm == RoundingMode::Down
+            };
+            return F::zero(negative);
+        }
+        let value = if a.significand == 0 { b } else { a };
+        return round(value, rm, flags);
+    }
+    // Leave headroom for the carry and plenty of guard bits below the
+    // widest operand (a 106-bit product), so jamming is exact enough.
+    let (a, b) = (a.normalize(125), b.normalize(125));
+    let (large, small) = if (a.exponent, a.significand) >= (b.exponent, b.significand) {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let small = small.jam_right(large.exponent - small.exponent);
+    let significand = if large.negative == small.negative {
+        large.significand + small.significand
+    } else {
+        large.significand - small.significand
+    };
+    if significand == 0 {
+        return F::zero(rm == RoundingMode::Down);
+    }
+    round(
+        Unpacked {
+            negative: large.negative,
+            significand,
+            exponent: large.exponent,
+        },
+        rm,
+        flags,
+    )
+}
+
+pub fn add<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b], flags);
+    }
+    if a.is_infinite() || b.is_infinite() {
+        if a.is_infinite() && b.is_infinite() && a.is_sign_negative() != b.is_sign_negative() {
+            return invalid(flags);
+        }
+        return if a.is_infinite() { a } else { b };
+    }
+    add_unpacked(Unpacked::new(a), Unpacked::new(b), rm, flags)
+}
+
+pub fn sub<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b], flags);
+    }
+    add(a, b.negate(), rm, flags)
+}
+
+pub fn mul<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b], flags);
+    }
+    let negative = a.is_sign_negative() != b.is_sign_negative();
+    if a.is_infinite() || b.is_infinite() {
+        if a.is_zero() || b.is_zero() {
+            return invalid(flags);
+        }
+        return F::infinity(negative);
+    }
+    let (a, b) = (Unpacked::new(a), Unpacked::new(b));
+    let product = Unpacked {
+        negative,
+        significand: a.significand * b.significand,
+        exponent: a.exponent + b.exponent,
+    };
+    round(product, rm, flags)
+}
+
+pub fn div<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b], flags);
+    }
+    let negative = a.is_sign_negative() != b.is_sign_negative();
+    if a.is_infinite() {
+        if b.is_infinite() {
+            return invalid(flags);
+        }
+        return F::infinity(negative);
+    }
+    if b.is_infinite() {
+        return F::zero(negative);
+    }
+    if b.is_zero() {
+        if a.is_zero() {
+            return invalid(flags);
+        }
+        *flags |= FLAG_DZ;
+        return F::infinity(negative);
+    }
+    if a.is_zero() {
+        return F::zero(negative);
+    }
+    let a = Unpacked::new(a).normalize(F::SIG_BITS);
+    let b = Unpacked::new(b).normalize(F::SIG_BITS);
+    // At least 73 quotient bits, plus a sticky bit for the remainder
+    let dividend = a.significand << 74;
+    let quotient = Unpacked {
+        negative,
+        significand: (dividend / b.significand) | !dividend.is_multiple_of(b.significand) as u128,
+        exponent: a.exponent - b.exponent - 74,
+    };
+    round(quotient, rm, flags)
+}
+
+pub fn sqrt<F: Float>(a: F, rm: RoundingMode, flags: &mut u32) -> F {
+    if a.is_nan() {
+        return propagate_nan(&[a], flags);
+    }
+    if a.is_zero() {
+        return a;
+    }
+    if a.is_sign_negative() {
+        return invalid(flags);
+    }
+    if a.is_infinite() {
+        return a;
+    }
+    let mut a = Unpacked::new(a).normalize(F::SIG_BITS);
+    if a.exponent & 1 != 0 {
+        a.significand <<= 1;
+        a.exponent -= 1;
+    }
+    let radicand = a.significand << 72;
+    let root = radicand.isqrt();
+    let root = Unpacked {
+        negative: false,
+        significand: root | (root * root != radicand) as u128,
+        exponent: (a.exponent - 72) / 2,
+    };
+    round(root, rm, flags)
+}
+
+/// Fused `a * b + c` with a single rounding
+pub fn mul_add<F: Float>(a: F, b: F, c: F, rm: RoundingMode, flags: &mut u32) -> F {
+    if (a.is_zero() && b.is_infinite()) || (a.is_infinite() && b.is_zero()) {
+        // Invalid even when the addend is a quiet NaN
+        return invalid(flags);
+    }
+    if a.is_nan() || b.is_nan() || c.is_nan() {
+        return propagate_nan(&[a, b, c], flags);
+    }
+    let negative = a.is_sign_negative() != b.is_sign_negative();
+    if a.is_infinite() || b.is_infinite() {
+        if c.is_infinite() && c.is_sign_negative() != negative {
+            return invalid(flags);
+        }
+        return F::infinity(negative);
+    }
+    if c.is_infinite() {
+        return c;
+    }
+    let (a, b) = (Unpacked::new(a), Unpacked::new(b));
+    let product = Unpacked {
+        negative,
+        significand: a.significand * b.significand,
+        exponent: a.exponent + b.exponent,
+    };
+    add_unpacked(product, Unpacked::new(c), rm, flags)
+}
+
+/// IEEE 754-2019 minimumNumber, with -0 ordered below +0
+pub fn min<F: Float>(a: F, b: F, flags: &mut u32) -> F {
+    min_max(a, b, flags, true)
+}
+
+/// IEEE 754-2019 maximumNumber, with -0 ordered below +0
+pub fn max<F: Float>(a: F, b: F, flags: &mut u32) -> F {
+    min_max(a, b, flags, false)
+}
+
+fn min_max<F: Float>(a: F, b: F, flags: &mut u32, minimum: bool) -> F {
+    if a.is_signaling() || b.is_signaling() {
+        *flags |= FLAG_NV;
+    }
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        (false, false) => {
+            let a_first = if a == b {
+                a.is_sign_negative() == minimum
+            } else {
+                (a < b) == minimum
+            };
+            if a_first {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+/// Quiet equality: only signaling NaNs raise NV
+pub fn eq<F: Float>(a: F, b: F, flags: &mut u32) -> bool {
+    if a.is_signaling() || b.is_signaling() {
+        *flags |= FLAG_NV;
+    }
+    a == b
+}
+
+/// Signaling less-than: any NaN raises NV
+pub fn lt<F: Float>(a: F, b: F, flags: &mut u32) -> bool {
+    if a.is_nan() || b.is_nan() {
+        *flags |= FLAG_NV;
+    }
+    a < b
+}
+
+/// Signaling less-than-or-equal: any NaN raises NV
+pub fn le<F: Float>(a: F, b: F, flags: &mut u32) -> bool {
+    if a.is_nan() || b.is_nan() {
+        *flags |= FLAG_NV;
+    }
+    a <= b
+}
+
+/// The one-hot mask written by FCLASS
+pub fn classify<F: Float>(a: F) -> u64 {
+    let negative = a.is_sign_negative();
+    let bit = if a.is_nan() {
+        if a.is_signaling() {
+            8
+        } else {
+            9
+        }
+    } else if a.is_infinite() {
+        if negative {
+            0
+        } else {
+            7
+        }
+    } else if a.is_zero() {
+        if negative {
+            3
+        } else {
+            4
+        }
+    } else if a.is_subnormal() {
+        if negative {
+            2
+        } else {
+            5
+        }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+/// Convert between formats (FCVT.S.D / FCVT.D.S)
+pub fn convert<F: Float, T: Float>(a: F, rm: RoundingMode, flags: &mut u32) -> T {
+    if a.is_nan() {
+        return propagate_nan(&[a], flags);
+    }
+    if a.is_infinite() {
+        return T::infinity(a.is_sign_negative());
+    }
+    round(Unpacked::new(a), rm, flags)
+}
+
+/// Convert an integer to floating point, rounding as needed
+pub fn from_int<F: Float>(value: i128, rm: RoundingMode, flags: &mut u32) -> F {
+    let value = Unpacked {
+        negative: value < 0,
+        significand: value.unsigned_abs(),
+        exponent: 0,
+    };
+    round(value, rm, flags)
+}
+
+/// Convert to an integer in `[min, max]`, saturating out-of-range values
+/// and NaNs as required by the spec.
+pub fn to_int<F: Float>(a: F, min: i128, max: i128, rm: RoundingMode, flags: &mut u32) -> i128 {
+    if a.is_nan() {
+        *flags |= FLAG_NV;
+        return max;
+    }
+    let x = a.to_f64();
+    let rounded = match rm {
+        RoundingMode::NearestEven => x.round_ties_even(),
+        RoundingMode::TowardZero => x.trunc(),
+        RoundingMode::Down => x.floor(),
+        RoundingMode::Up => x.ceil(),
+        RoundingMode::NearestMaxMagnitude => x.round(),
+    };
+    // Saturating cast; infinities map outside every target range
+    let value = rounded as i128;
+    if value < min {
+        *flags |= FLAG_NV;
+        return min;
+    }
+    if value > max {
+        *flags |= FLAG_NV;
+        return max;
+    }
+    if rounded != x {
+        *flags |= FLAG_NX;
+    }
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RNE: RoundingMode = RoundingMode::NearestEven;
+    const RTZ: RoundingMode = RoundingMode::TowardZero;
+    const RDN: RoundingMode = RoundingMode::Down;
+    const RUP: RoundingMode = RoundingMode::Up;
+    const RMM: RoundingMode = RoundingMode::NearestMaxMagnitude;
+
+    #[test]
+    fn test_add_rounding_modes() {
+        // 1 + 2^-60 is not representable in either format
+        let tiny = 2f64.powi(-60);
+        for (rm, expected) in &[
+            (RNE, 1.0),
+            (RTZ, 1.0),
+            (RDN, 1.0),
+            (RUP, 1.0f64.next_up()),
+            (RMM, 1.0),
+        ] {
+            let mut flags = 0;
+            assert_eq!(add(1.0, tiny, *rm, &mut flags), *expected);
+            assert_eq!(flags, FLAG_NX);
+        }
+
+        let mut flags = 0;
+        assert_eq!(add(-1.0, -tiny, RTZ, &mut flags), -1.0);
+        assert_eq!(add(-1.0, -tiny, RDN, &mut flags), (-1.0f64).next_down());
+
+        // 1 + 2^-24 is exactly halfway between two binary32 values
+        let half = 2f32.powi(-24);
+        assert_eq!(add(1.0f32, half, RNE, &mut flags), 1.0);
+        assert_eq!(add(1.0f32, half, RMM, &mut flags), 1.0f32.next_up());
+        assert_eq!(add(1.0f32, half, RUP, &mut flags), 1.0f32.next_up());
+    }
+
+    #[test]
+    fn test_exact_results() {
+        let mut flags = 0;
+        assert_eq!(add(1.5f32, 2.25, RUP, &mut flags), 3.75);
+        assert_eq!(mul(3.0f64, 0.5, RDN, &mut flags), 1.5);
+        assert_eq!(sqrt(16.0f32, RTZ, &mut flags), 4.0);
+        assert_eq!(flags, 0);
+
+        // x - x is +0, except when rounding down
+        assert!(!sub(1.0f64, 1.0, RNE, &mut flags).is_sign_negative());
+        assert!(sub(1.0f64, 1.0, RDN, &mut flags).is_sign_negative());
+        assert!(add(-0.0f32, -0.0, RNE, &mut flags).is_sign_negative());
+        assert!(!add(0.0f32, 0.0, RDN, &mut flags).is_sign_negative());
+    }
+
+    #[test]
+    fn test_division() {
+        let mut flags = 0;
+        let third = div(1.0f64, 3.0, RNE, &mut flags);
+        assert_eq!(third, 1.0 / 3.0);
+        assert_eq!(flags, FLAG_NX);
+        // 1/3 rounds down to nearest, so RUP must step up
+        assert_eq!(div(1.0f64, 3.0, RUP, &mut flags), third.next_up());
+        // ...whereas in binary32 it rounds up
+        assert_eq!(div(1.0f32, 3.0, RUP, &mut flags), 1.0f32 / 3.0);
+        assert_eq!(
+            div(1.0f32, 3.0, RTZ, &mut flags),
+            (1.0f32 / 3.0).next_down()
+        );
+
+        let mut flags = 0;
+        assert_eq!(div(1.0f32, 0.0, RNE, &mut flags), f32::INFINITY);
+        assert_eq!(flags, FLAG_DZ);
+
+        let mut flags = 0;
+        assert!(div(0.0f64, 0.0, RNE, &mut flags).is_nan());
+        assert_eq!(flags, FLAG_NV);
+    }
+
+    #[test]
+    fn test_overflow_underflow() {
+        let mut flags = 0;
+        assert_eq!(mul(f64::MAX, 2.0, RNE, &mut flags), f64::INFINITY);
+        assert_eq!(flags, FLAG_OF | FLAG_NX);
+        assert_eq!(mul(f64::MAX, 2.0, RTZ, &mut flags), f64::MAX);
+        assert_eq!(mul(f32::MAX, -2.0, RUP, &mut flags), -f32::MAX);
+        assert_eq!(mul(f32::MAX, -2.0, RDN, &mut flags), f32::NEG_INFINITY);
+
+        let mut flags = 0;
+        let min = f64::from_bits(1);
+        assert_eq!(mul(min, 0.5, RNE, &mut flags), 0.0);
+        assert_eq!(flags, FLAG_UF | FLAG_NX);
+        assert_eq!(mul(min, 0.5, RUP, &mut flags), min);
+        assert_eq!(mul(min, 0.75, RNE, &mut flags), min);
+        assert_eq!(mul(min, 0.75, RTZ, &mut flags), 0.0);
+        assert_eq!(div(min, 4.0, RUP, &mut flags), min);
+
+        let mut flags = 0;
+        assert_eq!(
+            mul(f32::MIN_POSITIVE, 0.5, RNE, &mut flags),
+            f32::MIN_POSITIVE / 2.0
+        );
+        assert_eq!(flags, 0); // exact subnormal results do not underflow
+    }
+
+    #[test]
+    fn test_nan_handling() {
+        let snan = f32::from_bits(0x7f800001);
+        let qnan = f32::from_bits(0x7fc00001);
+
+        let mut flags = 0;
+        assert_eq!(add(qnan, 1.0, RNE, &mut flags).to_bits(), 0x7fc00000);
+        assert_eq!(flags, 0);
+        assert_eq!(add(snan, 1.0, RNE, &mut flags).to_bits(), 0x7fc00000);
+        assert_eq!(flags, FLAG_NV);
+
+        let mut flags = 0;
+        assert!(sub(f64::INFINITY, f64::INFINITY, RNE, &mut flags).is_nan());
+        assert_eq!(flags, FLAG_NV);
+
+        let mut flags = 0;
+        assert!(mul_add(0.0, f32::INFINITY, qnan, RNE, &mut flags).is_nan());
+        assert_eq!(flags, FLAG_NV);
+
+        let mut flags = 0;
+        assert_eq!(min(qnan, 2.0, &mut flags), 2.0);
+        assert_eq!(flags, 0);
+        assert_eq!(max(snan, 2.0, &mut flags), 2.0);
+        assert_eq!(flags, FLAG_NV);
+        assert!(min(-0.0f64, 0.0, &mut flags).is_sign_negative());
+        assert!(!max(-0.0f64, 0.0, &mut flags).is_sign_negative());
+    }
+
+    #[test]
+    fn test_fused_multiply_add() {
+        let mut flags = 0;
+        // (1 + 2^-30)^2 - 1 needs the unrounded product
+        let x = 1.0 + 2f64.powi(-30);
+        assert_eq!(
+            mul_add(x, x, -1.0, RNE, &mut flags),
+            2f64.powi(-29) + 2f64.powi(-60)
+        );
+        assert_eq!(flags, 0);
+
+        let y = 1.0f32 + 2f32.powi(-12);
+        assert_eq!(
+            mul_add(y, y, -1.0, RNE, &mut flags),
+            2f32.powi(-11) + 2f32.powi(-24)
+        );
+        assert_eq!(mul_add(y, y, 1.0, RDN, &mut flags), 2.0 + 2f32.powi(-11));
+        assert_eq!(flags, FLAG_NX);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let qnan = f64::NAN;
+        let mut flags = 0;
+        assert!(!eq(qnan, qnan, &mut flags));
+        assert_eq!(flags, 0);
+        assert!(!lt(qnan, 1.0, &mut flags));
+        assert_eq!(flags, FLAG_NV);
+        assert!(le(-0.0f32, 0.0, &mut flags));
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(f32::NEG_INFINITY), 1 << 0);
+        assert_eq!(classify(-1.0f64), 1 << 1);
+        assert_eq!(classify(-f64::from_bits(1)), 1 << 2);
+        assert_eq!(classify(-0.0f32), 1 << 3);
+        assert_eq!(classify(0.0f32), 1 << 4);
+        assert_eq!(classify(f32::from_bits(1)), 1 << 5);
+        assert_eq!(classify(1.0f64), 1 << 6);
+        assert_eq!(classify(f64::INFINITY), 1 << 7);
+        assert_eq!(classify(f32::from_bits(0x7f800001)), 1 << 8);
+        assert_eq!(classify(f32::NAN), 1 << 9);
+    }
+
+    #[test]
+    fn test_int_conversions() {
+        let (min, max) = (i32::MIN as i128, i32::MAX as i128);
+        let mut flags = 0;
+        assert_eq!(to_int(-1.5f32, min, max, RNE, &mut flags), -2);
+        assert_eq!(to_int(-1.5f32, min, max, RTZ, &mut flags), -1);
+        assert_eq!(to_int(-1.5f32, min, max, RMM, &mut flags), -2);
+        assert_eq!(to_int(2.5f64, min, max, RNE, &mut flags), 2);
+        assert_eq!(to_int(2.5f64, min, max, RMM, &mut flags), 3);
+        assert_eq!(flags, FLAG_NX);
+
+        let mut flags = 0;
+        assert_eq!(to_int(3e9f64, min, max, RNE, &mut flags), max);
+        assert_eq!(to_int(f32::NAN, min, max, RNE, &mut flags), max);
+        assert_eq!(to_int(f32::NEG_INFINITY, min, max, RNE, &mut flags), min);
+        assert_eq!(flags, FLAG_NV);
+
+        // Rounds into range of an unsigned conversion: inexact, not invalid
+        let mut flags = 0;
+        assert_eq!(to_int(-0.25f64, 0, u64::MAX as i128, RTZ, &mut flags), 0);
+        assert_eq!(flags, FLAG_NX);
+
+        let mut flags = 0;
+        let big = (1i128 << 53) + 1;
+        assert_eq!(from_int::<f64>(big, RNE, &mut flags), 2f64.powi(53));
+        assert_eq!(from_int::<f64>(big, RUP, &mut flags), 2f64.powi(53) + 2.0);
+        assert_eq!(
+            from_int::<f32>(u64::MAX as i128, RTZ, &mut flags),
+            2f32.powi(64).next_down()
+        );
+        assert_eq!(flags, FLAG_NX);
+    }
+
+    #[test]
+    fn test_format_conversions() {
+        let mut flags = 0;
+        let x: f32 = convert(1.0f64 / 3.0, RDN, &mut flags);
+        assert_eq!(x, (1.0f32 / 3.0).next_down());
+        assert_eq!(flags, FLAG_NX);
+
+        let mut flags = 0;
+        let x: f32 = convert(1e300f64, RTZ, &mut flags);
+        assert_eq!(x, f32::MAX);
+        assert_eq!(flags, FLAG_OF | FLAG_NX);
+
+        let mut flags = 0;
+        let x: f64 = convert(f32::from_bits(0xff800001), RNE, &mut flags);
+        assert_eq!(x.to_bits(), 0x7ff8000000000000);
+        assert_eq!(flags, FLAG_NV);
+    }
+}
@@ -0,0 +1,214 @@
+use std::io::{self, Write};
+
+use crate::emulator::Emulator;
+use crate::symbols::SymbolTable;
+use crate::syscall::{Abi, Syscalls};
+
+/// Device 0: the frontend syscall proxy, also used to report exit codes
+const DEVICE_SYSCALL: u64 = 0;
+/// Device 1: the block character device, i.e. the console
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+const PAYLOAD_MASK: u64 = 0xffff_ffff_ffff;
+
+/// Host-target interface as implemented by Spike's frontend: the program
+/// writes commands to the 64-bit `tohost` word and reads responses from
+/// `fromhost`. A command holds a device in bits 63:56, a command in bits
+/// 55:48 and a payload in bits 47:0.
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    /// Syscalls proxied through device 0, with no heap or mappings to
+    /// manage since the program runs on bare metal
+    syscalls: Syscalls,
+    exit_code: Option<u64>,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            syscalls: Syscalls::new(Abi::Linux, 0, 0, 0),
+            exit_code: None,
+        }
+    }
+
+    /// The interface of a program defining a `tohost` symbol
+    pub fn from_symbols(symbols: &SymbolTable) -> Option<Self> {
+        let tohost = symbols.get("tohost")?.address;
+        let fromhost = symbols.get("fromhost").map(|symbol| symbol.address);
+        Some(Self::new(tohost, fromhost))
+    }
+
+    /// Exit code reported by the program, 0 meaning the test passed. The
+    /// riscv-tests report a failure by the number of the failing test.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.exit_code.or_else(|| {
+            self.syscalls
+                .exit_status()
+                .map(|status| status as u32 as u64)
+        })
+    }
+}
+
+impl Emulator {
+    /// Service a command the program has written to `tohost`, if any
+    pub(crate) fn poll_htif(&mut self) {
+        let mut htif = match self.htif.take() {
+            Some(htif) => htif,
+            None => return,
+        };
+        if let Some(command) = self.read_host_word(htif.tohost).filter(|&word| word != 0) {
+            self.write_host_word(htif.tohost, 0);
+            self.htif_command(&mut htif, command);
+        }
+        self.htif = Some(htif);
+    }
+
+    fn htif_command(&mut self, htif: &mut Htif, command: u64) {
+        let device = command >> 56;
+        let cmd = (command >> 48) & 0xff;
+        let payload = command & PAYLOAD_MASK;
+        let response = match (device, cmd) {
+            (DEVICE_SYSCALL, _) if payload & 1 == 1 => {
+                htif.exit_code = Some(payload >> 1);
+                return;
+            }
+            // The payload points at the syscall number followed by its
+            // arguments; the result replaces the number
+            (DEVICE_SYSCALL, _) => {
+                let mut words = [0; 8];
+                for (i, word) in words.iter_mut().enumerate() {
+                    *word = self.read_host_word(payload + 8 * i as u64).unwrap_or(0);
+                }
+                let mut args = [0; 6];
+                args.copy_from_slice(&words[1..7]);
+                let result = self.dispatch_syscall(&mut htif.syscalls, words[0], args);
+                self.write_host_word(payload, result.unwrap_or_else(|errno| (-errno) as u64));
+                1
+            }
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&[payload as u8]);
+                let _ = stdout.flush();
+                0x100 | (payload & 0xff)
+            }
+            // Other devices and commands are not implemented
+            _ => return,
+        };
+        if let Some(fromhost) = htif.fromhost {
+            self.write_host_word(fromhost, (command & !PAYLOAD_MASK) | response);
+        }
+    }
+
+    fn read_host_word(&self, address: u64) -> Option<u64> {
+        let range = self.bus.ram_range(address, 8)?;
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.bus.ram[range]);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    fn write_host_word(&mut self, address: u64, value: u64) {
+        let _ = self.write_guest_u64(address, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::{Elf, Segment};
+    use crate::symbols::Symbol;
+
+    const TOHOST: u64 = 0x1000;
+    const FROMHOST: u64 = 0x1040;
+
+    fn emulator(program: &[u8]) -> Emulator {
+        let mut memory = vec![0; 0x2000];
+        memory[..program.len()].copy_from_slice(program);
+        let mut emu = Emulator::new(memory);
+        emu.htif = Some(Htif::new(TOHOST, Some(FROMHOST)));
+        emu
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut emu = emulator(&[
+            0x37, 0x15, 0x00, 0x00, // lui a0, 1
+            0x93, 0x05, 0x70, 0x00, // li a1, 7
+            0x23, 0x20, 0xb5, 0x00, // sw a1, 0(a0)
+            0x6f, 0x00, 0x00, 0x00, // j .
+        ]);
+        assert!(emu.run().is_ok());
+        assert_eq!(emu.exit_status(), Some(3));
+        assert_eq!(emu.htif.as_ref().unwrap().exit_code(), Some(3));
+        assert_eq!(emu.pc, 12);
+        assert_eq!(emu.read_host_word(TOHOST), Some(0));
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut emu = emulator(&[]);
+        // Console output is acknowledged with the character
+        emu.write_host_word(TOHOST, (1 << 56) | (1 << 48) | u64::from(b'x'));
+        emu.poll_htif();
+        assert_eq!(emu.read_host_word(TOHOST), Some(0));
+        assert_eq!(
+            emu.read_host_word(FROMHOST),
+            Some((1 << 56) | (1 << 48) | 0x178)
+        );
+
+        // uname through the syscall proxy, with its result written back
+        // over the syscall number
+        emu.write_host_word(0x1800, 160);
+        emu.write_host_word(0x1808, 0x1900);
+        emu.write_host_word(TOHOST, 0x1800);
+        emu.poll_htif();
+        assert_eq!(emu.read_host_word(0x1800), Some(0));
+        assert_eq!(&emu.bus.ram[0x1900..0x1906], b"Linux\0");
+        assert_eq!(emu.read_host_word(FROMHOST), Some(1));
+        assert_eq!(emu.exit_status(), None);
+
+        // Unknown syscalls fail with ENOSYS
+        emu.write_host_word(0x1800, 9999);
+        emu.write_host_word(TOHOST, 0x1800);
+        emu.poll_htif();
+        assert_eq!(emu.read_host_word(0x1800), Some(-38i64 as u64));
+
+        // An exit through the proxy ends the program
+        emu.write_host_word(0x1800, 93);
+        emu.write_host_word(0x1808, 5);
+        emu.write_host_word(TOHOST, 0x1800);
+        emu.poll_htif();
+        assert_eq!(emu.exit_status(), Some(5));
+    }
+
+    #[test]
+    fn test_from_elf() {
+        let mut elf = Elf {
+            entry: 0x10000,
+            segments: vec![Segment {
+                address: 0x10000,
+                data: vec![0x13, 0x00, 0x00, 0x00],
+                size: 0x2000,
+            }],
+            program_headers: None,
+            program_header_size: 0,
+            program_header_count: 0,
+            linux: false,
+            symbols: SymbolTable::default(),
+        };
+        assert!(Emulator::from_elf(&elf).htif.is_none());
+
+        elf.symbols = SymbolTable::new(vec![Symbol {
+            name: "tohost".to_string(),
+            address: 0x11000,
+            size: 8,
+        }]);
+        let emu = Emulator::from_elf(&elf);
+        let htif = emu.htif.as_ref().unwrap();
+        assert_eq!(htif.tohost, 0x11000);
+        assert_eq!(htif.fromhost, None);
+    }
+}
@@ -1,11 +1,31 @@
-use crate::types::{Btype, Itype, Jtype, Rtype, Stype, Utype};
+use crate::types::{Atype, Btype, Itype, Jtype, R4type, Rtype, Stype, Utype};
 
 #[derive(Debug, PartialEq)]
 pub enum Instruction {
     Add(Rtype),
     Addi(Itype),
+    Addiw(Itype),
+    Addw(Rtype),
     And(Rtype),
     Andi(Itype),
+    AmoaddD(Atype),
+    AmoaddW(Atype),
+    AmoandD(Atype),
+    AmoandW(Atype),
+    AmomaxD(Atype),
+    AmomaxW(Atype),
+    AmomaxuD(Atype),
+    AmomaxuW(Atype),
+    AmominD(Atype),
+    AmominW(Atype),
+    AmominuD(Atype),
+    AmominuW(Atype),
+    AmoorD(Atype),
+    AmoorW(Atype),
+    AmoswapD(Atype),
+    AmoswapW(Atype),
+    AmoxorD(Atype),
+    AmoxorW(Atype),
     Auipc(Utype),
     Beq(Btype),
     Bne(Btype),
@@ -13,30 +33,205 @@ pub enum Instruction {
     Bge(Btype),
     Bltu(Btype),
     Bgeu(Btype),
+    Csrrc(Itype),
+    Csrrci(Itype),
+    Csrrs(Itype),
+    Csrrsi(Itype),
+    Csrrw(Itype),
+    Csrrwi(Itype),
+    Div(Rtype),
+    Divu(Rtype),
+    Divuw(Rtype),
+    Divw(Rtype),
+    Ebreak,
+    Ecall,
+    FaddD(Rtype),
+    FaddS(Rtype),
+    FclassD(Rtype),
+    FclassS(Rtype),
+    FcvtDL(Rtype),
+    FcvtDLu(Rtype),
+    FcvtDS(Rtype),
+    FcvtDW(Rtype),
+    FcvtDWu(Rtype),
+    FcvtLD(Rtype),
+    FcvtLS(Rtype),
+    FcvtLuD(Rtype),
+    FcvtLuS(Rtype),
+    FcvtSD(Rtype),
+    FcvtSL(Rtype),
+    FcvtSLu(Rtype),
+    FcvtSW(Rtype),
+    FcvtSWu(Rtype),
+    FcvtWD(Rtype),
+    FcvtWS(Rtype),
+    FcvtWuD(Rtype),
+    FcvtWuS(Rtype),
+    FdivD(Rtype),
+    FdivS(Rtype),
+    FeqD(Rtype),
+    FeqS(Rtype),
+    Fence,
+    FenceI,
+    Fld(Itype),
+    FleD(Rtype),
+    FleS(Rtype),
+    FltD(Rtype),
+    FltS(Rtype),
+    Flw(Itype),
+    FmaddD(R4type),
+    FmaddS(R4type),
+    FmaxD(Rtype),
+    FmaxS(Rtype),
+    FminD(Rtype),
+    FminS(Rtype),
+    FmsubD(R4type),
+    FmsubS(R4type),
+    FmulD(Rtype),
+    FmulS(Rtype),
+    FmvDX(Rtype),
+    FmvWX(Rtype),
+    FmvXD(Rtype),
+    FmvXW(Rtype),
+    FnmaddD(R4type),
+    FnmaddS(R4type),
+    FnmsubD(R4type),
+    FnmsubS(R4type),
+    Fsd(Stype),
+    FsgnjD(Rtype),
+    FsgnjS(Rtype),
+    FsgnjnD(Rtype),
+    FsgnjnS(Rtype),
+    FsgnjxD(Rtype),
+    FsgnjxS(Rtype),
+    FsqrtD(Rtype),
+    FsqrtS(Rtype),
+    FsubD(Rtype),
+    FsubS(Rtype),
+    Fsw(Stype),
     Jal(Jtype),
     Jalr(Itype),
     Lui(Utype),
+    LrD(Atype),
+    LrW(Atype),
     Lb(Itype),
     Lbu(Itype),
     Lh(Itype),
     Lhu(Itype),
     Lw(Itype),
+    Lwu(Itype),
+    Ld(Itype),
+    Mret,
+    Mul(Rtype),
+    Mulh(Rtype),
+    Mulhsu(Rtype),
+    Mulhu(Rtype),
+    Mulw(Rtype),
     Or(Rtype),
     Ori(Itype),
+    Rem(Rtype),
+    Remu(Rtype),
+    Remuw(Rtype),
+    Remw(Rtype),
     Sb(Stype),
+    ScD(Atype),
+    ScW(Atype),
+    Sd(Stype),
+    SfenceVma(Rtype),
     Sh(Stype),
     Sll(Rtype),
     Slli(Itype),
+    Slliw(Itype),
+    Sllw(Rtype),
     Slt(Rtype),
     Slti(Itype),
     Sltiu(Itype),
     Sltu(Rtype),
+    Sret,
     Sra(Rtype),
+    Sraw(Rtype),
     Srl(Rtype),
     Srli(Itype),
+    Srliw(Itype),
+    Srlw(Rtype),
     Srai(Itype),
+    Sraiw(Itype),
     Sw(Stype),
     Sub(Rtype),
+    Subw(Rtype),
+    Wfi,
     Xor(Rtype),
     Xori(Itype),
 }
+
+impl Instruction {
+    /// Whether this is an F or D instruction, which are illegal while
+    /// mstatus.FS is Off
+    pub fn is_float(&self) -> bool {
+        matches!(
+            self,
+            Instruction::FaddD(_)
+                | Instruction::FaddS(_)
+                | Instruction::FclassD(_)
+                | Instruction::FclassS(_)
+                | Instruction::FcvtDL(_)
+                | Instruction::FcvtDLu(_)
+                | Instruction::FcvtDS(_)
+                | Instruction::FcvtDW(_)
+                | Instruction::FcvtDWu(_)
+                | Instruction::FcvtLD(_)
+                | Instruction::FcvtLS(_)
+                | Instruction::FcvtLuD(_)
+                | Instruction::FcvtLuS(_)
+                | Instruction::FcvtSD(_)
+                | Instruction::FcvtSL(_)
+                | Instruction::FcvtSLu(_)
+                | Instruction::FcvtSW(_)
+                | Instruction::FcvtSWu(_)
+                | Instruction::FcvtWD(_)
+                | Instruction::FcvtWS(_)
+                | Instruction::FcvtWuD(_)
+                | Instruction::FcvtWuS(_)
+                | Instruction::FdivD(_)
+                | Instruction::FdivS(_)
+                | Instruction::FeqD(_)
+                | Instruction::FeqS(_)
+                | Instruction::Fld(_)
+                | Instruction::FleD(_)
+                | Instruction::FleS(_)
+                | Instruction::FltD(_)
+                | Instruction::FltS(_)
+                | Instruction::Flw(_)
+                | Instruction::FmaddD(_)
+                | Instruction::FmaddS(_)
+                | Instruction::FmaxD(_)
+                | Instruction::FmaxS(_)
+                | Instruction::FminD(_)
+                | Instruction::FminS(_)
+                | Instruction::FmsubD(_)
+                | Instruction::FmsubS(_)
+                | Instruction::FmulD(_)
+                | Instruction::FmulS(_)
+                | Instruction::FmvDX(_)
+                | Instruction::FmvWX(_)
+                | Instruction::FmvXD(_)
+                | Instruction::FmvXW(_)
+                | Instruction::FnmaddD(_)
+                | Instruction::FnmaddS(_)
+                | Instruction::FnmsubD(_)
+                | Instruction::FnmsubS(_)
+                | Instruction::Fsd(_)
+                | Instruction::FsgnjD(_)
+                | Instruction::FsgnjS(_)
+                | Instruction::FsgnjnD(_)
+                | Instruction::FsgnjnS(_)
+                | Instruction::FsgnjxD(_)
+                | Instruction::FsgnjxS(_)
+                | Instruction::FsqrtD(_)
+                | Instruction::FsqrtS(_)
+                | Instruction::FsubD(_)
+                | Instruction::FsubS(_)
+                | Instruction::Fsw(_)
+        )
+    }
+}
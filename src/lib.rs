@@ -0,0 +1,29 @@
+//! A RISC-V RV64GC emulator
+//!
+//! Programs are loaded into an [`emulator::Emulator`] either as a raw image
+//! placed at physical address 0, or from an ELF executable with
+//! [`emulator::Emulator::from_elf`].
+
+pub mod bus;
+pub mod clint;
+pub mod csr;
+mod decoder;
+pub mod elf;
+pub mod emulator;
+pub mod fdt;
+mod float;
+pub mod htif;
+mod instruction;
+pub mod linux;
+pub mod mmu;
+pub mod plic;
+mod pmp;
+pub mod signature;
+pub mod symbols;
+pub mod syscall;
+#[cfg(test)]
+mod testing;
+pub mod trap;
+mod types;
+pub mod uart;
+pub mod virtio;
@@ -0,0 +1,362 @@
+use crate::csr;
+use crate::elf::Elf;
+use crate::emulator::Emulator;
+use crate::mmu::PAGE_SIZE;
+use crate::syscall::{self, Abi, Syscalls};
+
+/// Memory given to a process beyond its loaded image, shared between the
+/// heap, mmap allocations and the stack
+const PROCESS_MEMORY_SIZE: u64 = 256 << 20;
+
+/// Memory reserved for the stack at the top of the address space
+const STACK_SIZE: u64 = 8 << 20;
+
+// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+/// AT_HWCAP bits, which use the misa encoding, for the single-letter
+/// extensions implemented
+const HWCAP: u64 = csr::extension(b'I')
+    | csr::extension(b'M')
+    | csr::extension(b'A')
+    | csr::extension(b'F')
+    | csr::extension(b'D')
+    | csr::extension(b'C');
+
+impl Emulator {
+    /// Create a process running a statically linked Linux executable in
+    /// U-mode, with its syscalls serviced by the host. Memory starts at the
+    /// lowest loaded page and is mapped one-to-one without translation; the
+    /// stack holding `args`, `env` and the auxiliary vector is at its top.
+    pub fn linux_process(elf: &Elf, args: &[String], env: &[String]) -> Self {
+        Self::process(elf, args, env, Abi::Linux)
+    }
+
+    /// Create a process running a program built for the riscv-pk proxy
+    /// kernel, such as a newlib program, laid out as by `linux_process`.
+    /// Like pk, no environment is passed.
+    pub fn pk_process(elf: &Elf, args: &[String]) -> Self {
+        Self::process(elf, args, &[], Abi::Pk)
+    }
+
+    fn process(elf: &Elf, args: &[String], env: &[String], abi: Abi) -> Self {
+        let mut emu = Emulator::from_elf_with_memory(elf, PROCESS_MEMORY_SIZE);
+        let stack_top = emu.bus.ram_base + emu.bus.ram.len() as u64;
+        let image_end = stack_top - PROCESS_MEMORY_SIZE;
+
+        emu.privilege = csr::Privilege::User;
+        // A PMP entry granting U-mode access to all memory
+        emu.csr.set(csr::PMPADDR0, u64::MAX >> 10);
+        emu.csr.set(csr::PMPCFG0, 0x1f);
+        let mstatus = emu.csr.get(csr::MSTATUS);
+        emu.csr.set(csr::MSTATUS, mstatus | csr::MSTATUS_FS_INITIAL);
+
+        emu.regs[2] = emu.build_stack(elf, stack_top, args, env);
+        emu.syscalls = Some(Syscalls::new(
+            abi,
+            image_end,
+            stack_top - STACK_SIZE,
+            STACK_SIZE,
+        ));
+        emu
+    }
+
+    /// Write the initial process stack below `top` and return the stack
+    /// pointer, which points at argc followed by the argv and envp arrays
+    /// and the auxiliary vector
+    fn build_stack(&mut self, elf: &Elf, top: u64, args: &[String], env: &[String]) -> u64 {
+        let mut sp = top;
+        let arg_pointers: Vec<u64> = args
+            .iter()
+            .map(|arg| self.push_string(&mut sp, arg))
+            .collect();
+        let env_pointers: Vec<u64> = env
+            .iter()
+            .map(|var| self.push_string(&mut sp, var))
+            .collect();
+        let execfn = arg_pointers.first().copied().unwrap_or(0);
+
+        let mut random = [0; 16];
+        // Without a host random source the bytes are left as zeroes
+        let _ = syscall::host_random(&mut random);
+        let random = self.push_bytes(&mut sp, &random);
+        let (uid, gid) = syscall::host_ids();
+
+        let auxv = [
+            (AT_PHDR, elf.program_headers.unwrap_or(0)),
+            (AT_PHENT, elf.program_header_size as u64),
+            (AT_PHNUM, elf.program_header_count as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, uid),
+            (AT_EUID, uid),
+            (AT_GID, gid),
+            (AT_EGID, gid),
+            (AT_HWCAP, HWCAP),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+
+        let mut words = vec![args.len() as u64];
+        words.extend(&arg_pointers);
+        words.push(0);
+        words.extend(&env_pointers);
+        words.push(0);
+        for (key, value) in auxv {
+            words.extend([key, value]);
+        }
+
+        // The stack pointer is 16-byte aligned at entry
+        let sp = (sp - 8 * words.len() as u64) & !15;
+        for (index, word) in words.iter().enumerate() {
+            self.write_memory(sp + 8 * index as u64, 8, *word);
+        }
+        sp
+    }
+
+    /// Copy `bytes` to the stack below `sp`, returning their address
+    fn push_bytes(&mut self, sp: &mut u64, bytes: &[u8]) -> u64 {
+        *sp -= bytes.len() as u64;
+        let start = (*sp - self.bus.ram_base) as usize;
+        self.bus.ram[start..start + bytes.len()].copy_from_slice(bytes);
+        *sp
+    }
+
+    fn push_string(&mut self, sp: &mut u64, string: &str) -> u64 {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        self.push_bytes(sp, &bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::elf::Segment;
+    use crate::symbols::SymbolTable;
+    use crate::syscall::PK_PANIC_STATUS;
+    use crate::trap::Exception;
+
+    /// A Linux executable with its code at 0x10000
+    fn executable(code: Vec<u8>) -> Elf {
+        Elf {
+            entry: 0x10000,
+            segments: vec![Segment {
+                address: 0x10000,
+                size: code.len() as u64,
+                data: code,
+            }],
+            program_headers: Some(0x10040),
+            program_header_size: 56,
+            program_header_count: 2,
+            linux: true,
+            symbols: SymbolTable::default(),
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn read_string(emu: &Emulator, address: u64) -> String {
+        let start = (address - emu.bus.ram_base) as usize;
+        let length = emu.bus.ram[start..].iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(emu.bus.ram[start..start + length].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_initial_stack() {
+        let elf = executable(vec![0; 4]);
+        let mut emu = Emulator::linux_process(&elf, &strings(&["prog", "arg"]), &strings(&["A=1"]));
+        assert_eq!(emu.pc, 0x10000);
+        assert_eq!(emu.privilege, csr::Privilege::User);
+
+        let sp = emu.getreg(2);
+        assert_eq!(sp % 16, 0);
+        let words: Vec<u64> = (0..)
+            .map_while(|index| emu.read_memory(sp + 8 * index, 8))
+            .collect();
+        let word = |index: usize| words[index];
+        assert_eq!(word(0), 2);
+        assert_eq!(read_string(&emu, word(1)), "prog");
+        assert_eq!(read_string(&emu, word(2)), "arg");
+        assert_eq!(word(3), 0);
+        assert_eq!(read_string(&emu, word(4)), "A=1");
+        assert_eq!(word(5), 0);
+
+        let mut auxv = Vec::new();
+        for index in (6..).step_by(2) {
+            auxv.push((word(index), word(index + 1)));
+            if word(index) == AT_NULL {
+                break;
+            }
+        }
+        let aux = |key| auxv.iter().find(|(k, _)| *k == key).unwrap().1;
+        assert_eq!(aux(AT_PHDR), 0x10040);
+        assert_eq!(aux(AT_PHENT), 56);
+        assert_eq!(aux(AT_PHNUM), 2);
+        assert_eq!(aux(AT_PAGESZ), 4096);
+        assert_eq!(aux(AT_ENTRY), 0x10000);
+        assert_eq!(read_string(&emu, aux(AT_EXECFN)), "prog");
+        let random = aux(AT_RANDOM);
+        assert!(random > sp && emu.bus.ram_range(random, 16).is_some());
+    }
+
+    #[test]
+    fn test_syscalls() {
+        let code = vec![
+            0x03, 0x34, 0x01, 0x00, // ld s0, 0(sp)
+            0x83, 0x34, 0x01, 0x01, // ld s1, 16(sp)
+            0x13, 0x05, 0xc0, 0xf9, // addi a0, zero, -100
+            0x93, 0x85, 0x04, 0x00, // addi a1, s1, 0
+            0x13, 0x06, 0x10, 0x24, // addi a2, zero, 577
+            0x93, 0x06, 0x40, 0x1a, // addi a3, zero, 420
+            0x93, 0x08, 0x80, 0x03, // addi a7, zero, 56
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x09, 0x05, 0x00, // addi s2, a0, 0
+            0x13, 0x05, 0x00, 0x00, // addi a0, zero, 0
+            0x93, 0x08, 0x60, 0x0d, // addi a7, zero, 214
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x93, 0x09, 0x05, 0x00, // addi s3, a0, 0
+            0xb7, 0x12, 0x00, 0x00, // lui t0, 1
+            0x33, 0x85, 0x59, 0x00, // add a0, s3, t0
+            0x93, 0x08, 0x60, 0x0d, // addi a7, zero, 214
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x0a, 0x05, 0x00, // addi s4, a0, 0
+            0x23, 0xb0, 0x89, 0x00, // sd s0, 0(s3)
+            0x13, 0x05, 0x00, 0x00, // addi a0, zero, 0
+            0xb7, 0x25, 0x00, 0x00, // lui a1, 2
+            0x13, 0x06, 0x30, 0x00, // addi a2, zero, 3
+            0x93, 0x06, 0x20, 0x02, // addi a3, zero, 34
+            0x13, 0x07, 0xf0, 0xff, // addi a4, zero, -1
+            0x93, 0x07, 0x00, 0x00, // addi a5, zero, 0
+            0x93, 0x08, 0xe0, 0x0d, // addi a7, zero, 222
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x93, 0x0a, 0x05, 0x00, // addi s5, a0, 0
+            0x93, 0x08, 0x00, 0x0a, // addi a7, zero, 160
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x05, 0x09, 0x00, // addi a0, s2, 0
+            0x93, 0x85, 0x4a, 0x10, // addi a1, s5, 260
+            0x13, 0x06, 0x70, 0x00, // addi a2, zero, 7
+            0x93, 0x08, 0x00, 0x04, // addi a7, zero, 64
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x05, 0x09, 0x00, // addi a0, s2, 0
+            0x93, 0x08, 0x90, 0x03, // addi a7, zero, 57
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x93, 0x08, 0x80, 0x3e, // addi a7, zero, 1000
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x0b, 0x05, 0x00, // addi s6, a0, 0
+            0x13, 0x05, 0xa0, 0x02, // addi a0, zero, 42
+            0x93, 0x08, 0xe0, 0x05, // addi a7, zero, 94
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x73, 0x10, 0x00, 0xc0, // unimp
+        ];
+        let path = std::env::temp_dir().join(format!("rvemu-linux-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut emu = Emulator::linux_process(&executable(code), &strings(&["prog", path]), &[]);
+        let image_end = 0x11000;
+        let stack_top = image_end + PROCESS_MEMORY_SIZE;
+        emu.run().unwrap();
+
+        assert_eq!(emu.exit_status(), Some(42));
+        assert_eq!(emu.getreg(8), 2); // argc
+        assert_eq!(emu.getreg(18), 3); // the first free file descriptor
+        assert_eq!(emu.getreg(19), image_end);
+        assert_eq!(emu.getreg(20), image_end + 4096);
+        assert_eq!(emu.read_memory(image_end, 8), Some(2));
+        assert_eq!(emu.getreg(21), stack_top - STACK_SIZE - 8192);
+        assert_eq!(emu.getreg(22), -38i64 as u64); // ENOSYS
+                                                   // uname's machine field was written to the file
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "riscv64");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_fault() {
+        let code = vec![
+            0x03, 0x35, 0x00, 0x00, // ld a0, 0(zero)
+        ];
+        let mut emu = Emulator::linux_process(&executable(code), &strings(&["prog"]), &[]);
+        // Exceptions end the process, as there is no guest kernel
+        assert_eq!(emu.run(), Err(Exception::LoadAccessFault(0)));
+        assert_eq!(emu.pc, 0x10000);
+        assert_eq!(emu.exit_status(), None);
+    }
+
+    #[test]
+    fn test_pk() {
+        let code = vec![
+            0x83, 0x34, 0x01, 0x01, // ld s1, 16(sp)
+            0x13, 0x85, 0x04, 0x00, // addi a0, s1, 0
+            0x93, 0x05, 0x10, 0x24, // addi a1, zero, 577
+            0x13, 0x06, 0x40, 0x1a, // addi a2, zero, 420
+            0x93, 0x08, 0x00, 0x40, // addi a7, zero, 1024
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x09, 0x05, 0x00, // addi s2, a0, 0
+            0x13, 0x01, 0x01, 0xf8, // addi sp, sp, -128
+            0xb7, 0x72, 0x00, 0x00, // lui t0, 7
+            0x9b, 0x82, 0x02, 0xb7, // addiw t0, t0, -1168
+            0x23, 0x10, 0x51, 0x00, // sh t0, 0(sp)
+            0x13, 0x05, 0x09, 0x00, // addi a0, s2, 0
+            0x93, 0x05, 0x01, 0x00, // addi a1, sp, 0
+            0x13, 0x06, 0x20, 0x00, // addi a2, zero, 2
+            0x93, 0x08, 0x00, 0x04, // addi a7, zero, 64
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x05, 0x09, 0x00, // addi a0, s2, 0
+            0x93, 0x08, 0x90, 0x03, // addi a7, zero, 57
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x85, 0x04, 0x00, // addi a0, s1, 0
+            0x93, 0x05, 0x01, 0x00, // addi a1, sp, 0
+            0x93, 0x08, 0xe0, 0x40, // addi a7, zero, 1038
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x93, 0x09, 0x05, 0x00, // addi s3, a0, 0
+            0x03, 0x3a, 0x01, 0x03, // ld s4, 48(sp)
+            0x93, 0x08, 0x80, 0x3e, // addi a7, zero, 1000
+            0x73, 0x00, 0x00, 0x00, // ecall
+            0x13, 0x05, 0x00, 0x00, // addi a0, zero, 0
+            0x93, 0x08, 0xd0, 0x05, // addi a7, zero, 93
+            0x73, 0x00, 0x00, 0x00, // ecall
+        ];
+        let path = std::env::temp_dir().join(format!("rvemu-pk-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let elf = executable(code);
+        let mut emu = Emulator::pk_process(&elf, &strings(&["prog", path]));
+        emu.run().unwrap();
+
+        assert_eq!(emu.getreg(18), 3); // fd from the legacy open
+        assert_eq!(emu.getreg(19), 0); // stat succeeded
+        assert_eq!(emu.getreg(20), 2); // st_size
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "pk");
+        // The unknown syscall ended the program before its exit
+        assert_eq!(emu.exit_status(), Some(PK_PANIC_STATUS));
+
+        // Linux has no legacy open, so it fails with ENOSYS
+        let mut emu = Emulator::linux_process(&elf, &strings(&["prog", path]), &[]);
+        for _ in 0..6 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.getreg(10), -38i64 as u64);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,29 +1,305 @@
-mod decoder;
-mod emulator;
-mod instruction;
-mod types;
-
 use std::env;
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::process;
+use std::sync::mpsc;
 
-use emulator::Emulator;
+use rvemu::clint::{Clint, TimeBase, CLINT_BASE, CLINT_SIZE};
+use rvemu::elf::Elf;
+use rvemu::emulator::Emulator;
+use rvemu::plic::{Plic, PLIC_BASE, PLIC_SIZE, PLIC_SOURCES};
+use rvemu::uart::{RawTerminal, Uart, UART_BASE, UART_IRQ, UART_SIZE};
+use rvemu::virtio::block::{DiskMode, VirtioBlock};
+use rvemu::virtio::console::{Port, VirtioConsole};
+use rvemu::virtio::net::{parse_mac, Backend, Loopback, Pcap, Socket, VirtioNet, DEFAULT_MAC};
+use rvemu::virtio::rng::VirtioRng;
+use rvemu::virtio::{self, VirtioDevice, VirtioMmio, VIRTIO_SIZE, VIRTIO_SLOTS};
 
-fn main() -> Result<(), std::io::Error> {
+fn main() -> Result<(), io::Error> {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        println!("Usage: {} <filename>", args[0]);
+    let mut pk = false;
+    let mut signature = None;
+    let mut granularity = 4;
+    let mut time_base = TimeBase::WallClock;
+    let mut disks = Vec::new();
+    let mut disk_mode = DiskMode::ReadWrite;
+    let mut net = None;
+    let mut pcap = None;
+    let mut mac = DEFAULT_MAC;
+    let mut console = false;
+    let mut console_ports = Vec::new();
+    let mut rng = None;
+    let mut memory = None;
+    let mut bootargs = None;
+    let mut dtb = None;
+    let mut dump_dtb = None;
+    let mut options = 1;
+    for arg in args.iter().skip(1).take_while(|arg| arg.starts_with("--")) {
+        if arg == "--pk" {
+            pk = true;
+        } else if let Some(path) = arg.strip_prefix("--signature=") {
+            signature = Some(path);
+        } else if let Some(Ok(bytes)) = arg.strip_prefix("--signature-granularity=").map(str::parse)
+        {
+            granularity = bytes;
+        } else if arg == "--timebase=wall" {
+            time_base = TimeBase::WallClock;
+        } else if arg == "--timebase=instructions" {
+            time_base = TimeBase::Instructions;
+        } else if let Some(path) = arg.strip_prefix("--disk=") {
+            disks.push(path);
+        } else if arg == "--disk-mode=rw" {
+            disk_mode = DiskMode::ReadWrite;
+        } else if arg == "--disk-mode=ro" {
+            disk_mode = DiskMode::ReadOnly;
+        } else if arg == "--disk-mode=overlay" {
+            disk_mode = DiskMode::Overlay;
+        } else if arg == "--net=loopback"
+            || arg.starts_with("--net=listen:")
+            || arg.starts_with("--net=connect:")
+        {
+            net = arg.strip_prefix("--net=");
+        } else if let Some(path) = arg.strip_prefix("--net-pcap=") {
+            pcap = Some(path);
+        } else if let Some(Some(address)) = arg.strip_prefix("--net-mac=").map(parse_mac) {
+            mac = address;
+        } else if arg == "--console" {
+            console = true;
+        } else if let Some((name, path)) = arg
+            .strip_prefix("--console-port=")
+            .and_then(|port| port.split_once(':'))
+        {
+            console = true;
+            console_ports.push((name, path));
+        } else if arg == "--rng=host" {
+            rng = Some(None);
+        } else if let Some(Ok(seed)) = arg.strip_prefix("--rng=seed:").map(str::parse) {
+            rng = Some(Some(seed));
+        } else if let Some(Ok(mib)) = arg.strip_prefix("--memory=").map(str::parse::<usize>) {
+            memory = Some(mib << 20);
+        } else if let Some(text) = arg.strip_prefix("--bootargs=") {
+            bootargs = Some(text);
+        } else if let Some(path) = arg.strip_prefix("--dtb=") {
+            dtb = Some(path);
+        } else if let Some(path) = arg.strip_prefix("--dump-dtb=") {
+            dump_dtb = Some(path);
+        } else {
+            eprintln!("{}: invalid option {}", args[0], arg);
+            process::exit(1);
+        }
+        options += 1;
+    }
+    let program_args = &args[options..];
+    if program_args.is_empty() {
+        println!("Usage: {} [options] <filename> [args...]", args[0]);
+        println!("  --pk                       service syscalls as riscv-pk does");
+        println!("  --signature=<file>         write the riscv-arch-test signature");
+        println!("  --signature-granularity=<bytes>");
+        println!("                             bytes per signature line (default 4)");
+        println!("  --timebase=wall|instructions");
+        println!("                             count mtime in host time (default) or");
+        println!("                             in instructions, for reproducible runs");
+        println!("  --disk=<image>             attach a virtio block device");
+        println!("  --disk-mode=rw|ro|overlay  let the guest write to disk images (default),");
+        println!("                             only read them, or keep its writes in memory");
+        println!("  --net=loopback|listen:<socket>|connect:<socket>");
+        println!("                             attach a virtio network device returning");
+        println!("                             frames to the guest, or connected to another");
+        println!("                             emulator over a Unix socket");
+        println!("  --net-pcap=<file>          record network traffic to a pcap file");
+        println!("  --net-mac=<address>        MAC address (default 52:54:00:12:34:56)");
+        println!("  --console                  attach a virtio console on stdio, taking");
+        println!("                             the input the UART would otherwise get");
+        println!("  --console-port=<name>:<socket>");
+        println!("                             add a console port on a Unix socket");
+        println!("  --rng=host|seed:<n>        attach a virtio entropy device, random or");
+        println!("                             seeded for reproducible runs");
+        println!("  --memory=<MiB>             RAM size of bare-metal programs (default:");
+        println!("                             as much as the program takes)");
+        println!("  --bootargs=<text>          kernel command line in the device tree");
+        println!("  --dtb=<file>               pass this device tree instead of one");
+        println!("                             describing the machine");
+        println!("  --dump-dtb=<file>          write the device tree to a file and exit");
+        println!();
+        println!("Guests on a serial or virtio console get Ctrl-C like any other key;");
+        println!("type Ctrl-A x to quit.");
         return Ok(());
     }
-    let mut file = File::open(&args[1])?;
+    let filename = &program_args[0];
+    let mut file = File::open(filename)?;
     let mut data = Vec::new();
     file.read_to_end(&mut data)?;
 
-    let mut emu = Emulator::new(data);
-    emu.run();
+    // ELF executables are loaded at their own addresses, and run as user
+    // processes if built for Linux or pk; anything else is a raw image
+    // starting at address 0. Programs defining `tohost` report to the host
+    // through HTIF, so they run on bare metal even though linkers commonly
+    // mark their stack as they would a Linux program's.
+    let mut emu = if data.starts_with(b"\x7fELF") {
+        match Elf::parse(&data) {
+            Ok(elf) if pk => Emulator::pk_process(&elf, program_args),
+            Ok(elf) if elf.linux && elf.symbols.get("tohost").is_none() => {
+                let env: Vec<String> = env::vars()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                Emulator::linux_process(&elf, program_args, &env)
+            }
+            Ok(elf) => Emulator::from_elf(&elf),
+            Err(error) => {
+                eprintln!("{}: {}", filename, error);
+                process::exit(1);
+            }
+        }
+    } else if pk {
+        eprintln!("{}: pk programs must be ELF executables", filename);
+        process::exit(1);
+    } else {
+        Emulator::new(data)
+    };
+
+    // Bare-metal programs get a timer, an interrupt controller, a serial
+    // console and virtio devices where QEMU's virt board has them, unless
+    // they occupy their addresses
+    let mut terminal = None;
+    let mut stdin = false;
+    if emu.syscalls.is_none() {
+        if let Some(size) = memory {
+            if emu.bus.ram.len() < size {
+                emu.bus.ram.resize(size, 0);
+            }
+        }
+        if !emu.bus.overlaps(CLINT_BASE, CLINT_SIZE) {
+            emu.bus
+                .add_device(CLINT_BASE, CLINT_SIZE, Box::new(Clint::new(time_base)));
+        }
+        if !emu.bus.overlaps(PLIC_BASE, PLIC_SIZE) {
+            emu.bus
+                .add_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(PLIC_SOURCES)));
+        }
+        if !emu.bus.overlaps(UART_BASE, UART_SIZE) {
+            // The virtio console takes stdin when there is one
+            let uart = if console {
+                let (_, input) = mpsc::channel();
+                Uart::new(input, Box::new(io::stdout()))
+            } else {
+                Uart::stdio()
+            };
+            emu.bus
+                .add_device_with_irq(UART_BASE, UART_SIZE, UART_IRQ, Box::new(uart));
+            stdin = true;
+        }
+
+        let mut devices: Vec<Box<dyn VirtioDevice>> = Vec::new();
+        for path in disks {
+            let disk = VirtioBlock::open(Path::new(path), disk_mode);
+            devices.push(Box::new(or_exit(disk, path)));
+        }
+        if net.is_some() || pcap.is_some() {
+            let backend: Option<Box<dyn Backend>> = match net {
+                Some("loopback") => Some(Box::new(Loopback::default())),
+                Some(spec) => Some(Box::new(match spec.split_once(':') {
+                    Some(("listen", path)) => or_exit(Socket::listen(Path::new(path)), path),
+                    Some((_, path)) => or_exit(Socket::connect(Path::new(path)), path),
+                    None => unreachable!(),
+                })),
+                None => None,
+            };
+            let backend = match pcap {
+                Some(path) => {
+                    let file = or_exit(File::create(path), path);
+                    Box::new(Pcap::new(Box::new(file), backend))
+                }
+                None => backend.unwrap(),
+            };
+            devices.push(Box::new(VirtioNet::new(backend, mac)));
+        }
+        if console {
+            let mut ports = vec![Port::stdio()];
+            for (name, path) in console_ports {
+                ports.push(or_exit(Port::listen(name, Path::new(path)), path));
+            }
+            devices.push(Box::new(VirtioConsole::new(ports)));
+            stdin = true;
+        }
+        match rng {
+            Some(Some(seed)) => devices.push(Box::new(VirtioRng::seeded(seed))),
+            Some(None) => devices.push(Box::new(or_exit(VirtioRng::host(), "/dev/urandom"))),
+            None => {}
+        }
+        // Virtio devices take the free slots in order
+        for device in devices {
+            let slot = (0..VIRTIO_SLOTS)
+                .map(virtio::slot)
+                .find(|&(base, _)| !emu.bus.overlaps(base, VIRTIO_SIZE));
+            match slot {
+                Some((base, irq)) => {
+                    let device = Box::new(VirtioMmio::new(device));
+                    emu.bus.add_device_with_irq(base, VIRTIO_SIZE, irq, device);
+                }
+                None => {
+                    eprintln!("{}: not enough free virtio slots", args[0]);
+                    process::exit(1);
+                }
+            }
+        }
+
+        // Programs booting as kernels do find the machine described by a
+        // device tree, at the end of RAM
+        let dtb = match dtb {
+            Some(path) => or_exit(fs::read(path), path),
+            None => emu.device_tree(bootargs),
+        };
+        if let Some(path) = dump_dtb {
+            fs::write(path, &dtb)?;
+            return Ok(());
+        }
+        emu.load_device_tree(&dtb);
+        if stdin {
+            terminal = RawTerminal::enable();
+        }
+    }
+    let result = emu.run();
+    drop(terminal);
+    if let Some(path) = signature {
+        match emu.signature(granularity) {
+            Ok(signature) => fs::write(path, signature)?,
+            Err(error) => {
+                eprintln!("{}: {}", filename, error);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(status) = emu.exit_status() {
+        // Tests reporting through HTIF fail with the failing test's number
+        if emu.syscalls.is_none() && status != 0 {
+            eprintln!("*** FAILED *** (tohost = {})", status);
+        }
+        process::exit(status);
+    }
+    if let Err(exception) = &result {
+        eprintln!(
+            "unhandled exception: {:?} at pc = {}",
+            exception,
+            emu.symbols.describe(emu.pc)
+        );
+    }
 
     emu.print_state();
 
+    // Programs stopped by a fault failed, as did user processes that
+    // stopped without exiting
+    if result.is_err() || emu.syscalls.is_some() {
+        process::exit(1);
+    }
     Ok(())
 }
+
+/// The value of a host file operation, or exit with its error
+fn or_exit<T>(result: io::Result<T>, path: &str) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("{}: {}", path, error);
+        process::exit(1);
+    })
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+
+use crate::csr::{self, Privilege};
+use crate::emulator::Emulator;
+use crate::trap::Exception;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Number of translations cached before the TLB is flushed to make room
+const TLB_ENTRIES: usize = 256;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// satp MODE values of the supported translation schemes
+const SATP_MODE_SV39: u64 = 8;
+const SATP_MODE_SV48: u64 = 9;
+const SATP_MODE_SV57: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    /// Stores and AMOs
+    Store,
+}
+
+impl AccessType {
+    fn page_fault(self, address: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(address),
+            AccessType::Load => Exception::LoadPageFault(address),
+            AccessType::Store => Exception::StorePageFault(address),
+        }
+    }
+
+    pub(crate) fn access_fault(self, address: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault(address),
+            AccessType::Load => Exception::LoadAccessFault(address),
+            AccessType::Store => Exception::StoreAccessFault(address),
+        }
+    }
+}
+
+/// A cached leaf PTE
+#[derive(Clone, Copy, Debug)]
+struct TlbEntry {
+    asid: u16,
+    pte: u64,
+    /// Level of the leaf: 0 for a 4 KiB page, 1 for a 2 MiB megapage, ...
+    level: u32,
+}
+
+impl TlbEntry {
+    fn page_size(&self) -> u64 {
+        PAGE_SIZE << (9 * self.level)
+    }
+
+    fn physical_address(&self, vaddr: u64) -> u64 {
+        let offset = self.page_size() - 1;
+        ((self.pte >> 10) << 12) & !offset | vaddr & offset
+    }
+}
+
+/// Translation cache, indexed by 4 KiB virtual page number. Entries for
+/// superpages are cached once for each 4 KiB page that was accessed.
+#[derive(Default)]
+pub struct Tlb {
+    entries: HashMap<u64, TlbEntry>,
+}
+
+impl Tlb {
+    fn lookup(&self, vaddr: u64, asid: u16) -> Option<TlbEntry> {
+        let entry = self.entries.get(&(vaddr / PAGE_SIZE))?;
+        if entry.pte & PTE_G != 0 || entry.asid == asid {
+            Some(*entry)
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, vaddr: u64, entry: TlbEntry) {
+        if self.entries.len() >= TLB_ENTRIES {
+            self.entries.clear();
+        }
+        self.entries.insert(vaddr / PAGE_SIZE, entry);
+    }
+
+    /// Invalidate cached translations as sfence.vma does: those covering
+    /// `vaddr` (or all of them), for non-global mappings of `asid` (or all
+    /// mappings)
+    pub fn flush(&mut self, vaddr: Option<u64>, asid: Option<u16>) {
+        self.entries.retain(|&vpn, entry| {
+            let address_match = match vaddr {
+                Some(vaddr) => {
+                    let base = (vpn * PAGE_SIZE) & !(entry.page_size() - 1);
+                    vaddr.wrapping_sub(base) < entry.page_size()
+                }
+                None => true,
+            };
+            let asid_match = match asid {
+                Some(asid) => entry.pte & PTE_G == 0 && entry.asid == asid,
+                None => true,
+            };
+            !(address_match && asid_match)
+        });
+    }
+}
+
+impl Emulator {
+    /// Privilege level that memory accesses of this type are checked
+    /// against, taking mstatus.MPRV into account
+    pub(crate) fn effective_privilege(&self, access: AccessType) -> Privilege {
+        let mstatus = self.csr.get(csr::MSTATUS);
+        if access != AccessType::Instruction && mstatus & csr::MSTATUS_MPRV != 0 {
+            Privilege::from_bits((mstatus & csr::MSTATUS_MPP) >> 11)
+        } else {
+            self.privilege
+        }
+    }
+
+    /// Check the permission bits of a leaf PTE
+    fn permitted(&self, pte: u64, access: AccessType, privilege: Privilege) -> bool {
+        let mstatus = self.csr.get(csr::MSTATUS);
+        let user_page = pte & PTE_U != 0;
+        match privilege {
+            Privilege::User if !user_page => return false,
+            Privilege::Supervisor
+                if user_page
+                    && (access == AccessType::Instruction || mstatus & csr::MSTATUS_SUM == 0) =>
+            {
+                return false
+            }
+            _ => {}
+        }
+        match access {
+            AccessType::Instruction => pte & PTE_X != 0,
+            AccessType::Load => {
+                pte & PTE_R != 0 || mstatus & csr::MSTATUS_MXR != 0 && pte & PTE_X != 0
+            }
+            AccessType::Store => pte & PTE_W != 0,
+        }
+    }
+
+    /// Number of page table levels that accesses of this type are
+    /// translated through, or None if their addresses are physical
+    fn translation_levels(&self, access: AccessType) -> Option<u32> {
+        if self.effective_privilege(access) == Privilege::Machine {
+            return None;
+        }
+        match self.csr.get(csr::SATP) >> 60 {
+            SATP_MODE_SV39 => Some(3),
+            SATP_MODE_SV48 => Some(4),
+            SATP_MODE_SV57 => Some(5),
+            _ => None,
+        }
+    }
+
+    /// Whether accesses of this type use virtual addresses
+    pub(crate) fn translates(&self, access: AccessType) -> bool {
+        self.translation_levels(access).is_some()
+    }
+
+    /// Translate a virtual address to a physical one according to satp
+    pub fn translate(&mut self, vaddr: u64, access: AccessType) -> Result<u64, Exception> {
+        let levels = match self.translation_levels(access) {
+            Some(levels) => levels,
+            None => return Ok(vaddr),
+        };
+        let privilege = self.effective_privilege(access);
+        let satp = self.csr.get(csr::SATP);
+
+        // The bits above the virtual address width must all equal its top bit
+        let unused_bits = 64 - (12 + 9 * levels);
+        if ((vaddr << unused_bits) as i64 >> unused_bits) as u64 != vaddr {
+            return Err(access.page_fault(vaddr));
+        }
+
+        let asid = (satp >> 44 & 0xffff) as u16;
+        let entry = match self.tlb.lookup(vaddr, asid) {
+            // A cached PTE can be used unless the A or D bit needs setting
+            Some(entry)
+                if entry.pte & PTE_A != 0
+                    && (access != AccessType::Store || entry.pte & PTE_D != 0) =>
+            {
+                if !self.permitted(entry.pte, access, privilege) {
+                    return Err(access.page_fault(vaddr));
+                }
+                entry
+            }
+            _ => {
+                let entry = self.walk(vaddr, access, privilege, satp, levels)?;
+                self.tlb.insert(vaddr, entry);
+                entry
+            }
+        };
+        Ok(entry.physical_address(vaddr))
+    }
+
+    /// Walk the page table rooted at satp.PPN, setting the A and D bits of
+    /// the leaf PTE as required by the access
+    fn walk(
+        &mut self,
+        vaddr: u64,
+        access: AccessType,
+        privilege: Privilege,
+        satp: u64,
+        levels: u32,
+    ) -> Result<TlbEntry, Exception> {
+        let asid = (satp >> 44 & 0xffff) as u16;
+        let mut table = (satp & ((1 << 44) - 1)) * PAGE_SIZE;
+        for level in (0..levels).rev() {
+            let index = vaddr >> (12 + 9 * level) & 0x1ff;
+            let pte_address = table + index * 8;
+            // Page-table accesses are checked by PMP as S-mode loads and stores
+            if !self
+                .csr
+                .pmp_permits(pte_address, 8, AccessType::Load, Privilege::Supervisor)
+            {
+                return Err(access.access_fault(vaddr));
+            }
+            let pte = self
+                .read_memory(pte_address, 8)
+                .ok_or_else(|| access.access_fault(vaddr))?;
+            // Invalid, write-only or using reserved bits
+            if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W || pte >> 54 != 0 {
+                return Err(access.page_fault(vaddr));
+            }
+
+            let ppn = pte >> 10;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // Pointer to the next level, whose A, D and U bits are reserved
+                if pte & (PTE_A | PTE_D | PTE_U) != 0 {
+                    return Err(access.page_fault(vaddr));
+                }
+                table = ppn * PAGE_SIZE;
+                continue;
+            }
+
+            // Superpages must be aligned to their size
+            if ppn & ((1 << (9 * level)) - 1) != 0 || !self.permitted(pte, access, privilege) {
+                return Err(access.page_fault(vaddr));
+            }
+            let mut updated = pte | PTE_A;
+            if access == AccessType::Store {
+                updated |= PTE_D;
+            }
+            if updated != pte {
+                if !self
+                    .csr
+                    .pmp_permits(pte_address, 8, AccessType::Store, Privilege::Supervisor)
+                {
+                    return Err(access.access_fault(vaddr));
+                }
+                self.write_memory(pte_address, 8, updated)
+                    .ok_or_else(|| access.access_fault(vaddr))?;
+            }
+            return Ok(TlbEntry {
+                asid,
+                pte: updated,
+                level,
+            });
+        }
+        // Ran out of levels without finding a leaf
+        Err(access.page_fault(vaddr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROOT: u64 = 0x1000;
+
+    /// A PTE pointing at the page containing `address`
+    fn pte(address: u64, flags: u64) -> u64 {
+        (address / PAGE_SIZE) << 10 | flags | PTE_V
+    }
+
+    /// An emulator in S-mode with a PMP entry granting access to all of
+    /// physical memory
+    fn supervisor() -> Emulator {
+        let mut emu = Emulator::new(vec![0; 0x10000]);
+        emu.privilege = Privilege::Supervisor;
+        emu.csr.set(csr::PMPADDR0, u64::MAX >> 10);
+        emu.csr.set(csr::PMPCFG0, 0x1f); // NAPOT, RWX
+        emu
+    }
+
+    /// An emulator in S-mode with Sv39 enabled and the page tables
+    /// root 0x1000 -> 0x2000 -> 0x3000 covering virtual 0x0..0x200000
+    fn sv39() -> Emulator {
+        let mut emu = supervisor();
+        emu.csr
+            .set(csr::SATP, (SATP_MODE_SV39 << 60) | (ROOT / PAGE_SIZE));
+        emu.write_memory(ROOT, 8, pte(0x2000, 0));
+        emu.write_memory(0x2000, 8, pte(0x3000, 0));
+        emu
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut emu = sv39();
+        // Virtual page 5 -> physical 0x8000
+        emu.write_memory(0x3000 + 5 * 8, 8, pte(0x8000, PTE_R | PTE_W));
+
+        assert_eq!(emu.translate(0x5123, AccessType::Load), Ok(0x8123));
+        let leaf = emu.read_memory(0x3000 + 5 * 8, 8).unwrap();
+        assert_eq!(leaf & (PTE_A | PTE_D), PTE_A);
+
+        assert_eq!(emu.translate(0x5008, AccessType::Store), Ok(0x8008));
+        let leaf = emu.read_memory(0x3000 + 5 * 8, 8).unwrap();
+        assert_eq!(leaf & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+        assert_eq!(
+            emu.translate(0x5000, AccessType::Instruction),
+            Err(Exception::InstructionPageFault(0x5000))
+        );
+        assert_eq!(
+            emu.translate(0x6000, AccessType::Load),
+            Err(Exception::LoadPageFault(0x6000))
+        );
+        // Bits 63:39 are not a sign extension of bit 38
+        assert_eq!(
+            emu.translate(1 << 39, AccessType::Load),
+            Err(Exception::LoadPageFault(1 << 39))
+        );
+
+        // M-mode bypasses translation, unless MPRV applies it to data
+        emu.privilege = Privilege::Machine;
+        assert_eq!(emu.translate(0x5000, AccessType::Load), Ok(0x5000));
+        let mstatus = emu.csr.get(csr::MSTATUS);
+        emu.csr
+            .set(csr::MSTATUS, mstatus | csr::MSTATUS_MPRV | 1 << 11);
+        assert_eq!(emu.translate(0x5000, AccessType::Load), Ok(0x8000));
+        assert_eq!(emu.translate(0x5000, AccessType::Instruction), Ok(0x5000));
+    }
+
+    #[test]
+    fn test_run_translated() {
+        let mut emu = sv39();
+        // Virtual 0x100000, outside the 64 KiB of RAM, -> physical 0x8000
+        emu.write_memory(0x3000 + 0x100 * 8, 8, pte(0x8000, PTE_R | PTE_X));
+        emu.write_memory(0x8000, 4, 0x0010_0093); // addi x1, x0, 1
+        emu.pc = 0x100000;
+        // The zeroed word after it is illegal, with no handler to trap to
+        assert_eq!(emu.run(), Err(Exception::IllegalInstruction(0)));
+        assert_eq!(emu.regs[1], 1);
+        assert_eq!(emu.pc, 0x100004);
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut emu = sv39();
+        emu.write_memory(0x3000 + 8, 8, pte(0x8000, PTE_R | PTE_X | PTE_U));
+        emu.write_memory(0x3000 + 2 * 8, 8, pte(0x9000, PTE_X));
+        emu.write_memory(0x3000 + 3 * 8, 8, pte(0xa000, PTE_W));
+
+        // User pages need SUM for S-mode data accesses and are never
+        // executable from S-mode
+        assert!(emu.translate(0x1000, AccessType::Load).is_err());
+        emu.csr.set(csr::MSTATUS, csr::MSTATUS_SUM);
+        assert_eq!(emu.translate(0x1000, AccessType::Load), Ok(0x8000));
+        assert!(emu.translate(0x1000, AccessType::Instruction).is_err());
+        emu.privilege = Privilege::User;
+        assert_eq!(emu.translate(0x1000, AccessType::Instruction), Ok(0x8000));
+        assert!(emu.translate(0x1000, AccessType::Store).is_err());
+        assert!(emu.translate(0x2000, AccessType::Instruction).is_err());
+
+        // Execute-only pages are readable with MXR
+        emu.privilege = Privilege::Supervisor;
+        assert!(emu.translate(0x2000, AccessType::Load).is_err());
+        emu.csr.set(csr::MSTATUS, csr::MSTATUS_MXR);
+        assert_eq!(emu.translate(0x2000, AccessType::Load), Ok(0x9000));
+
+        // Write without read is a reserved encoding
+        assert_eq!(
+            emu.translate(0x3000, AccessType::Store),
+            Err(Exception::StorePageFault(0x3000))
+        );
+    }
+
+    #[test]
+    fn test_superpages() {
+        let mut emu = sv39();
+        // 2 MiB megapage at virtual 0x200000 -> physical 0x400000
+        emu.write_memory(0x2000 + 8, 8, pte(0x400000, PTE_R));
+        assert_eq!(emu.translate(0x212345, AccessType::Load), Ok(0x412345));
+        // Misaligned megapage
+        emu.write_memory(0x2000 + 2 * 8, 8, pte(0x401000, PTE_R));
+        assert!(emu.translate(0x400000, AccessType::Load).is_err());
+        // 1 GiB gigapage at virtual 0x40000000 -> physical 0x80000000
+        emu.write_memory(ROOT + 8, 8, pte(0x80000000, PTE_R));
+        assert_eq!(emu.translate(0x4abcdef0, AccessType::Load), Ok(0x8abcdef0));
+    }
+
+    #[test]
+    fn test_sv48_sv57() {
+        for (mode, levels) in [(SATP_MODE_SV48, 4), (SATP_MODE_SV57, 5)] {
+            let mut emu = supervisor();
+            emu.csr.set(csr::SATP, (mode << 60) | (ROOT / PAGE_SIZE));
+            // Chain tables 0x1000 -> 0x2000 -> ... with index 0 at each level
+            for level in 1..levels {
+                let table = level * PAGE_SIZE;
+                emu.write_memory(table, 8, pte(table + PAGE_SIZE, 0));
+            }
+            let leaf = levels * PAGE_SIZE;
+            emu.write_memory(leaf + 7 * 8, 8, pte(0xc000, PTE_R));
+            assert_eq!(emu.translate(0x7010, AccessType::Load), Ok(0xc010));
+            // Canonical negative address, which is unmapped
+            assert_eq!(
+                emu.translate(u64::MAX, AccessType::Load),
+                Err(Exception::LoadPageFault(u64::MAX))
+            );
+        }
+    }
+
+    #[test]
+    fn test_tlb_flush() {
+        let mut emu = sv39();
+        let leaf = 0x3000 + 4 * 8;
+        emu.write_memory(leaf, 8, pte(0x8000, PTE_R | PTE_A));
+        assert_eq!(emu.translate(0x4000, AccessType::Load), Ok(0x8000));
+
+        // The stale translation is used until sfence.vma
+        emu.write_memory(leaf, 8, pte(0x9000, PTE_R | PTE_A));
+        assert_eq!(emu.translate(0x4000, AccessType::Load), Ok(0x8000));
+        emu.tlb.flush(Some(0x5000), None);
+        assert_eq!(emu.translate(0x4000, AccessType::Load), Ok(0x8000));
+        emu.tlb.flush(Some(0x4ff8), None);
+        assert_eq!(emu.translate(0x4000, AccessType::Load), Ok(0x9000));
+
+        // Global mappings survive an ASID-specific flush
+        emu.write_memory(leaf, 8, pte(0xa000, PTE_R | PTE_A | PTE_G));
+        emu.tlb.flush(None, None);
+        assert_eq!(emu.translate(0x4000, AccessType::Load), Ok(0xa000));
+        emu.write_memory(leaf, 8, pte(0xb000, PTE_R | PTE_A));
+        emu.tlb.flush(None, Some(0));
+        assert_eq!(emu.translate(0x4000, AccessType::Load), Ok(0xa000));
+        emu.tlb.flush(None, None);
+        assert_eq!(emu.translate(0x4000, AccessType::Load), Ok(0xb000));
+    }
+
+    #[test]
+    fn test_walk_pmp() {
+        let mut emu = sv39();
+        emu.write_memory(0x3000 + 5 * 8, 8, pte(0x8000, PTE_R));
+        // Page tables at 0x1000..0x4000 are inaccessible to S-mode
+        emu.csr.set(csr::PMPADDR0, 0x7ff);
+        emu.csr.set(csr::PMPADDR0 + 1, u64::MAX >> 10);
+        emu.csr.set(csr::PMPCFG0, 0x1f18); // NAPOT with no access, then RWX
+        assert_eq!(
+            emu.translate(0x5000, AccessType::Load),
+            Err(Exception::LoadAccessFault(0x5000))
+        );
+    }
+}
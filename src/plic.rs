@@ -0,0 +1,352 @@
+use crate::bus::Device;
+use crate::csr::{MIP_MEIP, MIP_SEIP};
+use crate::fdt::{Fdt, CPU_INTC_PHANDLE, PLIC_PHANDLE};
+
+/// Address and size of the PLIC on QEMU's `virt` board
+pub const PLIC_BASE: u64 = 0x0c00_0000;
+pub const PLIC_SIZE: u64 = 0x400_0000;
+
+/// Number of interrupt sources on QEMU's `virt` board, as given by the
+/// `riscv,ndev` property of its device tree
+pub const PLIC_SOURCES: u32 = 95;
+
+// Register regions
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const THRESHOLD: u64 = 0x0;
+const CLAIM: u64 = 0x4;
+
+/// Priorities range from 1 to 7, 0 disabling a source
+const PRIORITY_MASK: u32 = 0x7;
+
+/// Interrupt targets: the M-mode and S-mode contexts of hart 0, in that
+/// order, and the mip bit each drives
+const CONTEXTS: [u64; 2] = [MIP_MEIP, MIP_SEIP];
+
+/// Platform-level interrupt controller, routing level-triggered device
+/// interrupt lines to the external interrupts of the hart's contexts.
+/// Source 0 does not exist; sources are numbered from 1.
+pub struct Plic {
+    sources: u32,
+    priority: Vec<u32>,
+    /// Whether each source's line is asserted, unless it has been claimed
+    /// and not yet completed
+    pending: Vec<bool>,
+    claimed: Vec<bool>,
+    /// Enable bits of each context, 32 sources per word
+    enable: Vec<Vec<u32>>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    /// A PLIC with sources 1 to `sources`
+    pub fn new(sources: u32) -> Self {
+        let count = sources as usize + 1;
+        Self {
+            sources,
+            priority: vec![0; count],
+            pending: vec![false; count],
+            claimed: vec![false; count],
+            enable: vec![vec![0; count.div_ceil(32)]; CONTEXTS.len()],
+            threshold: vec![0; CONTEXTS.len()],
+        }
+    }
+
+    fn enabled(&self, context: usize, source: usize) -> bool {
+        self.enable[context][source / 32] >> (source % 32) & 1 == 1
+    }
+
+    /// The pending source a context would claim: the one enabled for it
+    /// with the highest priority above its threshold, ties going to the
+    /// lowest source number
+    fn best(&self, context: usize) -> Option<usize> {
+        (1..self.priority.len())
+            .filter(|&source| self.pending[source] && self.enabled(context, source))
+            .filter(|&source| self.priority[source] > self.threshold[context])
+            .min_by_key(|&source| (u32::MAX - self.priority[source], source))
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending[source] = false;
+                self.claimed[source] = true;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    /// Signal the end of a source's handler, so that it can interrupt
+    /// again. Completions for sources not enabled for the context are
+    /// ignored.
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if source < self.claimed.len() && self.enabled(context, source) {
+            self.claimed[source] = false;
+        }
+    }
+
+    /// Context and register offset within it of an access to the
+    /// per-context registers
+    fn context(offset: u64) -> Option<(usize, u64)> {
+        let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+        if context < CONTEXTS.len() {
+            Some((context, (offset - CONTEXT) % CONTEXT_STRIDE))
+        } else {
+            None
+        }
+    }
+
+    /// Context and word index of an access to the enable bits
+    fn enable_word(&self, offset: u64) -> Option<(usize, usize)> {
+        let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+        let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+        if context < CONTEXTS.len() && word < self.enable[context].len() {
+            Some((context, word))
+        } else {
+            None
+        }
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = match offset {
+            PRIORITY..=0xfff => {
+                let source = (offset / 4) as usize;
+                self.priority.get(source).copied().unwrap_or(0)
+            }
+            PENDING..=0x1fff => {
+                let first = (offset - PENDING) as usize * 8;
+                (0..32)
+                    .filter(|bit| self.pending.get(first + bit) == Some(&true))
+                    .fold(0, |word, bit| word | 1 << bit)
+            }
+            ENABLE..=0x1f_ffff => match self.enable_word(offset) {
+                Some((context, word)) => self.enable[context][word],
+                None => 0,
+            },
+            _ if offset >= CONTEXT => match Self::context(offset) {
+                Some((context, THRESHOLD)) => self.threshold[context],
+                Some((context, CLAIM)) => self.claim(context),
+                _ => 0,
+            },
+            _ => 0,
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = value as u32;
+        match offset {
+            PRIORITY..=0xfff => {
+                let source = (offset / 4) as usize;
+                if source != 0 && source < self.priority.len() {
+                    self.priority[source] = value & PRIORITY_MASK;
+                }
+            }
+            ENABLE..=0x1f_ffff => {
+                if let Some((context, word)) = self.enable_word(offset) {
+                    // Source 0 and sources past the last do not exist
+                    let mut mask = u32::MAX;
+                    if word == 0 {
+                        mask &= !1;
+                    }
+                    let last = self.sources as usize;
+                    if last / 32 == word {
+                        mask &= u32::MAX >> (31 - last % 32);
+                    }
+                    self.enable[context][word] = value & mask;
+                }
+            }
+            _ if offset >= CONTEXT => match Self::context(offset) {
+                Some((context, THRESHOLD)) => self.threshold[context] = value & PRIORITY_MASK,
+                Some((context, CLAIM)) => self.complete(context, value),
+                _ => {}
+            },
+            // The pending bits are read-only
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn set_interrupt_sources(&mut self, sources: &[u32]) {
+        for source in 1..self.pending.len() {
+            self.pending[source] = !self.claimed[source] && sources.contains(&(source as u32));
+        }
+    }
+
+    fn hart_interrupts(&self) -> u64 {
+        CONTEXTS
+            .iter()
+            .enumerate()
+            .filter(|&(context, _)| self.best(context).is_some())
+            .fold(0, |mip, (_, bit)| mip | bit)
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, _irq: Option<u32>) {
+        fdt.begin_node(&format!("plic@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_u64s("reg", &[base, size]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        // The M-mode and S-mode external interrupts, in context order
+        fdt.property_cells(
+            "interrupts-extended",
+            &[CPU_INTC_PHANDLE, 11, CPU_INTC_PHANDLE, 9],
+        );
+        fdt.property_u32("riscv,ndev", self.sources);
+        fdt.property_u32("phandle", PLIC_PHANDLE);
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::csr;
+    use crate::emulator::Emulator;
+
+    const M: usize = 0;
+    const S: usize = 1;
+
+    fn threshold(context: usize) -> u64 {
+        CONTEXT + CONTEXT_STRIDE * context as u64 + THRESHOLD
+    }
+
+    fn claim(context: usize) -> u64 {
+        CONTEXT + CONTEXT_STRIDE * context as u64 + CLAIM
+    }
+
+    fn enable(context: usize) -> u64 {
+        ENABLE + ENABLE_STRIDE * context as u64
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut plic = Plic::new(PLIC_SOURCES);
+        plic.write(PRIORITY + 4 * 10, 4, 0xff).unwrap();
+        assert_eq!(plic.read(PRIORITY + 4 * 10, 4), Some(7));
+        plic.write(PRIORITY, 4, 1).unwrap();
+        assert_eq!(plic.read(PRIORITY, 4), Some(0));
+
+        // Source 0 and sources above the last cannot be enabled
+        plic.write(enable(S), 4, u32::MAX as u64).unwrap();
+        plic.write(enable(S) + 12, 4, u32::MAX as u64).unwrap();
+        assert_eq!(plic.read(enable(S), 4), Some(0xffff_fffe));
+        assert_eq!(plic.read(enable(S) + 12, 4), Some(0));
+        assert_eq!(plic.read(enable(M), 4), Some(0));
+        let mut small = Plic::new(40);
+        small.write(enable(M) + 4, 4, u32::MAX as u64).unwrap();
+        assert_eq!(small.read(enable(M) + 4, 4), Some(0x1ff));
+
+        plic.write(threshold(M), 4, 3).unwrap();
+        assert_eq!(plic.read(threshold(M), 4), Some(3));
+        assert_eq!(plic.read(threshold(S), 4), Some(0));
+        assert_eq!(plic.read(PRIORITY, 2), None);
+    }
+
+    #[test]
+    fn test_claim_complete() {
+        let mut plic = Plic::new(PLIC_SOURCES);
+        for source in [1, 10, 40] {
+            plic.write(PRIORITY + 4 * source, 4, 1).unwrap();
+        }
+        plic.write(PRIORITY + 4 * 40, 4, 2).unwrap();
+        plic.write(enable(S), 4, 1 << 1 | 1 << 10).unwrap();
+        plic.write(enable(S) + 4, 4, 1 << 8).unwrap();
+
+        plic.set_interrupt_sources(&[10, 40, 1]);
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 1 | 1 << 10));
+        assert_eq!(plic.read(PENDING + 4, 4), Some(1 << 8));
+        assert_eq!(plic.hart_interrupts(), MIP_SEIP);
+
+        // Highest priority first, then the lowest source number
+        assert_eq!(plic.read(claim(S), 4), Some(40));
+        assert_eq!(plic.read(claim(S), 4), Some(1));
+        assert_eq!(plic.read(claim(S), 4), Some(10));
+        assert_eq!(plic.read(claim(S), 4), Some(0));
+        assert_eq!(plic.hart_interrupts(), 0);
+
+        // A claimed source does not interrupt again until completed, even
+        // with its line still asserted
+        plic.set_interrupt_sources(&[10]);
+        assert_eq!(plic.read(PENDING, 4), Some(0));
+        plic.write(claim(M), 4, 10).unwrap();
+        plic.set_interrupt_sources(&[10]);
+        assert_eq!(plic.read(PENDING, 4), Some(0));
+        plic.write(claim(S), 4, 10).unwrap();
+        plic.set_interrupt_sources(&[10]);
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 10));
+
+        // Interrupts at or below the threshold are masked
+        plic.write(threshold(S), 4, 1).unwrap();
+        assert_eq!(plic.hart_interrupts(), 0);
+        assert_eq!(plic.read(claim(S), 4), Some(0));
+        plic.write(threshold(S), 4, 0).unwrap();
+        plic.write(enable(M), 4, 1 << 10).unwrap();
+        plic.write(PRIORITY + 4 * 10, 4, 1).unwrap();
+        assert_eq!(plic.hart_interrupts(), MIP_MEIP | MIP_SEIP);
+        // Lowering the line before the claim withdraws the interrupt
+        plic.set_interrupt_sources(&[]);
+        assert_eq!(plic.hart_interrupts(), 0);
+    }
+
+    /// Asserts its interrupt line while `level` is set
+    struct Line {
+        level: bool,
+    }
+
+    impl Device for Line {
+        fn read(&mut self, _offset: u64, _size: usize) -> Option<u64> {
+            Some(0)
+        }
+
+        fn write(&mut self, _offset: u64, _size: usize, value: u64) -> Option<()> {
+            self.level = value != 0;
+            Some(())
+        }
+
+        fn interrupt(&self) -> bool {
+            self.level
+        }
+    }
+
+    #[test]
+    fn test_external_interrupt() {
+        let mut bus = Bus::new(0, vec![0; 0x1000]);
+        bus.add_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(PLIC_SOURCES)));
+        bus.add_device_with_irq(0x1000_0000, 0x100, 10, Box::new(Line { level: false }));
+        let mut emu = Emulator::new(Vec::new());
+        emu.bus = bus;
+        emu.bus.write(PLIC_BASE + 4 * 10, 4, 1).unwrap();
+        emu.bus.write(PLIC_BASE + enable(M), 4, 1 << 10).unwrap();
+        emu.csr.set(csr::MIE, csr::MIP_MEIP);
+        emu.csr.set(csr::MSTATUS, csr::MSTATUS_MIE);
+        emu.csr.set(csr::MTVEC, 0x100);
+        // A loop at 0, and a handler at 0x100
+        emu.bus.write(0, 4, 0x0000006f).unwrap();
+        emu.bus.write(0x100, 4, 0x0000006f).unwrap();
+
+        emu.run_for(10).unwrap();
+        assert_eq!(emu.pc, 0);
+        emu.bus.write(0x1000_0000, 1, 1).unwrap();
+        emu.run_for(1).unwrap();
+        assert_eq!(emu.pc, 0x100);
+        assert_eq!(emu.csr.get(csr::MCAUSE), 1 << 63 | 11);
+        assert_eq!(emu.bus.read(PLIC_BASE + claim(M), 4), Some(10));
+    }
+}
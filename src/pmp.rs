@@ -0,0 +1,282 @@
+use std::ops::Range;
+
+use crate::csr::{CsrFile, Privilege, PMPADDR0, PMPCFG0};
+use crate::mmu::AccessType;
+
+/// Number of PMP entries, the maximum the privileged spec allows
+pub const PMP_ENTRIES: usize = 64;
+
+// Fields of a pmpNcfg byte
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A: u8 = 0b11 << 3;
+pub const PMP_L: u8 = 1 << 7;
+
+/// Address-matching modes held in the A field, other than OFF (0)
+const PMP_TOR: u8 = 1 << 3;
+const PMP_NA4: u8 = 2 << 3;
+const PMP_NAPOT: u8 = 3 << 3;
+
+impl CsrFile {
+    /// Configuration byte of a PMP entry
+    pub(crate) fn pmpcfg(&self, entry: usize) -> u8 {
+        let address = PMPCFG0 + (entry / 8 * 2) as u16;
+        (self.get(address) >> (8 * (entry % 8))) as u8
+    }
+
+    fn pmpaddr(&self, entry: usize) -> u64 {
+        self.get(PMPADDR0 + entry as u16)
+    }
+
+    /// Whether writes to pmpaddr of an entry are ignored: it is locked
+    /// itself, or it is the bottom of a locked TOR range
+    pub(crate) fn pmpaddr_locked(&self, entry: usize) -> bool {
+        if self.pmpcfg(entry) & PMP_L != 0 {
+            return true;
+        }
+        entry + 1 < PMP_ENTRIES && {
+            let next = self.pmpcfg(entry + 1);
+            next & PMP_L != 0 && next & PMP_A == PMP_TOR
+        }
+    }
+
+    /// Physical byte range matched by a PMP entry, which is empty when the
+    /// entry is off
+    fn pmp_range(&self, entry: usize) -> Range<u64> {
+        let pmpaddr = self.pmpaddr(entry);
+        match self.pmpcfg(entry) & PMP_A {
+            PMP_TOR => {
+                let bottom = if entry == 0 {
+                    0
+                } else {
+                    self.pmpaddr(entry - 1)
+                };
+                (bottom << 2)..(pmpaddr << 2)
+            }
+            PMP_NA4 => (pmpaddr << 2)..((pmpaddr << 2) + 4),
+            PMP_NAPOT => {
+                // The number of trailing ones encodes a region of 8 << ones bytes
+                let ones = pmpaddr.trailing_ones();
+                let base = (pmpaddr & !((1 << ones) - 1)) << 2;
+                base..(base + (8 << ones))
+            }
+            _ => 0..0,
+        }
+    }
+
+    /// Check a physical access of `size` bytes against the PMP entries.
+    /// The lowest-numbered entry matching any byte decides: the access
+    /// must lie entirely within it, and is then checked against its
+    /// permissions unless made from M-mode to an unlocked entry. Accesses
+    /// no entry matches only succeed from M-mode.
+    pub fn pmp_permits(
+        &self,
+        address: u64,
+        size: usize,
+        access: AccessType,
+        privilege: Privilege,
+    ) -> bool {
+        let end = address.saturating_add(size as u64);
+        for entry in 0..PMP_ENTRIES {
+            let range = self.pmp_range(entry);
+            if address >= range.end || range.start >= end {
+                continue;
+            }
+            if address < range.start || end > range.end {
+                return false;
+            }
+            let cfg = self.pmpcfg(entry);
+            if privilege == Privilege::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+            let permission = match access {
+                AccessType::Instruction => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return cfg & permission != 0;
+        }
+        privilege == Privilege::Machine
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::csr::{PMPADDR63, PMPCFG14};
+    use crate::emulator::Emulator;
+    use crate::trap::Exception;
+
+    const M: Privilege = Privilege::Machine;
+    const S: Privilege = Privilege::Supervisor;
+    const U: Privilege = Privilege::User;
+
+    /// Configure a PMP entry through CSR writes from M-mode
+    fn configure(csr: &mut CsrFile, entry: usize, cfg: u8, pmpaddr: u64) {
+        csr.write(PMPADDR0 + entry as u16, pmpaddr, M).unwrap();
+        let address = PMPCFG0 + (entry / 8 * 2) as u16;
+        let shift = 8 * (entry % 8);
+        let value = csr.get(address) & !(0xff << shift) | (cfg as u64) << shift;
+        csr.write(address, value, M).unwrap();
+    }
+
+    #[test]
+    fn test_off() {
+        let mut csr = CsrFile::new(0);
+        // With every entry off only M-mode has access
+        assert!(csr.pmp_permits(0x1000, 8, AccessType::Store, M));
+        assert!(!csr.pmp_permits(0x1000, 8, AccessType::Load, S));
+        assert!(!csr.pmp_permits(0x1000, 2, AccessType::Instruction, U));
+
+        // An entry that is off does not match, even with permissions set
+        configure(&mut csr, 0, PMP_R, 0x1000 >> 2);
+        assert!(!csr.pmp_permits(0x0, 8, AccessType::Load, S));
+    }
+
+    #[test]
+    fn test_tor() {
+        let mut csr = CsrFile::new(0);
+        // Entry 0 covers [0, 0x1000), entry 1 covers [0x1000, 0x3000)
+        configure(&mut csr, 0, PMP_TOR | PMP_X, 0x1000 >> 2);
+        configure(&mut csr, 1, PMP_TOR | PMP_R | PMP_W, 0x3000 >> 2);
+
+        assert!(csr.pmp_permits(0x0, 2, AccessType::Instruction, U));
+        assert!(csr.pmp_permits(0xffc, 4, AccessType::Instruction, U));
+        assert!(!csr.pmp_permits(0xffc, 4, AccessType::Load, U));
+        assert!(csr.pmp_permits(0x1000, 8, AccessType::Store, S));
+        assert!(csr.pmp_permits(0x2ff8, 8, AccessType::Load, S));
+        assert!(!csr.pmp_permits(0x2ff8, 8, AccessType::Instruction, S));
+        assert!(!csr.pmp_permits(0x3000, 8, AccessType::Load, S));
+
+        // A top below the bottom matches nothing
+        configure(&mut csr, 1, PMP_TOR | PMP_R, 0x800 >> 2);
+        assert!(!csr.pmp_permits(0x1000, 8, AccessType::Load, S));
+    }
+
+    #[test]
+    fn test_na4() {
+        let mut csr = CsrFile::new(0);
+        configure(&mut csr, 0, PMP_NA4 | PMP_R, 0x2004 >> 2);
+
+        assert!(csr.pmp_permits(0x2004, 4, AccessType::Load, U));
+        assert!(csr.pmp_permits(0x2006, 2, AccessType::Load, U));
+        assert!(!csr.pmp_permits(0x2004, 4, AccessType::Store, U));
+        assert!(!csr.pmp_permits(0x2000, 4, AccessType::Load, U));
+        assert!(!csr.pmp_permits(0x2008, 4, AccessType::Load, U));
+    }
+
+    #[test]
+    fn test_napot() {
+        let mut csr = CsrFile::new(0);
+        // 8 bytes at 0x1000 and 64 KiB at 0x10000
+        configure(&mut csr, 0, PMP_NAPOT | PMP_R, 0x1000 >> 2);
+        configure(
+            &mut csr,
+            1,
+            PMP_NAPOT | PMP_R | PMP_X,
+            (0x10000 >> 2) | 0x1fff,
+        );
+
+        assert!(csr.pmp_permits(0x1000, 8, AccessType::Load, S));
+        assert!(!csr.pmp_permits(0x1008, 1, AccessType::Load, S));
+        assert!(csr.pmp_permits(0x10000, 8, AccessType::Load, S));
+        assert!(csr.pmp_permits(0x1fffe, 2, AccessType::Instruction, S));
+        assert!(!csr.pmp_permits(0x1fff8, 8, AccessType::Store, S));
+        assert!(!csr.pmp_permits(0x20000, 8, AccessType::Load, S));
+        assert!(!csr.pmp_permits(0xfff8, 8, AccessType::Load, S));
+
+        // All ones covers the whole physical address space
+        configure(&mut csr, 2, PMP_NAPOT | PMP_R | PMP_W, u64::MAX);
+        assert!(csr.pmp_permits(0xfff8, 8, AccessType::Store, U));
+        assert!(csr.pmp_permits(0xff_ffff_ffff_fff8, 8, AccessType::Store, U));
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut csr = CsrFile::new(0);
+        // A read-only page inside a read-write region
+        configure(&mut csr, 0, PMP_NAPOT | PMP_R, (0x2000 >> 2) | 0x1ff);
+        configure(&mut csr, 1, PMP_NAPOT | PMP_R | PMP_W, 0xfff);
+
+        assert!(csr.pmp_permits(0x1ff8, 8, AccessType::Store, U));
+        assert!(!csr.pmp_permits(0x2000, 8, AccessType::Store, U));
+        assert!(csr.pmp_permits(0x3000, 8, AccessType::Store, U));
+        // An access straddling the boundary only partly matches entry 0
+        assert!(!csr.pmp_permits(0x1ffc, 8, AccessType::Load, U));
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut csr = CsrFile::new(0);
+        configure(&mut csr, 0, PMP_NAPOT | PMP_R, 0x1000 >> 2);
+        // M-mode ignores the permissions of unlocked entries
+        assert!(csr.pmp_permits(0x1000, 8, AccessType::Store, M));
+
+        configure(&mut csr, 0, PMP_L | PMP_NAPOT | PMP_R, 0x1000 >> 2);
+        assert!(!csr.pmp_permits(0x1000, 8, AccessType::Store, M));
+        assert!(csr.pmp_permits(0x1000, 8, AccessType::Load, M));
+
+        // Neither the configuration nor the address can change until reset
+        configure(&mut csr, 0, PMP_NAPOT | PMP_R | PMP_W, 0x2000 >> 2);
+        assert_eq!(csr.pmpcfg(0), PMP_L | PMP_NAPOT | PMP_R);
+        assert_eq!(csr.get(PMPADDR0), 0x1000 >> 2);
+
+        // A locked TOR entry also locks the address of the entry below
+        configure(&mut csr, 2, 0, 0x3000 >> 2);
+        configure(&mut csr, 3, PMP_L | PMP_TOR | PMP_R, 0x4000 >> 2);
+        configure(&mut csr, 2, PMP_R, 0x5000 >> 2);
+        assert_eq!(csr.get(PMPADDR0 + 2), 0x3000 >> 2);
+        assert_eq!(csr.pmpcfg(2), PMP_R);
+        assert!(!csr.pmp_permits(0x3000, 8, AccessType::Store, M));
+    }
+
+    #[test]
+    fn test_warl() {
+        let mut csr = CsrFile::new(0);
+        // Write-only is reserved, and bits 6:5 are zero
+        csr.write(PMPCFG14, 0x7f02, M).unwrap();
+        assert_eq!(csr.get(PMPCFG14), 0x1f00);
+        assert_eq!(csr.pmpcfg(57), PMP_NAPOT | PMP_X | PMP_W | PMP_R);
+        // Only bits 55:2 of the address are held
+        csr.write(PMPADDR63, u64::MAX, M).unwrap();
+        assert_eq!(csr.get(PMPADDR63), (1 << 54) - 1);
+        // The odd pmpcfg registers do not exist on RV64
+        assert!(csr.write(PMPCFG0 + 1, 0, M).is_err());
+        assert!(csr.read(PMPCFG0, S).is_err());
+    }
+
+    #[test]
+    fn test_access_faults() {
+        let code = vec![
+            0x83, 0x35, 0x05, 0x00, // ld   a1,0(a0)
+            0x23, 0x30, 0xb5, 0x00, // sd   a1,0(a0)
+            0x67, 0x00, 0x05, 0x00, // jalr zero,0(a0)
+        ];
+        let mut emu = Emulator::new(vec![0; 0x4000]);
+        emu.bus.ram[..code.len()].copy_from_slice(&code);
+        // Code is executable, and data at 0x2000 only readable
+        configure(&mut emu.csr, 0, PMP_NAPOT | PMP_X, 0x1ff);
+        configure(&mut emu.csr, 1, PMP_NAPOT | PMP_R, (0x2000 >> 2) | 0x1ff);
+        emu.privilege = U;
+        emu.regs[10] = 0x2000;
+
+        emu.step().unwrap();
+        assert_eq!(emu.step(), Err(Exception::StoreAccessFault(0x2000)));
+        emu.pc = 8;
+        emu.step().unwrap();
+        assert_eq!(emu.step(), Err(Exception::InstructionAccessFault(0x2000)));
+        emu.regs[10] = 0x3000;
+        emu.pc = 0;
+        assert_eq!(emu.step(), Err(Exception::LoadAccessFault(0x3000)));
+
+        // MPRV makes M-mode data accesses subject to PMP as the MPP mode
+        emu.privilege = M;
+        emu.pc = 4;
+        emu.regs[10] = 0x2000;
+        let mstatus = emu.csr.get(crate::csr::MSTATUS);
+        emu.csr
+            .set(crate::csr::MSTATUS, mstatus | crate::csr::MSTATUS_MPRV);
+        assert_eq!(emu.step(), Err(Exception::StoreAccessFault(0x2000)));
+    }
+}
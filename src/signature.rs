@@ -0,0 +1,123 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use crate::emulator::Emulator;
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    /// The program does not define the named symbol
+    MissingSymbol(&'static str),
+    /// The signature does not lie within memory
+    InvalidRange(u64, u64),
+    /// The granularity is not a power of two
+    InvalidGranularity(usize),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::MissingSymbol(name) => write!(f, "no `{}` symbol", name),
+            SignatureError::InvalidRange(begin, end) => write!(
+                f,
+                "signature 0x{:08x}..0x{:08x} is outside memory",
+                begin, end
+            ),
+            SignatureError::InvalidGranularity(granularity) => write!(
+                f,
+                "signature granularity {} is not a power of two",
+                granularity
+            ),
+        }
+    }
+}
+
+impl Error for SignatureError {}
+
+impl Emulator {
+    /// The memory between the `begin_signature` and `end_signature` symbols
+    /// as written by riscv-arch-test's reference models: one line of hex
+    /// per `granularity` bytes, each holding a little-endian value. A
+    /// trailing partial line is padded with zeroes.
+    pub fn signature(&self, granularity: usize) -> Result<String, SignatureError> {
+        if !granularity.is_power_of_two() {
+            return Err(SignatureError::InvalidGranularity(granularity));
+        }
+        let symbol = |name| {
+            self.symbols
+                .get(name)
+                .map(|symbol| symbol.address)
+                .ok_or(SignatureError::MissingSymbol(name))
+        };
+        let (begin, end) = (symbol("begin_signature")?, symbol("end_signature")?);
+        let range = end
+            .checked_sub(begin)
+            .and_then(|size| usize::try_from(size).ok())
+            .and_then(|size| self.bus.ram_range(begin, size))
+            .ok_or(SignatureError::InvalidRange(begin, end))?;
+
+        let mut signature = String::new();
+        for chunk in self.bus.ram[range].chunks(granularity) {
+            for i in (0..granularity).rev() {
+                signature.push_str(&format!("{:02x}", chunk.get(i).unwrap_or(&0)));
+            }
+            signature.push('\n');
+        }
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::symbols::{Symbol, SymbolTable};
+
+    fn emulator(begin: u64, end: u64) -> Emulator {
+        let mut emu = Emulator::new((0..0x40).collect());
+        let symbol = |name: &str, address| Symbol {
+            name: name.to_string(),
+            address,
+            size: 0,
+        };
+        emu.symbols = SymbolTable::new(vec![
+            symbol("begin_signature", begin),
+            symbol("end_signature", end),
+        ]);
+        emu
+    }
+
+    #[test]
+    fn test_signature() {
+        let emu = emulator(0x10, 0x1a);
+        assert_eq!(emu.signature(4).unwrap(), "13121110\n17161514\n00001918\n");
+        assert_eq!(
+            emu.signature(8).unwrap(),
+            "1716151413121110\n0000000000001918\n"
+        );
+        assert_eq!(
+            emu.signature(1).unwrap().lines().collect::<Vec<_>>(),
+            ["10", "11", "12", "13", "14", "15", "16", "17", "18", "19"]
+        );
+        assert_eq!(emulator(0x10, 0x10).signature(4).unwrap(), "");
+    }
+
+    #[test]
+    fn test_signature_errors() {
+        assert_eq!(
+            Emulator::new(vec![0; 0x10]).signature(4),
+            Err(SignatureError::MissingSymbol("begin_signature"))
+        );
+        assert_eq!(
+            emulator(0x10, 0x80).signature(4),
+            Err(SignatureError::InvalidRange(0x10, 0x80))
+        );
+        assert_eq!(
+            emulator(0x20, 0x10).signature(4),
+            Err(SignatureError::InvalidRange(0x20, 0x10))
+        );
+        assert_eq!(
+            emulator(0x10, 0x20).signature(3),
+            Err(SignatureError::InvalidGranularity(3))
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    /// Size in bytes, or 0 for labels whose extent is unknown
+    pub size: u64,
+}
+
+/// Index from addresses to the functions and objects containing them
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    /// Sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        // Among symbols at the same address, sized ones (functions and
+        // objects) sort last so that lookups prefer them over bare labels
+        symbols.sort_by_key(|symbol| (symbol.address, symbol.size != 0));
+        Self { symbols }
+    }
+
+    /// The symbol containing `address` and the offset of `address` into
+    /// it. A symbol of size 0 is taken to extend up to the next symbol.
+    pub fn lookup(&self, address: u64) -> Option<(&Symbol, u64)> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address);
+        let symbol = &self.symbols[index.checked_sub(1)?];
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    /// Find a symbol by name
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// An address formatted as `0x...`, followed by `<function+offset>`
+    /// when a symbol contains it
+    pub fn describe(&self, address: u64) -> Description<'_> {
+        Description {
+            address,
+            symbol: self.lookup(address),
+        }
+    }
+}
+
+/// Display adapter returned by `SymbolTable::describe`
+pub struct Description<'a> {
+    address: u64,
+    symbol: Option<(&'a Symbol, u64)>,
+}
+
+impl fmt::Display for Description<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:08x}", self.address)?;
+        match self.symbol {
+            Some((symbol, 0)) => write!(f, " <{}>", symbol.name),
+            Some((symbol, offset)) => write!(f, " <{}+0x{:x}>", symbol.name, offset),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn symbol(name: &str, address: u64, size: u64) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            address,
+            size,
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let table = SymbolTable::new(vec![
+            symbol("main", 0x1020, 0x10),
+            symbol("_start", 0x1000, 0),
+            symbol("loop", 0x1028, 0),
+            symbol("data", 0x2000, 8),
+        ]);
+
+        assert_eq!(table.lookup(0xfff), None);
+        assert_eq!(table.lookup(0x1000).unwrap().0.name, "_start");
+        // _start extends up to main
+        assert_eq!(table.lookup(0x101e).unwrap().1, 0x1e);
+        assert_eq!(table.lookup(0x1024).unwrap().0.name, "main");
+        assert_eq!(table.lookup(0x102c).unwrap().0.name, "loop");
+        assert_eq!(table.lookup(0x2007).unwrap().1, 7);
+        assert_eq!(table.lookup(0x2008), None);
+        assert_eq!(table.get("data").unwrap().address, 0x2000);
+        assert_eq!(table.get("missing"), None);
+    }
+
+    #[test]
+    fn test_describe() {
+        let table = SymbolTable::new(vec![
+            symbol("label", 0x80000000, 0),
+            symbol("main", 0x80000000, 0x20),
+        ]);
+
+        // Sized symbols are preferred over labels at the same address
+        assert_eq!(table.describe(0x80000000).to_string(), "0x80000000 <main>");
+        assert_eq!(
+            table.describe(0x80000014).to_string(),
+            "0x80000014 <main+0x14>"
+        );
+        assert_eq!(table.describe(0x80000020).to_string(), "0x80000020");
+        assert_eq!(
+            SymbolTable::default().describe(0x10).to_string(),
+            "0x00000010"
+        );
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::emulator::Emulator;
+use crate::mmu::PAGE_SIZE;
+
+// Syscall numbers of the generic Linux ABI used by riscv64
+const SYS_GETCWD: u64 = 17;
+const SYS_IOCTL: u64 = 29;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_SIGALTSTACK: u64 = 132;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+
+// Legacy syscalls of riscv-pk, which take paths relative to the working
+// directory instead of a directory file descriptor
+const SYS_OPEN: u64 = 1024;
+const SYS_UNLINK: u64 = 1026;
+const SYS_ACCESS: u64 = 1033;
+const SYS_STAT: u64 = 1038;
+const SYS_LSTAT: u64 = 1039;
+
+/// Exit status of a pk program stopped by an unknown syscall
+pub(crate) const PK_PANIC_STATUS: i32 = 255;
+
+// errno values
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ERANGE: i64 = 34;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+// openat flags
+const O_ACCMODE: u64 = 0b11;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+// mmap flags
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const S_IFCHR: u32 = 0o020000;
+const RLIMIT_STACK: u64 = 3;
+const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Result of a syscall: the value for a0, or an errno returned negated
+type SyscallResult = Result<u64, i64>;
+
+/// A guest file descriptor
+enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+/// Syscall interface presented to the guest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Abi {
+    /// Linux user-mode processes
+    Linux,
+    /// The riscv-pk proxy kernel, as targeted by newlib's libgloss: the
+    /// Linux numbering plus legacy path-based calls. Unknown syscalls end
+    /// the program, as pk does.
+    Pk,
+}
+
+/// State of the host-side syscall layer, which services `ecall` in place
+/// of a guest kernel
+pub struct Syscalls {
+    abi: Abi,
+    files: BTreeMap<u64, HostFile>,
+    /// Initial and current program break
+    brk_start: u64,
+    brk: u64,
+    /// Lowest address handed out by mmap, which allocates downwards from
+    /// the bottom of the stack
+    mmap_bottom: u64,
+    /// Size reported for RLIMIT_STACK
+    stack_size: u64,
+    started: Instant,
+    exit_status: Option<i32>,
+}
+
+impl Syscalls {
+    pub fn new(abi: Abi, brk: u64, mmap_top: u64, stack_size: u64) -> Self {
+        let mut files = BTreeMap::new();
+        files.insert(0, HostFile::Stdin);
+        files.insert(1, HostFile::Stdout);
+        files.insert(2, HostFile::Stderr);
+        Self {
+            abi,
+            files,
+            brk_start: brk,
+            brk,
+            mmap_bottom: mmap_top,
+            stack_size,
+            started: Instant::now(),
+            exit_status: None,
+        }
+    }
+
+    /// Status passed to the exit syscall, once the program has made it
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Lowest unused file descriptor
+    fn allocate_fd(&self) -> u64 {
+        (0..).find(|fd| !self.files.contains_key(fd)).unwrap()
+    }
+
+    fn file(&mut self, fd: u64) -> Result<&mut HostFile, i64> {
+        self.files.get_mut(&fd).ok_or(EBADF)
+    }
+}
+
+/// errno of a failed host operation. The host is assumed to use the same
+/// errno numbering as riscv64 Linux.
+fn errno(error: io::Error) -> i64 {
+    error.raw_os_error().map_or(EIO, i64::from)
+}
+
+/// Fill `buf` from the host's random number source
+pub(crate) fn host_random(buf: &mut [u8]) -> io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// User and group IDs of the host process, taken from the owner of its
+/// /proc entry, or root if that is unavailable
+pub(crate) fn host_ids() -> (u64, u64) {
+    fs::metadata("/proc/self").map_or((0, 0), |metadata| {
+        (metadata.uid() as u64, metadata.gid() as u64)
+    })
+}
+
+/// Lay out a riscv64 `struct stat`
+fn stat_bytes(metadata: &Metadata) -> [u8; 128] {
+    let fields: [(usize, u64, usize); 16] = [
+        (0, metadata.dev(), 8),
+        (8, metadata.ino(), 8),
+        (16, metadata.mode() as u64, 4),
+        (20, metadata.nlink(), 4),
+        (24, metadata.uid() as u64, 4),
+        (28, metadata.gid() as u64, 4),
+        (32, metadata.rdev(), 8),
+        (48, metadata.size(), 8),
+        (56, metadata.blksize(), 4),
+        (64, metadata.blocks(), 8),
+        (72, metadata.atime() as u64, 8),
+        (80, metadata.atime_nsec() as u64, 8),
+        (88, metadata.mtime() as u64, 8),
+        (96, metadata.mtime_nsec() as u64, 8),
+        (104, metadata.ctime() as u64, 8),
+        (112, metadata.ctime_nsec() as u64, 8),
+    ];
+    let mut stat = [0; 128];
+    for (offset, value, size) in fields {
+        stat[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+    stat
+}
+
+/// `struct stat` for the standard streams: a character device, which is
+/// all programs look at to pick a buffering mode
+fn stream_stat_bytes() -> [u8; 128] {
+    let mut stat = [0; 128];
+    stat[16..20].copy_from_slice(&(S_IFCHR | 0o620).to_le_bytes());
+    stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+    stat[56..60].copy_from_slice(&1024u32.to_le_bytes());
+    stat
+}
+
+impl Emulator {
+    /// Exit status of a program that made an exit syscall, or that
+    /// reported an exit code through HTIF
+    pub fn exit_status(&self) -> Option<i32> {
+        match (&self.syscalls, &self.htif) {
+            (Some(syscalls), _) => syscalls.exit_status,
+            (None, Some(htif)) => htif.exit_code().map(|code| code as i32),
+            (None, None) => None,
+        }
+    }
+
+    /// Guest memory covering `size` bytes at `address`. Syscalls are only
+    /// serviced for programs running without address translation.
+    fn guest_bytes(&self, address: u64, size: u64) -> Result<&[u8], i64> {
+        let size = usize::try_from(size).map_err(|_| EFAULT)?;
+        let range = self.bus.ram_range(address, size).ok_or(EFAULT)?;
+        Ok(&self.bus.ram[range])
+    }
+
+    fn write_guest_bytes(&mut self, address: u64, bytes: &[u8]) -> Result<(), i64> {
+        let range = self.bus.ram_range(address, bytes.len()).ok_or(EFAULT)?;
+        // The host writes behind the hart's back, so drop any reservation
+        self.reservation = None;
+        self.bus.ram[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub(crate) fn write_guest_u64(&mut self, address: u64, value: u64) -> Result<(), i64> {
+        self.write_guest_bytes(address, &value.to_le_bytes())
+    }
+
+    /// A NUL-terminated string from guest memory
+    fn guest_string(&self, address: u64) -> Result<String, i64> {
+        let start = self.bus.ram_range(address, 0).ok_or(EFAULT)?.start;
+        let length = self.bus.ram[start..]
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or(EFAULT)?;
+        Ok(String::from_utf8_lossy(&self.bus.ram[start..start + length]).into_owned())
+    }
+
+    /// Service an `ecall`. Under both ABIs the number is in a7, the
+    /// arguments in a0-a5, and the result is returned in a0.
+    pub(crate) fn syscall(&mut self) {
+        let mut syscalls = self.syscalls.take().expect("syscalls are enabled");
+        let number = self.getreg(17);
+        let args = [
+            self.getreg(10),
+            self.getreg(11),
+            self.getreg(12),
+            self.getreg(13),
+            self.getreg(14),
+            self.getreg(15),
+        ];
+        let result = self.dispatch_syscall(&mut syscalls, number, args);
+        self.syscalls = Some(syscalls);
+        self.setreg(10, result.unwrap_or_else(|errno| (-errno) as u64));
+    }
+
+    pub(crate) fn dispatch_syscall(
+        &mut self,
+        syscalls: &mut Syscalls,
+        number: u64,
+        args: [u64; 6],
+    ) -> SyscallResult {
+        match number {
+            SYS_GETCWD => self.sys_getcwd(args[0], args[1]),
+            SYS_IOCTL => {
+                syscalls.file(args[0])?;
+                Err(ENOTTY)
+            }
+            SYS_FACCESSAT => {
+                let path = self.guest_string(args[1])?;
+                let path = self.resolve_path(args[0], path)?;
+                fs::metadata(path).map(|_| 0).map_err(errno)
+            }
+            SYS_OPENAT => self.sys_openat(syscalls, args[0], args[1], args[2], args[3]),
+            SYS_CLOSE => syscalls.files.remove(&args[0]).map(|_| 0).ok_or(EBADF),
+            SYS_LSEEK => {
+                let position = match args[2] {
+                    0 => SeekFrom::Start(args[1]),
+                    1 => SeekFrom::Current(args[1] as i64),
+                    2 => SeekFrom::End(args[1] as i64),
+                    _ => return Err(EINVAL),
+                };
+                match syscalls.file(args[0])? {
+                    HostFile::File(file) => file.seek(position).map_err(errno),
+                    _ => Err(ESPIPE),
+                }
+            }
+            SYS_READ => self.sys_read(syscalls, args[0], args[1], args[2]),
+            SYS_WRITE => self.sys_write(syscalls, args[0], args[1], args[2]),
+            SYS_READV | SYS_WRITEV => {
+                let mut total = 0;
+                for index in 0..args[2] {
+                    let iovec = args[1].wrapping_add(16 * index);
+                    let base = self.load_guest_u64(iovec)?;
+                    let length = self.load_guest_u64(iovec.wrapping_add(8))?;
+                    let count = if number == SYS_READV {
+                        self.sys_read(syscalls, args[0], base, length)?
+                    } else {
+                        self.sys_write(syscalls, args[0], base, length)?
+                    };
+                    total += count;
+                    if count < length {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_NEWFSTATAT => {
+                let path = self.guest_string(args[1])?;
+                if path.is_empty() && args[3] & AT_EMPTY_PATH != 0 {
+                    return self.sys_fstat(syscalls, args[0], args[2]);
+                }
+                let path = self.resolve_path(args[0], path)?;
+                self.sys_stat(path, args[2], args[3] & AT_SYMLINK_NOFOLLOW == 0)
+            }
+            SYS_FSTAT => self.sys_fstat(syscalls, args[0], args[1]),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                syscalls.exit_status = Some(args[0] as i32);
+                Ok(0)
+            }
+            SYS_GETPID | SYS_GETTID | SYS_SET_TID_ADDRESS => Ok(std::process::id() as u64),
+            SYS_GETPPID => Ok(std::os::unix::process::parent_id() as u64),
+            SYS_GETUID | SYS_GETEUID => Ok(host_ids().0),
+            SYS_GETGID | SYS_GETEGID => Ok(host_ids().1),
+            // Signals are never delivered, so handlers and masks are accepted
+            // and ignored, as are robust futex lists of the single thread
+            SYS_SET_ROBUST_LIST | SYS_SIGALTSTACK | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+            SYS_CLOCK_GETTIME => {
+                let (seconds, nanoseconds) = match args[0] {
+                    // CLOCK_REALTIME and CLOCK_REALTIME_COARSE
+                    0 | 5 => {
+                        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                        (now.as_secs(), now.subsec_nanos())
+                    }
+                    // The monotonic, boot-time and CPU-time clocks all count
+                    // from the start of the program
+                    1..=4 | 6 | 7 => {
+                        let elapsed = syscalls.started.elapsed();
+                        (elapsed.as_secs(), elapsed.subsec_nanos())
+                    }
+                    _ => return Err(EINVAL),
+                };
+                self.write_guest_u64(args[1], seconds)?;
+                self.write_guest_u64(args[1].wrapping_add(8), nanoseconds as u64)?;
+                Ok(0)
+            }
+            SYS_UNAME => {
+                // sysname, nodename, release, version, machine, domainname
+                let fields = ["Linux", "rvemu", "6.1.0", "#1", "riscv64", ""];
+                let mut utsname = [0; 6 * 65];
+                for (index, field) in fields.iter().enumerate() {
+                    utsname[65 * index..65 * index + field.len()].copy_from_slice(field.as_bytes());
+                }
+                self.write_guest_bytes(args[0], &utsname).map(|_| 0)
+            }
+            SYS_GETTIMEOFDAY => {
+                if args[0] != 0 {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    self.write_guest_u64(args[0], now.as_secs())?;
+                    self.write_guest_u64(args[0].wrapping_add(8), now.subsec_micros() as u64)?;
+                }
+                Ok(0)
+            }
+            SYS_BRK => {
+                let requested = args[0];
+                if requested >= syscalls.brk_start && requested <= syscalls.mmap_bottom {
+                    // Memory given back and then reclaimed reads as zero
+                    if requested > syscalls.brk {
+                        let length = requested - syscalls.brk;
+                        self.write_guest_bytes(syscalls.brk, &vec![0; length as usize])?;
+                    }
+                    syscalls.brk = requested;
+                }
+                Ok(syscalls.brk)
+            }
+            SYS_MMAP => self.sys_mmap(syscalls, args),
+            // Mappings are never reused, so unmapping only checks alignment
+            SYS_MUNMAP => {
+                if !args[0].is_multiple_of(PAGE_SIZE) {
+                    return Err(EINVAL);
+                }
+                Ok(0)
+            }
+            // All memory is readable, writable and executable
+            SYS_MPROTECT | SYS_MADVISE => Ok(0),
+            SYS_PRLIMIT64 => {
+                let old_limit = args[3];
+                if old_limit != 0 {
+                    let limit = if args[1] == RLIMIT_STACK {
+                        syscalls.stack_size
+                    } else {
+                        RLIM_INFINITY
+                    };
+                    self.write_guest_u64(old_limit, limit)?;
+                    self.write_guest_u64(old_limit.wrapping_add(8), RLIM_INFINITY)?;
+                }
+                Ok(0)
+            }
+            SYS_GETRANDOM => {
+                let mut bytes = vec![0; self.guest_bytes(args[0], args[1])?.len()];
+                host_random(&mut bytes).map_err(errno)?;
+                self.write_guest_bytes(args[0], &bytes)?;
+                Ok(args[1])
+            }
+            SYS_OPEN if syscalls.abi == Abi::Pk => {
+                self.sys_openat(syscalls, AT_FDCWD as u64, args[0], args[1], args[2])
+            }
+            SYS_UNLINK if syscalls.abi == Abi::Pk => fs::remove_file(self.guest_string(args[0])?)
+                .map(|_| 0)
+                .map_err(errno),
+            SYS_ACCESS if syscalls.abi == Abi::Pk => fs::metadata(self.guest_string(args[0])?)
+                .map(|_| 0)
+                .map_err(errno),
+            SYS_STAT | SYS_LSTAT if syscalls.abi == Abi::Pk => {
+                let path = self.guest_string(args[0])?;
+                self.sys_stat(path, args[1], number == SYS_STAT)
+            }
+            _ if syscalls.abi == Abi::Pk => {
+                eprintln!("bad syscall #{}!", number);
+                syscalls.exit_status = Some(PK_PANIC_STATUS);
+                Ok(0)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn load_guest_u64(&self, address: u64) -> Result<u64, i64> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.guest_bytes(address, 8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    /// Resolve a path argument of an *at syscall. Only AT_FDCWD is
+    /// supported as the directory of relative paths.
+    fn resolve_path(&self, dirfd: u64, path: String) -> Result<String, i64> {
+        if path.starts_with('/') || dirfd as i64 == AT_FDCWD {
+            Ok(path)
+        } else {
+            Err(ENOSYS)
+        }
+    }
+
+    fn sys_getcwd(&mut self, buf: u64, size: u64) -> SyscallResult {
+        let cwd = env::current_dir().map_err(errno)?;
+        let mut bytes = cwd.to_string_lossy().into_owned().into_bytes();
+        bytes.push(0);
+        if bytes.len() as u64 > size {
+            return Err(ERANGE);
+        }
+        self.write_guest_bytes(buf, &bytes)?;
+        Ok(bytes.len() as u64)
+    }
+
+    fn sys_openat(
+        &mut self,
+        syscalls: &mut Syscalls,
+        dirfd: u64,
+        path: u64,
+        flags: u64,
+        mode: u64,
+    ) -> SyscallResult {
+        let path = self.resolve_path(dirfd, self.guest_string(path)?)?;
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .create(flags & O_CREAT != 0)
+            .create_new(flags & O_CREAT != 0 && flags & O_EXCL != 0)
+            .mode(mode as u32);
+        let file = options.open(path).map_err(errno)?;
+        let fd = syscalls.allocate_fd();
+        syscalls.files.insert(fd, HostFile::File(file));
+        Ok(fd)
+    }
+
+    fn sys_read(
+        &mut self,
+        syscalls: &mut Syscalls,
+        fd: u64,
+        buf: u64,
+        count: u64,
+    ) -> SyscallResult {
+        let mut bytes = vec![0; self.guest_bytes(buf, count)?.len()];
+        let read = match syscalls.file(fd)? {
+            HostFile::Stdin => io::stdin().read(&mut bytes),
+            HostFile::File(file) => file.read(&mut bytes),
+            HostFile::Stdout | HostFile::Stderr => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        self.write_guest_bytes(buf, &bytes[..read])?;
+        Ok(read as u64)
+    }
+
+    fn sys_write(
+        &mut self,
+        syscalls: &mut Syscalls,
+        fd: u64,
+        buf: u64,
+        count: u64,
+    ) -> SyscallResult {
+        let bytes = self.guest_bytes(buf, count)?;
+        let written = match syscalls.file(fd)? {
+            HostFile::Stdout => {
+                let mut stdout = io::stdout();
+                stdout.write(bytes).and_then(|written| {
+                    stdout.flush()?;
+                    Ok(written)
+                })
+            }
+            HostFile::Stderr => io::stderr().write(bytes),
+            HostFile::File(file) => file.write(bytes),
+            HostFile::Stdin => return Err(EBADF),
+        }
+        .map_err(errno)?;
+        Ok(written as u64)
+    }
+
+    /// stat or lstat, depending on whether symbolic links are followed
+    fn sys_stat(&mut self, path: String, buf: u64, follow: bool) -> SyscallResult {
+        let metadata = if follow {
+            fs::metadata(path)
+        } else {
+            fs::symlink_metadata(path)
+        };
+        let stat = stat_bytes(&metadata.map_err(errno)?);
+        self.write_guest_bytes(buf, &stat).map(|_| 0)
+    }
+
+    fn sys_fstat(&mut self, syscalls: &mut Syscalls, fd: u64, buf: u64) -> SyscallResult {
+        let stat = match syscalls.file(fd)? {
+            HostFile::File(file) => stat_bytes(&file.metadata().map_err(errno)?),
+            _ => stream_stat_bytes(),
+        };
+        self.write_guest_bytes(buf, &stat).map(|_| 0)
+    }
+
+    fn sys_mmap(&mut self, syscalls: &mut Syscalls, args: [u64; 6]) -> SyscallResult {
+        let [address, length, _prot, flags, fd, offset] = args;
+        if length == 0 {
+            return Err(EINVAL);
+        }
+        let length = length.checked_next_multiple_of(PAGE_SIZE).ok_or(ENOMEM)?;
+        let address = if flags & MAP_FIXED != 0 {
+            if !address.is_multiple_of(PAGE_SIZE) {
+                return Err(EINVAL);
+            }
+            address
+        } else {
+            let bottom = syscalls.mmap_bottom.checked_sub(length).ok_or(ENOMEM)?;
+            if bottom < syscalls.brk {
+                return Err(ENOMEM);
+            }
+            syscalls.mmap_bottom = bottom;
+            bottom
+        };
+
+        let mut contents = vec![0; self.guest_bytes(address, length).map_err(|_| ENOMEM)?.len()];
+        if flags & MAP_ANONYMOUS == 0 {
+            match syscalls.file(fd)? {
+                HostFile::File(file) => {
+                    let mut filled = 0;
+                    while filled < contents.len() {
+                        let read = file
+                            .read_at(&mut contents[filled..], offset + filled as u64)
+                            .map_err(errno)?;
+                        if read == 0 {
+                            break;
+                        }
+                        filled += read;
+                    }
+                }
+                _ => return Err(EBADF),
+            }
+        }
+        self.write_guest_bytes(address, &contents)?;
+        Ok(address)
+    }
+}
@@ -0,0 +1,63 @@
+/// Synchronous exceptions, each carrying the value written to mtval or stval
+#[derive(Debug, PartialEq)]
+pub enum Exception {
+    /// Faulting fetch address
+    InstructionAccessFault(u64),
+    /// Instruction bits (zero-extended for compressed encodings)
+    IllegalInstruction(u64),
+    /// Address of the breakpoint instruction
+    Breakpoint(u64),
+    /// Faulting load address
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    /// Faulting store or AMO address
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    /// Faulting virtual address
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+}
+
+impl Exception {
+    /// Exception code written to mcause or scause
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    /// Trap value written to mtval or stval
+    pub fn value(&self) -> u64 {
+        match self {
+            Exception::InstructionAccessFault(value)
+            | Exception::IllegalInstruction(value)
+            | Exception::Breakpoint(value)
+            | Exception::LoadAddressMisaligned(value)
+            | Exception::LoadAccessFault(value)
+            | Exception::StoreAddressMisaligned(value)
+            | Exception::StoreAccessFault(value)
+            | Exception::InstructionPageFault(value)
+            | Exception::LoadPageFault(value)
+            | Exception::StorePageFault(value) => *value,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+}
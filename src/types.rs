@@ -19,6 +19,57 @@ impl From<u32> for Rtype {
     }
 }
 
+/// Atomic (AMO) format: an R-type whose funct7 carries funct5 and the
+/// acquire/release ordering bits.
+#[derive(Debug, PartialEq)]
+pub struct Atype {
+    pub rd: usize,
+    pub funct3: u32,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub rl: bool,
+    pub aq: bool,
+    pub funct5: u32,
+}
+
+impl From<u32> for Atype {
+    fn from(inst: u32) -> Self {
+        Self {
+            rd: ((inst >> 7) & 0x1f) as usize,
+            funct3: ((inst >> 12) & 0x7),
+            rs1: ((inst >> 15) & 0x1f) as usize,
+            rs2: ((inst >> 20) & 0x1f) as usize,
+            rl: (inst >> 25) & 0x1 == 1,
+            aq: (inst >> 26) & 0x1 == 1,
+            funct5: ((inst >> 27) & 0x1f),
+        }
+    }
+}
+
+/// Fused multiply-add format with a third source register.
+#[derive(Debug, PartialEq)]
+pub struct R4type {
+    pub rd: usize,
+    pub funct3: u32,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub funct2: u32,
+    pub rs3: usize,
+}
+
+impl From<u32> for R4type {
+    fn from(inst: u32) -> Self {
+        Self {
+            rd: ((inst >> 7) & 0x1f) as usize,
+            funct3: ((inst >> 12) & 0x7),
+            rs1: ((inst >> 15) & 0x1f) as usize,
+            rs2: ((inst >> 20) & 0x1f) as usize,
+            funct2: ((inst >> 25) & 0x3),
+            rs3: ((inst >> 27) & 0x1f) as usize,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Itype {
     pub rd: usize,
@@ -86,10 +137,10 @@ impl From<u32> for Btype {
             funct3: ((inst >> 12) & 0x7),
             rs1: ((inst >> 15) & 0x1f) as usize,
             rs2: ((inst >> 20) & 0x1f) as usize,
-            imm: (((inst & 0x80000000) >> 19)
-                | ((inst & 0x7e000000) >> 20)
-                | ((inst & 0x00000f00) >> 7)
-                | ((inst & 0x00000080) << 4)) as i32,
+            imm: ((inst & 0x80000000) as i32 >> 19)
+                | ((inst & 0x7e000000) >> 20) as i32
+                | ((inst & 0x00000f00) >> 7) as i32
+                | ((inst & 0x00000080) << 4) as i32,
         }
     }
 }
@@ -104,10 +155,10 @@ impl From<u32> for Jtype {
     fn from(inst: u32) -> Self {
         Self {
             rd: ((inst >> 7) & 0x1f) as usize,
-            imm: (((inst & 0x80000000) >> 11)
-                | ((inst & 0x7fe00000) >> 20)
-                | ((inst & 0x00100000) >> 9)
-                | (inst & 0x000ff000)) as i32,
+            imm: ((inst & 0x80000000) as i32 >> 11)
+                | ((inst & 0x7fe00000) >> 20) as i32
+                | ((inst & 0x00100000) >> 9) as i32
+                | (inst & 0x000ff000) as i32,
         }
     }
 }
@@ -139,4 +190,56 @@ mod test {
         assert_eq!(inst.funct3, 0b010); // width
         assert_eq!(inst.imm, 2047); // offset
     }
+
+    #[test]
+    fn test_decode_btype() {
+        let inst = Btype::from(0xfe051ee3); // bne	a0,zero,-4
+        assert_eq!(inst.rs1, 10);
+        assert_eq!(inst.rs2, 0);
+        assert_eq!(inst.funct3, 0b001);
+        assert_eq!(inst.imm, -4);
+
+        let inst = Btype::from(0x7eb50fe3); // beq	a0,a1,4094
+        assert_eq!(inst.imm, 4094);
+    }
+
+    #[test]
+    fn test_decode_jtype() {
+        let inst = Jtype::from(0x801ff0ef); // jal	ra,-2048
+        assert_eq!(inst.rd, 1);
+        assert_eq!(inst.imm, -2048);
+
+        let inst = Jtype::from(0x7ffff06f); // jal	zero,1048574
+        assert_eq!(inst.rd, 0);
+        assert_eq!(inst.imm, 1048574);
+    }
+
+    #[test]
+    fn test_decode_atype() {
+        let inst = Atype::from(0x1405b52f); // lr.d.aq a0,(a1)
+        assert_eq!(inst.rd, 10);
+        assert_eq!(inst.rs1, 11);
+        assert_eq!(inst.rs2, 0);
+        assert_eq!(inst.funct3, 0b011);
+        assert_eq!(inst.funct5, 0b00010);
+        assert!(inst.aq);
+        assert!(!inst.rl);
+
+        let inst = Atype::from(0x1ad5b62f); // sc.d.rl a2,a3,(a1)
+        assert_eq!(inst.rs2, 13);
+        assert_eq!(inst.funct5, 0b00011);
+        assert!(!inst.aq);
+        assert!(inst.rl);
+    }
+
+    #[test]
+    fn test_decode_r4type() {
+        let inst = R4type::from(0x6ac5f543); // fmadd.d fa0,fa1,fa2,fa3
+        assert_eq!(inst.rd, 10);
+        assert_eq!(inst.rs1, 11);
+        assert_eq!(inst.rs2, 12);
+        assert_eq!(inst.rs3, 13);
+        assert_eq!(inst.funct2, 0b01); // fmt
+        assert_eq!(inst.funct3, 0b111); // rm
+    }
 }
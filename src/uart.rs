@@ -0,0 +1,466 @@
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::bus::Device;
+use crate::fdt::{Fdt, PLIC_PHANDLE};
+
+/// Address, size and interrupt source of the UART on QEMU's `virt` board
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+pub const UART_IRQ: u32 = 10;
+
+// Register offsets. RBR, THR and IER are replaced by the divisor latch
+// (DLL and DLM) while LCR.DLAB is set.
+const RBR: u64 = 0;
+const THR: u64 = 0;
+const IER: u64 = 1;
+const IIR: u64 = 2;
+const FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+const IER_MASK: u8 = 0x0f;
+
+// Interrupt identifications reported in IIR, highest priority first
+const IIR_RDI: u8 = 0x04;
+const IIR_THRI: u8 = 0x02;
+const IIR_NO_INT: u8 = 0x01;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE_FIFO: u8 = 1 << 0;
+const FCR_CLEAR_RCVR: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_LOOP: u8 = 1 << 4;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// Frequency of the clock the baud rate divisor divides, as on QEMU
+const CLOCK_FREQUENCY: u32 = 3_686_400;
+
+/// Carrier detect, data set ready and clear to send: a connected terminal
+const MSR_CONNECTED: u8 = 0xb0;
+
+const FIFO_SIZE: usize = 16;
+
+/// NS16550A UART. Transmitted bytes are written to the host immediately,
+/// so the transmitter is always empty; received bytes come from a channel
+/// fed by the host, typically from stdin.
+pub struct Uart {
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+    receiver: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    /// THR empty interrupt, raised when the transmitter empties or the
+    /// interrupt is enabled, and cleared by reading IIR or writing THR
+    thre_interrupt: bool,
+}
+
+impl Uart {
+    pub fn new(input: Receiver<u8>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            receiver: VecDeque::new(),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_interrupt: false,
+        }
+    }
+
+    /// A UART connected to the host's stdin and stdout. Stdin is read on a
+    /// separate thread so that the guest never blocks waiting for input.
+    pub fn stdio() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || forward_stdin(&sender));
+        Self::new(receiver, Box::new(io::stdout()))
+    }
+
+    /// Move bytes the host has sent into the receive FIFO, as far as there
+    /// is room
+    fn receive(&mut self) {
+        while self.receiver.len() < FIFO_SIZE {
+            match self.input.try_recv() {
+                Ok(byte) => self.receiver.push_back(byte),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            if self.receiver.len() < FIFO_SIZE {
+                self.receiver.push_back(byte);
+            }
+        } else {
+            let _ = self.output.write_all(&[byte]);
+            let _ = self.output.flush();
+        }
+        self.thre_interrupt = true;
+    }
+
+    /// The highest-priority pending interrupt
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RDI != 0 && !self.receiver.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thre_interrupt {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+        if size != 1 {
+            return None;
+        }
+        self.receive();
+        let value = match offset {
+            RBR if self.dlab() => self.dll,
+            RBR => self.receiver.pop_front().unwrap_or(0),
+            IER if self.dlab() => self.dlm,
+            IER => self.ier,
+            IIR => {
+                let id = self.interrupt_id();
+                if id == IIR_THRI {
+                    self.thre_interrupt = false;
+                }
+                let fifo = if self.fcr & FCR_ENABLE_FIFO != 0 {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let ready = if self.receiver.is_empty() { 0 } else { LSR_DR };
+                ready | LSR_THRE | LSR_TEMT
+            }
+            MSR if self.mcr & MCR_LOOP != 0 => 0,
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => 0,
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if size != 1 {
+            return None;
+        }
+        let value = value as u8;
+        match offset {
+            THR if self.dlab() => self.dll = value,
+            THR => self.transmit(value),
+            IER if self.dlab() => self.dlm = value,
+            IER => {
+                // Enabling the THR empty interrupt raises it at once, since
+                // the transmitter is always empty
+                if value & IER_THRI != 0 && self.ier & IER_THRI == 0 {
+                    self.thre_interrupt = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            FCR => {
+                if value & FCR_CLEAR_RCVR != 0 {
+                    self.receiver.clear();
+                }
+                self.fcr = value;
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & MCR_MASK,
+            SCR => self.scr = value,
+            // LSR and MSR are read-only
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn tick(&mut self) {
+        self.receive();
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INT
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, irq: Option<u32>) {
+        fdt.begin_node(&format!("serial@{:x}", base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_u64s("reg", &[base, size]);
+        fdt.property_u32("clock-frequency", CLOCK_FREQUENCY);
+        if let Some(irq) = irq {
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.property_u32("interrupts", irq);
+        }
+        fdt.set_stdout();
+        fdt.end_node();
+    }
+}
+
+/// Send what `input` reads to `sender` until either ends, returning
+/// whether the receiving end is still there
+pub(crate) fn forward(mut input: impl Read, sender: &Sender<u8>) -> bool {
+    let mut buffer = [0; 64];
+    while let Ok(count @ 1..) = input.read(&mut buffer) {
+        if buffer[..count]
+            .iter()
+            .any(|&byte| sender.send(byte).is_err())
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Send what stdin reads to `sender`, as `forward` does, quitting the
+/// emulator when the escape sequence is typed on a raw terminal
+pub(crate) fn forward_stdin(sender: &Sender<u8>) -> bool {
+    forward(
+        Escape {
+            input: io::stdin(),
+            escaped: false,
+        },
+        sender,
+    )
+}
+
+/// Ctrl-A, which followed by `x` quits the emulator while the terminal is
+/// raw, as in QEMU. Typed twice it sends a single Ctrl-A to the guest.
+const ESCAPE: u8 = 0x01;
+
+/// Terminal settings to restore, as printed by `stty -g`, while a
+/// `RawTerminal` is enabled
+static SAVED_TERMINAL: Mutex<Option<String>> = Mutex::new(None);
+
+/// Input with the escape sequence taken out of it
+struct Escape<R> {
+    input: R,
+    /// Whether the last byte read was an escape
+    escaped: bool,
+}
+
+impl<R: Read> Read for Escape<R> {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = self.input.read(bytes)?;
+            if count == 0 {
+                return Ok(0);
+            }
+            let raw = SAVED_TERMINAL.lock().unwrap().is_some();
+            let mut kept = 0;
+            for i in 0..count {
+                let byte = bytes[i];
+                if self.escaped {
+                    self.escaped = false;
+                    match byte {
+                        b'x' => {
+                            RawTerminal::restore();
+                            eprintln!("rvemu: terminated");
+                            process::exit(0);
+                        }
+                        ESCAPE => {}
+                        // Other commands do nothing
+                        _ => continue,
+                    }
+                } else if byte == ESCAPE && raw {
+                    self.escaped = true;
+                    continue;
+                }
+                bytes[kept] = byte;
+                kept += 1;
+            }
+            // Reading nothing would look like the end of the input
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+/// The host terminal switched to raw mode, as a serial console expects,
+/// until dropped. Signal keys such as Ctrl-C go to the guest like any
+/// other; Ctrl-A x quits the emulator, restoring the terminal first.
+pub struct RawTerminal(());
+
+impl RawTerminal {
+    /// Switch stdin to raw mode, if it is a terminal
+    pub fn enable() -> Option<Self> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let saved = String::from_utf8(saved.stdout).ok()?.trim().to_string();
+        *SAVED_TERMINAL.lock().unwrap() = Some(saved);
+        stty(&["raw", "-echo", "-isig"]);
+        Some(Self(()))
+    }
+
+    /// Put back the saved settings, if the terminal is still raw
+    fn restore() {
+        if let Some(saved) = SAVED_TERMINAL.lock().unwrap().take() {
+            stty(&[&saved]);
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        Self::restore();
+    }
+}
+
+fn stty(args: &[&str]) {
+    let _ = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .status();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::Buffer;
+    use std::sync::mpsc::Sender;
+
+    fn uart() -> (Uart, Sender<u8>, Buffer) {
+        let (sender, receiver) = mpsc::channel();
+        let output = Buffer::default();
+        (
+            Uart::new(receiver, Box::new(output.clone())),
+            sender,
+            output,
+        )
+    }
+
+    #[test]
+    fn test_escape() {
+        let read = |bytes: &[u8]| {
+            let mut escape = Escape {
+                input: bytes,
+                escaped: false,
+            };
+            let mut output = Vec::new();
+            escape.read_to_end(&mut output).unwrap();
+            output
+        };
+        // Escapes only mean something while the terminal is raw
+        assert_eq!(read(b"\x01x"), b"\x01x");
+        *SAVED_TERMINAL.lock().unwrap() = Some(String::new());
+        assert_eq!(read(b"a\x01\x01b\x01cd"), b"a\x01bd");
+        *SAVED_TERMINAL.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_transmit_receive() {
+        let (mut uart, input, output) = uart();
+        assert_eq!(uart.read(LSR, 1), Some(0x60));
+        uart.write(THR, 1, b'h' as u64);
+        uart.write(THR, 1, b'i' as u64);
+        assert_eq!(*output.0.borrow(), b"hi");
+
+        input.send(b'a').unwrap();
+        input.send(b'b').unwrap();
+        assert_eq!(uart.read(LSR, 1), Some(0x61));
+        assert_eq!(uart.read(RBR, 1), Some(b'a' as u64));
+        assert_eq!(uart.read(RBR, 1), Some(b'b' as u64));
+        assert_eq!(uart.read(LSR, 1), Some(0x60));
+        // Only byte accesses are supported
+        assert_eq!(uart.read(RBR, 4), None);
+    }
+
+    #[test]
+    fn test_registers() {
+        let (mut uart, input, output) = uart();
+        // The divisor latch replaces RBR/THR and IER while DLAB is set
+        uart.write(LCR, 1, 0x83);
+        uart.write(THR, 1, 0x01);
+        uart.write(IER, 1, 0x02);
+        assert_eq!(uart.read(RBR, 1), Some(0x01));
+        assert_eq!(uart.read(IER, 1), Some(0x02));
+        uart.write(LCR, 1, 0x03);
+        assert_eq!(uart.read(IER, 1), Some(0));
+        assert!(output.0.borrow().is_empty());
+
+        uart.write(SCR, 1, 0x5a);
+        assert_eq!(uart.read(SCR, 1), Some(0x5a));
+        assert_eq!(uart.read(MSR, 1), Some(0xb0));
+
+        // FIFO control is reflected in IIR, and clears the receiver
+        input.send(b'x').unwrap();
+        uart.tick();
+        uart.write(FCR, 1, 0x07);
+        assert_eq!(uart.read(IIR, 1), Some(0xc1));
+        assert_eq!(uart.read(LSR, 1), Some(0x60));
+
+        // Loopback sends transmitted bytes to the receiver
+        uart.write(MCR, 1, 0x10);
+        uart.write(THR, 1, b'l' as u64);
+        assert_eq!(uart.read(RBR, 1), Some(b'l' as u64));
+        assert!(output.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let (mut uart, input, _) = uart();
+        assert!(!uart.interrupt());
+
+        // Received data interrupts until the FIFO is drained
+        uart.write(IER, 1, IER_RDI as u64);
+        input.send(b'x').unwrap();
+        assert!(!uart.interrupt());
+        uart.tick();
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(IIR, 1), Some(0x04));
+        uart.read(RBR, 1);
+        assert!(!uart.interrupt());
+
+        // THR empty interrupts on enabling and after each byte, and is
+        // acknowledged by reading IIR
+        uart.write(IER, 1, (IER_RDI | IER_THRI) as u64);
+        assert!(uart.interrupt());
+        assert_eq!(uart.read(IIR, 1), Some(0x02));
+        assert!(!uart.interrupt());
+        uart.write(THR, 1, b'y' as u64);
+        assert!(uart.interrupt());
+        uart.write(IER, 1, 0);
+        assert!(!uart.interrupt());
+    }
+}
@@ -0,0 +1,779 @@
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod rng;
+
+use crate::bus::{Device, Dma};
+use crate::fdt::{Fdt, PLIC_PHANDLE};
+
+/// Address of the first of the virtio-mmio slots on QEMU's `virt` board,
+/// each `VIRTIO_SIZE` bytes apart and wired to interrupt source
+/// `VIRTIO_IRQ` plus the slot's index
+pub const VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRTIO_SIZE: u64 = 0x1000;
+pub const VIRTIO_IRQ: u32 = 1;
+pub const VIRTIO_SLOTS: usize = 8;
+
+/// Address and interrupt source of a virtio-mmio slot
+pub fn slot(index: usize) -> (u64, u32) {
+    (
+        VIRTIO_BASE + VIRTIO_SIZE * index as u64,
+        VIRTIO_IRQ + index as u32,
+    )
+}
+
+// Register offsets of the version 2 (non-legacy) transport
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+/// "virt" in little-endian
+const MAGIC: u32 = 0x7472_6976;
+/// "QEMU", the vendor QEMU reports, which some drivers check for
+const VENDOR: u32 = 0x554d_4551;
+
+/// Feature bit every non-legacy device offers
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+
+/// Largest queue the transport offers
+const QUEUE_SIZE_MAX: u16 = 256;
+
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+const DESC_F_INDIRECT: u16 = 1 << 2;
+
+/// Size in bytes of a descriptor table entry
+const DESC_SIZE: u64 = 16;
+
+/// Most entries of an indirect descriptor table the device walks
+const INDIRECT_SIZE_MAX: u64 = 1024;
+
+/// Set in the available ring's flags by drivers that do not want an
+/// interrupt when buffers are used
+const AVAIL_F_NO_INTERRUPT: u64 = 1 << 0;
+
+/// The device-type specific half of a virtio device: what it offers and
+/// how it serves the buffers on its queues. `VirtioMmio` handles the rest.
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+
+    /// Device-specific feature bits
+    fn features(&self) -> u64;
+
+    fn queue_count(&self) -> usize;
+
+    /// The device configuration space
+    fn config(&self) -> Vec<u8>;
+
+    /// Return to the initial state, when the driver resets the device
+    fn reset(&mut self) {}
+
+    /// Serve the buffers the driver has made available on queue `index`,
+    /// after the driver notified the device of them
+    fn notify(&mut self, index: usize, queue: &mut Queue, memory: &mut Dma);
+
+    /// Serve the queues without a notification, once per cycle while the
+    /// driver is running, for devices with input arriving from the host
+    fn poll(&mut self, _queues: &mut [Queue], _memory: &mut Dma) {}
+}
+
+/// A split virtqueue, as set up by the driver
+#[derive(Default)]
+pub struct Queue {
+    size: u16,
+    ready: bool,
+    /// Physical addresses of the descriptor table and the available and
+    /// used rings
+    desc: u64,
+    driver: u64,
+    device: u64,
+    /// Index in the available ring of the next chain to serve, and in the
+    /// used ring of the next entry to fill
+    last_avail: u16,
+    used: u16,
+    /// Whether the driver has notified the device of the queue since the
+    /// device last served it
+    notified: bool,
+    /// Whether buffers were used since the driver was last interrupted,
+    /// and it asked to be
+    interrupt: bool,
+}
+
+/// A descriptor chain: the buffers of a request, those the device reads
+/// from and then those it writes to, as address and length pairs
+pub struct Chain {
+    head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+}
+
+impl Queue {
+    /// The next chain the driver has made available, if any. A chain
+    /// referring to descriptors or buffers outside the table or memory, or
+    /// breaking the rules on their number and nesting, is cut short at
+    /// that point.
+    pub fn pop(&mut self, memory: &Dma) -> Option<Chain> {
+        if !self.ready || self.size == 0 {
+            return None;
+        }
+        let avail = memory.read(self.driver + 2, 2)? as u16;
+        if avail == self.last_avail {
+            return None;
+        }
+        let slot = (self.last_avail % self.size) as u64;
+        let head = memory.read(self.driver + 4 + 2 * slot, 2)? as u16;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Chain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let (mut table, mut size) = (self.desc, self.size as u64);
+        let mut index = head as u64;
+        let mut indirect = false;
+        // Chains may not be longer than the queue, or an indirect chain its
+        // table, which also bounds the walk if the driver made a loop
+        let mut remaining = size;
+        // Nor may their buffers add up to more than memory, so that what
+        // the device allocates for them stays within reason
+        let (mut readable, mut writable) = (0, 0);
+        while index < size && remaining > 0 {
+            let desc = table + DESC_SIZE * index;
+            let (address, length, flags, next) = match (
+                memory.read(desc, 8),
+                memory.read(desc + 8, 4),
+                memory.read(desc + 12, 2),
+                memory.read(desc + 14, 2),
+            ) {
+                (Some(address), Some(length), Some(flags), Some(next)) => {
+                    (address, length as u32, flags as u16, next)
+                }
+                _ => break,
+            };
+            let first = !indirect && remaining == size;
+            remaining -= 1;
+            if flags & DESC_F_INDIRECT != 0 {
+                // Only the first descriptor of a chain may refer to a table
+                // of its own, which then holds the whole chain
+                if !first || flags & DESC_F_NEXT != 0 {
+                    break;
+                }
+                indirect = true;
+                table = address;
+                size = (length as u64 / DESC_SIZE).min(INDIRECT_SIZE_MAX);
+                index = 0;
+                remaining = size;
+                continue;
+            }
+            if !memory.contains(address, length as usize) {
+                break;
+            }
+            let total = if flags & DESC_F_WRITE != 0 {
+                &mut writable
+            } else {
+                &mut readable
+            };
+            *total += length as usize;
+            if *total > memory.size() {
+                break;
+            }
+            if flags & DESC_F_WRITE != 0 {
+                chain.writable.push((address, length));
+            } else {
+                chain.readable.push((address, length));
+            }
+            if flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Some(chain)
+    }
+
+    /// Return a chain to the driver, with `length` bytes written to it
+    pub fn push(&mut self, memory: &mut Dma, chain: Chain, length: u32) {
+        let slot = (self.used % self.size) as u64;
+        let entry = self.device + 4 + 8 * slot;
+        memory.write(entry, 4, chain.head as u64);
+        memory.write(entry + 4, 4, length as u64);
+        self.used = self.used.wrapping_add(1);
+        memory.write(self.device + 2, 2, self.used as u64);
+        let flags = memory.read(self.driver, 2).unwrap_or(0);
+        if flags & AVAIL_F_NO_INTERRUPT == 0 {
+            self.interrupt = true;
+        }
+    }
+}
+
+impl Chain {
+    /// All the bytes of the readable buffers, concatenated
+    pub fn read(&self, memory: &Dma) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for &(address, length) in &self.readable {
+            let start = bytes.len();
+            bytes.resize(start + length as usize, 0);
+            if memory.read_bytes(address, &mut bytes[start..]).is_none() {
+                bytes.truncate(start);
+                break;
+            }
+        }
+        bytes
+    }
+
+    /// Total size of the writable buffers
+    pub fn writable_len(&self) -> usize {
+        self.writable
+            .iter()
+            .map(|&(_, length)| length as usize)
+            .sum()
+    }
+
+    /// Write `bytes` across the writable buffers, as far as they reach,
+    /// returning how many were written
+    pub fn write(&self, memory: &mut Dma, bytes: &[u8]) -> usize {
+        let mut written = 0;
+        for &(address, length) in &self.writable {
+            let count = (length as usize).min(bytes.len() - written);
+            if memory
+                .write_bytes(address, &bytes[written..written + count])
+                .is_none()
+            {
+                break;
+            }
+            written += count;
+        }
+        written
+    }
+}
+
+/// Virtio-mmio transport, version 2, giving a virtio device its registers
+/// on the bus
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Queue>,
+    interrupt_status: u32,
+    status: u32,
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let queues = (0..device.queue_count())
+            .map(|_| Queue::default())
+            .collect();
+        Self {
+            device,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            interrupt_status: 0,
+            status: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues
+            .iter_mut()
+            .for_each(|queue| *queue = Queue::default());
+        self.interrupt_status = 0;
+        self.status = 0;
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Select the 32-bit half of a 64-bit value, by a selector register
+    fn half(value: u64, select: u32) -> u32 {
+        match select {
+            0 => value as u32,
+            1 => (value >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    fn set_half(value: &mut u64, high: bool, half: u32) {
+        if high {
+            *value = *value & 0xffff_ffff | (half as u64) << 32;
+        } else {
+            *value = *value & !0xffff_ffff | half as u64;
+        }
+    }
+}
+
+impl Device for VirtioMmio {
+    fn read(&mut self, offset: u64, size: usize) -> Option<u64> {
+        if offset >= CONFIG {
+            let config = self.device.config();
+            let start = (offset - CONFIG) as usize;
+            let mut value = [0; 8];
+            for (i, byte) in value.iter_mut().take(size).enumerate() {
+                *byte = config.get(start + i).copied().unwrap_or(0);
+            }
+            return Some(u64::from_le_bytes(value));
+        }
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let queue = self.queues.get(self.queue_sel as usize);
+        let value = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => Self::half(self.device_features(), self.device_features_sel),
+            QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_SIZE_MAX as u32),
+            QUEUE_NUM => queue.map_or(0, |queue| queue.size as u32),
+            QUEUE_READY => queue.map_or(0, |queue| queue.ready as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            QUEUE_DESC_LOW => queue.map_or(0, |queue| queue.desc as u32),
+            QUEUE_DESC_HIGH => queue.map_or(0, |queue| (queue.desc >> 32) as u32),
+            QUEUE_DRIVER_LOW => queue.map_or(0, |queue| queue.driver as u32),
+            QUEUE_DRIVER_HIGH => queue.map_or(0, |queue| (queue.driver >> 32) as u32),
+            QUEUE_DEVICE_LOW => queue.map_or(0, |queue| queue.device as u32),
+            QUEUE_DEVICE_HIGH => queue.map_or(0, |queue| (queue.device >> 32) as u32),
+            // The configuration never changes under the driver
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if offset >= CONFIG {
+            // The configuration spaces of the devices are read-only
+            return Some(());
+        }
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = value as u32;
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => Self::set_half(&mut self.driver_features, false, value),
+                1 => Self::set_half(&mut self.driver_features, true, value),
+                _ => {}
+            },
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM => {
+                if let Some(queue) = self.queue() {
+                    queue.size = (value as u16).min(QUEUE_SIZE_MAX);
+                }
+            }
+            QUEUE_READY => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            QUEUE_NOTIFY => {
+                if let Some(queue) = self.queues.get_mut(value as usize) {
+                    queue.notified = true;
+                }
+            }
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else if value & STATUS_FEATURES_OK != 0
+                    && self.driver_features & !self.device_features() != 0
+                {
+                    // Features the device did not offer cannot be accepted
+                    self.status = value & !STATUS_FEATURES_OK;
+                } else {
+                    self.status = value;
+                }
+            }
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.queue() {
+                    Self::set_half(&mut queue.desc, offset == QUEUE_DESC_HIGH, value);
+                }
+            }
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.queue() {
+                    Self::set_half(&mut queue.driver, offset == QUEUE_DRIVER_HIGH, value);
+                }
+            }
+            QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.queue() {
+                    Self::set_half(&mut queue.device, offset == QUEUE_DEVICE_HIGH, value);
+                }
+            }
+            // The identification registers are read-only
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn dma(&mut self, memory: &mut Dma) {
+        if self.status & STATUS_DRIVER_OK == 0 {
+            return;
+        }
+        for (index, queue) in self.queues.iter_mut().enumerate() {
+            if queue.notified {
+                queue.notified = false;
+                self.device.notify(index, queue, memory);
+            }
+        }
+        self.device.poll(&mut self.queues, memory);
+        for queue in &mut self.queues {
+            if queue.interrupt {
+                queue.interrupt = false;
+                self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            }
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn device_tree(&self, fdt: &mut Fdt, base: u64, size: u64, irq: Option<u32>) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_u64s("reg", &[base, size]);
+        if let Some(irq) = irq {
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.property_u32("interrupts", irq);
+        }
+        fdt.end_node();
+    }
+}
+
+/// A driver's side of a queue, for testing devices without a guest
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    const DESC: u64 = 0x0;
+    const DRIVER: u64 = 0x1000;
+    const DEVICE: u64 = 0x2000;
+    const BUFFERS: u64 = 0x4000;
+    const SIZE: u16 = 16;
+
+    /// A queue of `SIZE` entries in RAM starting at address 0, and the
+    /// buffers the driver allocates from it
+    pub struct Driver {
+        pub ram: Vec<u8>,
+        pub queue: Queue,
+        next_desc: u16,
+        next_buffer: u64,
+        avail: u16,
+        used: u16,
+    }
+
+    impl Driver {
+        pub fn new() -> Self {
+            Self {
+                ram: vec![0; 0x10000],
+                queue: Queue {
+                    size: SIZE,
+                    ready: true,
+                    desc: DESC,
+                    driver: DRIVER,
+                    device: DEVICE,
+                    ..Queue::default()
+                },
+                next_desc: 0,
+                next_buffer: BUFFERS,
+                avail: 0,
+                used: 0,
+            }
+        }
+
+        pub fn memory(&mut self) -> Dma<'_> {
+            Dma::new(0, &mut self.ram)
+        }
+
+        fn write(&mut self, address: u64, size: usize, value: u64) {
+            self.memory().write(address, size, value).unwrap();
+        }
+
+        /// Make a chain available of buffers holding each of `readable`,
+        /// followed by writable buffers of each of `writable` bytes,
+        /// returning the addresses of the writable ones
+        pub fn submit(&mut self, readable: &[&[u8]], writable: &[u32]) -> Vec<u64> {
+            let count = readable.len() + writable.len();
+            let head = self.next_desc;
+            let mut addresses = Vec::new();
+            for i in 0..count {
+                let index = (self.next_desc + i as u16) % SIZE;
+                let (length, flags) = match readable.get(i) {
+                    Some(bytes) => {
+                        let address = self.next_buffer;
+                        self.ram[address as usize..][..bytes.len()].copy_from_slice(bytes);
+                        (bytes.len() as u32, 0)
+                    }
+                    None => {
+                        addresses.push(self.next_buffer);
+                        (writable[i - readable.len()], DESC_F_WRITE)
+                    }
+                };
+                let next = if i + 1 < count { DESC_F_NEXT } else { 0 };
+                let desc = DESC + DESC_SIZE * index as u64;
+                self.write(desc, 8, self.next_buffer);
+                self.write(desc + 8, 4, length as u64);
+                self.write(desc + 12, 2, (flags | next) as u64);
+                self.write(desc + 14, 2, ((index + 1) % SIZE) as u64);
+                self.next_buffer += (length as u64).max(1);
+            }
+            self.next_desc = (self.next_desc + count as u16) % SIZE;
+            let slot = (self.avail % SIZE) as u64;
+            self.write(DRIVER + 4 + 2 * slot, 2, head as u64);
+            self.avail = self.avail.wrapping_add(1);
+            self.write(DRIVER + 2, 2, self.avail as u64);
+            addresses
+        }
+
+        /// The next chain the device returned: its head descriptor and the
+        /// number of bytes written to it
+        pub fn used(&mut self) -> Option<(u16, u32)> {
+            let memory = Dma::new(0, &mut self.ram);
+            if memory.read(DEVICE + 2, 2)? as u16 == self.used {
+                return None;
+            }
+            let entry = DEVICE + 4 + 8 * (self.used % SIZE) as u64;
+            let head = memory.read(entry, 4)? as u16;
+            let length = memory.read(entry + 4, 4)? as u32;
+            self.used = self.used.wrapping_add(1);
+            Some((head, length))
+        }
+
+        /// Notify `device` of the chains made available on its queue `index`
+        pub fn notify(&mut self, device: &mut dyn VirtioDevice, index: usize) {
+            let mut memory = Dma::new(0, &mut self.ram);
+            device.notify(index, &mut self.queue, &mut memory);
+        }
+
+        pub fn bytes(&self, address: u64, length: usize) -> &[u8] {
+            &self.ram[address as usize..][..length]
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::testing::Driver;
+    use super::*;
+    use crate::bus::Bus;
+    use std::io;
+    use std::sync::mpsc;
+
+    /// Answers each chain with its readable bytes reversed
+    struct Reverse;
+
+    impl VirtioDevice for Reverse {
+        fn device_id(&self) -> u32 {
+            0xff
+        }
+
+        fn features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn queue_count(&self) -> usize {
+            2
+        }
+
+        fn config(&self) -> Vec<u8> {
+            vec![1, 2, 3, 4, 5]
+        }
+
+        fn notify(&mut self, _index: usize, queue: &mut Queue, memory: &mut Dma) {
+            while let Some(chain) = queue.pop(memory) {
+                let mut bytes = chain.read(memory);
+                bytes.reverse();
+                let written = chain.write(memory, &bytes);
+                queue.push(memory, chain, written as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut mmio = VirtioMmio::new(Box::new(Reverse));
+        assert_eq!(mmio.read(MAGIC_VALUE, 4), Some(0x7472_6976));
+        assert_eq!(mmio.read(VERSION, 4), Some(2));
+        assert_eq!(mmio.read(DEVICE_ID, 4), Some(0xff));
+        assert_eq!(mmio.read(DEVICE_FEATURES, 4), Some(1 << 3));
+        mmio.write(DEVICE_FEATURES_SEL, 4, 1).unwrap();
+        assert_eq!(mmio.read(DEVICE_FEATURES, 4), Some(1));
+
+        // Configuration space accesses may be of any size
+        assert_eq!(mmio.read(CONFIG + 1, 2), Some(0x0302));
+        assert_eq!(mmio.read(CONFIG + 4, 4), Some(5));
+        assert_eq!(mmio.read(STATUS, 2), None);
+
+        // Queue registers apply to the selected queue
+        mmio.write(QUEUE_SEL, 4, 1).unwrap();
+        assert_eq!(mmio.read(QUEUE_NUM_MAX, 4), Some(256));
+        mmio.write(QUEUE_NUM, 4, 64).unwrap();
+        mmio.write(QUEUE_DESC_HIGH, 4, 0x1).unwrap();
+        mmio.write(QUEUE_DESC_LOW, 4, 0x8000_0000).unwrap();
+        assert_eq!(mmio.queues[1].desc, 0x1_8000_0000);
+        mmio.write(QUEUE_SEL, 4, 0).unwrap();
+        assert_eq!(mmio.read(QUEUE_NUM, 4), Some(0));
+        mmio.write(QUEUE_SEL, 4, 2).unwrap();
+        assert_eq!(mmio.read(QUEUE_NUM_MAX, 4), Some(0));
+
+        // Features the device does not offer are refused
+        mmio.write(DRIVER_FEATURES, 4, 1 << 4).unwrap();
+        mmio.write(STATUS, 4, 0xb).unwrap();
+        assert_eq!(mmio.read(STATUS, 4), Some(0x3));
+        mmio.write(DRIVER_FEATURES, 4, 1 << 3).unwrap();
+        mmio.write(STATUS, 4, 0xb).unwrap();
+        assert_eq!(mmio.read(STATUS, 4), Some(0xb));
+
+        // Writing zero resets the device
+        mmio.write(STATUS, 4, 0).unwrap();
+        assert_eq!(mmio.read(STATUS, 4), Some(0));
+        assert_eq!(mmio.queues[1].desc, 0);
+    }
+
+    #[test]
+    fn test_many_queues() {
+        // A console of 40 ports has 82 queues
+        let ports = (0..40)
+            .map(|_| console::Port::new(None, mpsc::channel().1, Box::new(io::sink())))
+            .collect();
+        let mut mmio = VirtioMmio::new(Box::new(console::VirtioConsole::new(ports)));
+        mmio.write(QUEUE_NOTIFY, 4, 81).unwrap();
+        assert!(mmio.queues[81].notified);
+        mmio.write(QUEUE_NOTIFY, 4, 82).unwrap();
+        assert_eq!(mmio.queues.iter().filter(|queue| queue.notified).count(), 1);
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut driver = Driver::new();
+        let out = driver.submit(&[b"ab", b"cde"], &[2, 4]);
+        driver.submit(&[b"xyz"], &[]);
+        driver.notify(&mut Reverse, 0);
+
+        assert_eq!(driver.used(), Some((0, 5)));
+        assert_eq!(driver.bytes(out[0], 2), b"ed");
+        assert_eq!(driver.bytes(out[1], 3), b"cba");
+        assert_eq!(driver.used(), Some((4, 0)));
+        assert_eq!(driver.used(), None);
+        assert!(driver.queue.interrupt);
+    }
+
+    #[test]
+    fn test_malformed_chains() {
+        let mut driver = Driver::new();
+        let write = |ram: &mut Vec<u8>, desc: u64, address: u64, length: u32, flags: u16| {
+            let mut memory = Dma::new(0, ram);
+            memory.write(desc, 8, address).unwrap();
+            memory.write(desc + 8, 4, length as u64).unwrap();
+            memory.write(desc + 12, 2, flags as u64).unwrap();
+        };
+
+        // An indirect table referring to itself ends the chain
+        driver.submit(&[b"a"], &[]);
+        write(&mut driver.ram, 0, 0x8000, 16, DESC_F_INDIRECT);
+        write(&mut driver.ram, 0x8000, 0x8000, 16, DESC_F_INDIRECT);
+        let chain = driver.queue.pop(&Dma::new(0, &mut driver.ram)).unwrap();
+        assert!(chain.readable.is_empty() && chain.writable.is_empty());
+
+        // So does one that is not the first descriptor of its chain
+        driver.submit(&[b"b", b"c"], &[]);
+        write(&mut driver.ram, 2 * DESC_SIZE, 0x8000, 16, DESC_F_INDIRECT);
+        let chain = driver.queue.pop(&Dma::new(0, &mut driver.ram)).unwrap();
+        assert_eq!(chain.readable.len(), 1);
+
+        // And a buffer larger than memory
+        driver.submit(&[], &[u32::MAX]);
+        let chain = driver.queue.pop(&Dma::new(0, &mut driver.ram)).unwrap();
+        assert_eq!(chain.writable_len(), 0);
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut bus = Bus::new(0, vec![0; 0x10000]);
+        let (base, irq) = slot(0);
+        bus.add_device_with_irq(
+            base,
+            VIRTIO_SIZE,
+            irq,
+            Box::new(VirtioMmio::new(Box::new(Reverse))),
+        );
+        let register = |offset| base + offset;
+
+        // A one-entry queue with a chain of a readable and a writable
+        // descriptor
+        bus.write(register(QUEUE_NUM), 4, 1).unwrap();
+        bus.write(register(QUEUE_DESC_LOW), 4, 0x100).unwrap();
+        bus.write(register(QUEUE_DRIVER_LOW), 4, 0x200).unwrap();
+        bus.write(register(QUEUE_DEVICE_LOW), 4, 0x300).unwrap();
+        bus.write(register(QUEUE_READY), 4, 1).unwrap();
+        // The chain is in an indirect table
+        bus.write(0x100, 8, 0x2000).unwrap();
+        bus.write(0x108, 4, 32).unwrap();
+        bus.write(0x10c, 2, DESC_F_INDIRECT as u64).unwrap();
+        bus.write(0x2000, 8, 0x3000).unwrap();
+        bus.write(0x2008, 4, 3).unwrap();
+        bus.write(0x200c, 2, DESC_F_NEXT as u64).unwrap();
+        bus.write(0x200e, 2, 1).unwrap();
+        bus.write(0x2010, 8, 0x3100).unwrap();
+        bus.write(0x2018, 4, 3).unwrap();
+        bus.write(0x201c, 2, DESC_F_WRITE as u64).unwrap();
+        bus.write(0x3000, 3, 0x414243).unwrap();
+        bus.write(0x202, 2, 1).unwrap();
+        bus.write(register(QUEUE_NOTIFY), 4, 0).unwrap();
+
+        // Nothing happens until the driver is ready
+        bus.tick();
+        assert_eq!(bus.read(0x302, 2), Some(0));
+        bus.write(register(STATUS), 4, 0xf).unwrap();
+        bus.tick();
+        assert_eq!(bus.read(0x302, 2), Some(1));
+        assert_eq!(bus.read(0x308, 4), Some(3));
+        assert_eq!(bus.read(0x3100, 4), Some(0x434241));
+        assert_eq!(bus.read(register(INTERRUPT_STATUS), 4), Some(1));
+        bus.write(register(INTERRUPT_ACK), 4, 1).unwrap();
+        assert_eq!(bus.read(register(INTERRUPT_STATUS), 4), Some(0));
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{Queue, VirtioDevice};
+use crate::bus::Dma;
+
+const DEVICE_ID: u32 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// Size of the sectors requests address the disk in, whatever the image
+pub const SECTOR_SIZE: usize = 512;
+
+// Request types
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+const T_GET_ID: u32 = 8;
+
+// Request statuses
+const S_OK: u8 = 0;
+const S_IOERR: u8 = 1;
+const S_UNSUPP: u8 = 2;
+
+/// Size of the header of each request: its type, a reserved word and the
+/// first sector it accesses
+const HEADER_SIZE: usize = 16;
+
+/// Size of the identifier GET_ID returns, padded with zeros if shorter
+const ID_SIZE: usize = 20;
+
+/// How guest writes reach the disk image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskMode {
+    /// Writes go to the image
+    ReadWrite,
+    /// The guest sees a read-only disk
+    ReadOnly,
+    /// Writes are kept in memory over the image, which is never modified,
+    /// and are lost when the emulator exits
+    Overlay,
+}
+
+/// Storage holding a disk image
+pub trait Image: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> Image for T {}
+
+/// Virtio block device serving a disk image
+pub struct VirtioBlock {
+    image: Box<dyn Image>,
+    sectors: u64,
+    mode: DiskMode,
+    id: Vec<u8>,
+    /// Sectors written in overlay mode
+    overlay: HashMap<u64, Vec<u8>>,
+}
+
+impl VirtioBlock {
+    /// A disk of the whole sectors of `image`, identified to the guest as
+    /// `id`
+    pub fn new(mut image: Box<dyn Image>, mode: DiskMode, id: &str) -> io::Result<Self> {
+        let size = image.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            image,
+            sectors: size / SECTOR_SIZE as u64,
+            mode,
+            id: id.bytes().take(ID_SIZE).collect(),
+            overlay: HashMap::new(),
+        })
+    }
+
+    /// A disk of the image at `path`, identified by its file name. Only
+    /// read-write disks open the image for writing.
+    pub fn open(path: &Path, mode: DiskMode) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+        let id = path
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        Self::new(Box::new(file), mode, &id)
+    }
+
+    /// Whether `length` bytes starting at `sector` are whole sectors within
+    /// the disk
+    fn in_bounds(&self, sector: u64, length: usize) -> bool {
+        length.is_multiple_of(SECTOR_SIZE)
+            && sector
+                .checked_add((length / SECTOR_SIZE) as u64)
+                .is_some_and(|end| end <= self.sectors)
+    }
+
+    fn read_sectors(&mut self, sector: u64, bytes: &mut [u8]) -> io::Result<()> {
+        self.image
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.image.read_exact(bytes)?;
+        for (i, chunk) in bytes.chunks_mut(SECTOR_SIZE).enumerate() {
+            if let Some(data) = self.overlay.get(&(sector + i as u64)) {
+                chunk.copy_from_slice(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, bytes: &[u8]) -> io::Result<()> {
+        if self.mode == DiskMode::Overlay {
+            for (i, chunk) in bytes.chunks(SECTOR_SIZE).enumerate() {
+                self.overlay.insert(sector + i as u64, chunk.to_vec());
+            }
+            return Ok(());
+        }
+        self.image
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+        self.image.write_all(bytes)
+    }
+
+    /// Serve a request, given the bytes the driver wrote and room for
+    /// `length` bytes of data in response, returning its status and the
+    /// data
+    fn request(&mut self, request: &[u8], length: usize) -> (u8, Vec<u8>) {
+        if request.len() < HEADER_SIZE {
+            return (S_IOERR, Vec::new());
+        }
+        let kind = u32::from_le_bytes(<[u8; 4]>::try_from(&request[0..4]).unwrap());
+        let sector = u64::from_le_bytes(<[u8; 8]>::try_from(&request[8..16]).unwrap());
+        let data = &request[HEADER_SIZE..];
+        let result = match kind {
+            T_IN if self.in_bounds(sector, length) => {
+                let mut bytes = vec![0; length];
+                self.read_sectors(sector, &mut bytes).map(|_| bytes)
+            }
+            T_OUT if self.mode != DiskMode::ReadOnly && self.in_bounds(sector, data.len()) => {
+                self.write_sectors(sector, data).map(|_| Vec::new())
+            }
+            T_IN | T_OUT => return (S_IOERR, Vec::new()),
+            // Overlay writes are only ever in memory, so there is nothing
+            // to flush
+            T_FLUSH if self.mode == DiskMode::Overlay => Ok(Vec::new()),
+            T_FLUSH => self.image.flush().map(|_| Vec::new()),
+            T_GET_ID => {
+                let mut id = self.id.clone();
+                id.resize(ID_SIZE.min(length), 0);
+                Ok(id)
+            }
+            _ => return (S_UNSUPP, Vec::new()),
+        };
+        match result {
+            Ok(bytes) => (S_OK, bytes),
+            Err(_) => (S_IOERR, Vec::new()),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        let read_only = if self.mode == DiskMode::ReadOnly {
+            VIRTIO_BLK_F_RO
+        } else {
+            0
+        };
+        VIRTIO_BLK_F_FLUSH | read_only
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    /// The capacity in sectors; the other fields belong to features the
+    /// device does not offer
+    fn config(&self) -> Vec<u8> {
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    fn notify(&mut self, _index: usize, queue: &mut Queue, memory: &mut Dma) {
+        while let Some(chain) = queue.pop(memory) {
+            let request = chain.read(memory);
+            // The last writable byte receives the status, after any data
+            let length = chain.writable_len().saturating_sub(1);
+            let (status, mut response) = self.request(&request, length);
+            response.resize(length, 0);
+            response.push(status);
+            let written = chain.write(memory, &response);
+            queue.push(memory, chain, written as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing::Driver;
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+
+    fn header(kind: u32, sector: u64) -> Vec<u8> {
+        let mut header = kind.to_le_bytes().to_vec();
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(&sector.to_le_bytes());
+        header
+    }
+
+    /// An image of `sectors` sectors, each filled with its number
+    fn image(sectors: u8) -> Vec<u8> {
+        (0..sectors).flat_map(|i| vec![i; SECTOR_SIZE]).collect()
+    }
+
+    fn disk(mode: DiskMode) -> VirtioBlock {
+        VirtioBlock::new(Box::new(Cursor::new(image(4))), mode, "disk").unwrap()
+    }
+
+    /// Read `sectors` sectors starting at `sector`, returning the status
+    /// and data
+    fn read(
+        driver: &mut Driver,
+        disk: &mut VirtioBlock,
+        sector: u64,
+        sectors: usize,
+    ) -> (u8, Vec<u8>) {
+        let length = SECTOR_SIZE * sectors;
+        let out = driver.submit(&[&header(T_IN, sector)], &[length as u32, 1]);
+        driver.notify(disk, 0);
+        let (_, written) = driver.used().unwrap();
+        assert_eq!(written as usize, length + 1);
+        (
+            driver.bytes(out[1], 1)[0],
+            driver.bytes(out[0], length).to_vec(),
+        )
+    }
+
+    fn write(driver: &mut Driver, disk: &mut VirtioBlock, sector: u64, data: &[u8]) -> u8 {
+        let out = driver.submit(&[&header(T_OUT, sector), data], &[1]);
+        driver.notify(disk, 0);
+        assert_eq!(driver.used().map(|(_, written)| written), Some(1));
+        driver.bytes(out[0], 1)[0]
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut driver = Driver::new();
+        let mut disk = disk(DiskMode::ReadWrite);
+        assert_eq!(disk.config(), vec![4, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(disk.features(), VIRTIO_BLK_F_FLUSH);
+
+        let (status, data) = read(&mut driver, &mut disk, 1, 2);
+        assert_eq!(status, S_OK);
+        assert_eq!(data, image(3)[SECTOR_SIZE..]);
+        assert_eq!(write(&mut driver, &mut disk, 2, &[0xaa; SECTOR_SIZE]), S_OK);
+        assert_eq!(
+            read(&mut driver, &mut disk, 2, 1),
+            (S_OK, vec![0xaa; SECTOR_SIZE])
+        );
+
+        // Requests beyond the end of the disk or of partial sectors fail
+        assert_eq!(read(&mut driver, &mut disk, 3, 2).0, S_IOERR);
+        assert_eq!(write(&mut driver, &mut disk, 0, &[0; 3]), S_IOERR);
+
+        let out = driver.submit(&[&header(T_FLUSH, 0)], &[1]);
+        driver.notify(&mut disk, 0);
+        assert_eq!(driver.bytes(out[0], 1), [S_OK]);
+        let out = driver.submit(&[&header(T_GET_ID, 0)], &[ID_SIZE as u32, 1]);
+        driver.notify(&mut disk, 0);
+        assert_eq!(driver.bytes(out[0], 6), b"disk\0\0");
+        assert_eq!(driver.bytes(out[1], 1), [S_OK]);
+        let out = driver.submit(&[&header(0x99, 0)], &[1]);
+        driver.notify(&mut disk, 0);
+        assert_eq!(driver.bytes(out[0], 1), [S_UNSUPP]);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut driver = Driver::new();
+        let mut disk = disk(DiskMode::ReadOnly);
+        assert_eq!(disk.features(), VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO);
+        assert_eq!(
+            write(&mut driver, &mut disk, 0, &[0xaa; SECTOR_SIZE]),
+            S_IOERR
+        );
+        assert_eq!(
+            read(&mut driver, &mut disk, 0, 1),
+            (S_OK, vec![0; SECTOR_SIZE])
+        );
+    }
+
+    #[test]
+    fn test_overlay() {
+        let path = std::env::temp_dir().join(format!("rvemu-overlay-{}.img", std::process::id()));
+        fs::write(&path, image(2)).unwrap();
+        let mut driver = Driver::new();
+        let mut disk = VirtioBlock::open(&path, DiskMode::Overlay).unwrap();
+
+        let mut data = vec![0xbb; SECTOR_SIZE * 2];
+        data[..SECTOR_SIZE].fill(0xaa);
+        assert_eq!(write(&mut driver, &mut disk, 0, &data), S_OK);
+        assert_eq!(
+            read(&mut driver, &mut disk, 1, 1),
+            (S_OK, vec![0xbb; SECTOR_SIZE])
+        );
+        assert_eq!(read(&mut driver, &mut disk, 0, 2), (S_OK, data));
+        let out = driver.submit(&[&header(T_GET_ID, 0)], &[ID_SIZE as u32, 1]);
+        driver.notify(&mut disk, 0);
+        assert!(driver.bytes(out[0], ID_SIZE).starts_with(b"rvemu-overlay-"));
+
+        // The image itself is untouched
+        assert_eq!(fs::read(&path).unwrap(), image(2));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::{Queue, VirtioDevice};
+use crate::bus::Dma;
+use crate::uart::{forward, forward_stdin};
+
+const DEVICE_ID: u32 = 3;
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// Queues of the control port, between those of ports 0 and 1
+const CONTROL_RECEIVE_QUEUE: usize = 2;
+const CONTROL_TRANSMIT_QUEUE: usize = 3;
+
+// Control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+/// Size of a control message: port id, event and value
+const CONTROL_SIZE: usize = 8;
+
+/// Most input buffered for the guest per port
+const INPUT_SIZE_MAX: usize = 4096;
+
+/// A port of the console: a stream of bytes between the guest and the
+/// host, which guests find by its name if it has one
+pub struct Port {
+    name: Option<String>,
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+    /// Input received from the host that the guest has no buffer for yet
+    pending: VecDeque<u8>,
+}
+
+impl Port {
+    pub fn new(name: Option<String>, input: Receiver<u8>, output: Box<dyn Write>) -> Self {
+        Self {
+            name,
+            input,
+            output,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// An unnamed port on the host's stdin and stdout. Stdin is read on a
+    /// separate thread so that the guest never blocks waiting for input.
+    pub fn stdio() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || forward_stdin(&sender));
+        Self::new(None, receiver, Box::new(io::stdout()))
+    }
+
+    /// A port named `name` on a Unix socket listening at `path`, replacing
+    /// any stale one. Clients connect one at a time; output while none is
+    /// connected is discarded.
+    pub fn listen(name: &str, path: &Path) -> io::Result<Self> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let (sender, receiver) = mpsc::channel();
+        let client = Arc::new(Mutex::new(None));
+        let output = SocketOutput(client.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream: UnixStream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                *client.lock().unwrap() = stream.try_clone().ok();
+                if !forward(stream, &sender) {
+                    break;
+                }
+                *client.lock().unwrap() = None;
+            }
+        });
+        Ok(Self::new(
+            Some(name.to_string()),
+            receiver,
+            Box::new(output),
+        ))
+    }
+
+    fn receive(&mut self) {
+        while self.pending.len() < INPUT_SIZE_MAX {
+            match self.input.try_recv() {
+                Ok(byte) => self.pending.push_back(byte),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Writes to the client connected to a socket port, if any
+struct SocketOutput(Arc<Mutex<Option<UnixStream>>>);
+
+impl Write for SocketOutput {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut client = self.0.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            if stream.write_all(bytes).is_err() {
+                *client = None;
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Virtio console with multiple ports. Port 0 is the console, `hvc0` in
+/// Linux guests; the others are its serial ports, found by name under
+/// `/dev/virtio-ports`.
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    /// Control messages waiting for the driver to provide buffers
+    control: VecDeque<Vec<u8>>,
+}
+
+impl VirtioConsole {
+    /// A console of `ports`, the first being the console port
+    pub fn new(ports: Vec<Port>) -> Self {
+        assert!(!ports.is_empty(), "a console needs a console port");
+        Self {
+            ports,
+            control: VecDeque::new(),
+        }
+    }
+
+    /// The receive queue of a port, which its transmit queue follows
+    fn receive_queue(port: usize) -> usize {
+        if port == 0 {
+            0
+        } else {
+            2 * (port + 1)
+        }
+    }
+
+    /// The port a data queue belongs to
+    fn port(queue: usize) -> usize {
+        if queue < CONTROL_RECEIVE_QUEUE {
+            0
+        } else {
+            queue / 2 - 1
+        }
+    }
+
+    fn send_control(&mut self, port: usize, event: u16, value: u16, data: &[u8]) {
+        let mut message = (port as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(data);
+        self.control.push_back(message);
+    }
+
+    /// Handle the control messages from the driver
+    fn control_transmit(&mut self, queue: &mut Queue, memory: &mut Dma) {
+        while let Some(chain) = queue.pop(memory) {
+            let message = chain.read(memory);
+            queue.push(memory, chain, 0);
+            if message.len() < CONTROL_SIZE {
+                continue;
+            }
+            let port = u32::from_le_bytes([message[0], message[1], message[2], message[3]]);
+            let event = u16::from_le_bytes([message[4], message[5]]);
+            let value = u16::from_le_bytes([message[6], message[7]]);
+            match event {
+                DEVICE_READY if value == 1 => {
+                    for port in 0..self.ports.len() {
+                        self.send_control(port, DEVICE_ADD, 0, &[]);
+                    }
+                }
+                PORT_READY if value == 1 && (port as usize) < self.ports.len() => {
+                    let port = port as usize;
+                    if port == 0 {
+                        self.send_control(port, CONSOLE_PORT, 1, &[]);
+                    }
+                    if let Some(name) = self.ports[port].name.clone() {
+                        self.send_control(port, PORT_NAME, 1, name.as_bytes());
+                    }
+                    // The host side of every port is always open
+                    self.send_control(port, PORT_OPEN, 1, &[]);
+                }
+                // Guests opening and closing ports need no response
+                _ => {}
+            }
+        }
+    }
+
+    fn control_receive(&mut self, queue: &mut Queue, memory: &mut Dma) {
+        while !self.control.is_empty() {
+            let chain = match queue.pop(memory) {
+                Some(chain) => chain,
+                None => return,
+            };
+            let message = self.control.pop_front().unwrap();
+            let written = chain.write(memory, &message);
+            queue.push(memory, chain, written as u32);
+        }
+    }
+
+    /// Pass a port's input to the guest while it has buffers for it
+    fn receive(&mut self, port: usize, queue: &mut Queue, memory: &mut Dma) {
+        let port = &mut self.ports[port];
+        port.receive();
+        while !port.pending.is_empty() {
+            let chain = match queue.pop(memory) {
+                Some(chain) => chain,
+                None => return,
+            };
+            let count = chain.writable_len().min(port.pending.len());
+            let bytes: Vec<u8> = port.pending.drain(..count).collect();
+            let written = chain.write(memory, &bytes);
+            queue.push(memory, chain, written as u32);
+        }
+    }
+
+    fn transmit(&mut self, port: usize, queue: &mut Queue, memory: &mut Dma) {
+        let port = &mut self.ports[port];
+        while let Some(chain) = queue.pop(memory) {
+            let bytes = chain.read(memory);
+            let _ = port.output.write_all(&bytes);
+            let _ = port.output.flush();
+            queue.push(memory, chain, 0);
+        }
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn queue_count(&self) -> usize {
+        2 * (self.ports.len() + 1)
+    }
+
+    /// No size, as the device does not offer it, then the number of ports
+    /// and no emergency write register
+    fn config(&self) -> Vec<u8> {
+        let mut config = vec![0; 4];
+        config.extend_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        config.extend_from_slice(&[0; 4]);
+        config
+    }
+
+    fn reset(&mut self) {
+        self.control.clear();
+    }
+
+    fn notify(&mut self, index: usize, queue: &mut Queue, memory: &mut Dma) {
+        match index {
+            CONTROL_RECEIVE_QUEUE => self.control_receive(queue, memory),
+            CONTROL_TRANSMIT_QUEUE => self.control_transmit(queue, memory),
+            _ if index.is_multiple_of(2) => self.receive(Self::port(index), queue, memory),
+            _ => self.transmit(Self::port(index), queue, memory),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], memory: &mut Dma) {
+        for port in 0..self.ports.len() {
+            let queue = &mut queues[Self::receive_queue(port)];
+            self.receive(port, queue, memory);
+        }
+        self.control_receive(&mut queues[CONTROL_RECEIVE_QUEUE], memory);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing::Driver;
+    use super::*;
+    use crate::testing::Buffer;
+    use std::io::Read;
+    use std::sync::mpsc::Sender;
+    use std::time::{Duration, Instant};
+
+    fn port(name: Option<&str>) -> (Port, Sender<u8>, Buffer) {
+        let (sender, receiver) = mpsc::channel();
+        let output = Buffer::default();
+        let port = Port::new(name.map(str::to_string), receiver, Box::new(output.clone()));
+        (port, sender, output)
+    }
+
+    fn control(port: u32, event: u16, value: u16) -> Vec<u8> {
+        let mut message = port.to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message
+    }
+
+    #[test]
+    fn test_ports() {
+        let (console, console_input, console_output) = port(None);
+        let (serial, _, serial_output) = port(Some("serial"));
+        let mut device = VirtioConsole::new(vec![console, serial]);
+        assert_eq!(device.queue_count(), 6);
+        assert_eq!(device.config()[4..8], [2, 0, 0, 0]);
+
+        let mut transmit = Driver::new();
+        transmit.submit(&[b"hello"], &[]);
+        transmit.notify(&mut device, 1);
+        assert_eq!(*console_output.0.borrow(), b"hello");
+        transmit.submit(&[b"data"], &[]);
+        transmit.notify(&mut device, 5);
+        assert_eq!(*serial_output.0.borrow(), b"data");
+
+        // Input waits for buffers, and is split across them
+        for &byte in b"typed" {
+            console_input.send(byte).unwrap();
+        }
+        let mut receive = Driver::new();
+        let out = receive.submit(&[], &[3]);
+        receive.notify(&mut device, 0);
+        assert_eq!(receive.used(), Some((0, 3)));
+        assert_eq!(receive.bytes(out[0], 3), b"typ");
+        let out = receive.submit(&[], &[8]);
+        receive.notify(&mut device, 0);
+        assert_eq!(receive.used(), Some((1, 2)));
+        assert_eq!(receive.bytes(out[0], 2), b"ed");
+    }
+
+    #[test]
+    fn test_control() {
+        let (console, _, _) = port(None);
+        let (serial, _, _) = port(Some("serial"));
+        let mut device = VirtioConsole::new(vec![console, serial]);
+        let mut transmit = Driver::new();
+        let mut receive = Driver::new();
+        let mut messages = |device: &mut VirtioConsole, count| {
+            let out: Vec<u64> = (0..count).map(|_| receive.submit(&[], &[16])[0]).collect();
+            receive.notify(device, CONTROL_RECEIVE_QUEUE);
+            out.iter()
+                .map(|&address| {
+                    let (_, length) = receive.used().unwrap();
+                    receive.bytes(address, length as usize).to_vec()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        transmit.submit(&[&control(0, DEVICE_READY, 1)], &[]);
+        transmit.notify(&mut device, CONTROL_TRANSMIT_QUEUE);
+        assert_eq!(
+            messages(&mut device, 2),
+            [control(0, DEVICE_ADD, 0), control(1, DEVICE_ADD, 0)]
+        );
+
+        transmit.submit(&[&control(0, PORT_READY, 1)], &[]);
+        transmit.submit(&[&control(1, PORT_READY, 1)], &[]);
+        transmit.notify(&mut device, CONTROL_TRANSMIT_QUEUE);
+        let mut name = control(1, PORT_NAME, 1);
+        name.extend_from_slice(b"serial");
+        assert_eq!(
+            messages(&mut device, 4),
+            [
+                control(0, CONSOLE_PORT, 1),
+                control(0, PORT_OPEN, 1),
+                name,
+                control(1, PORT_OPEN, 1),
+            ]
+        );
+        assert!(device.control.is_empty());
+    }
+
+    #[test]
+    fn test_socket_port() {
+        let path = std::env::temp_dir().join(format!("rvemu-port-{}.sock", std::process::id()));
+        let mut port = Port::listen("serial", &path).unwrap();
+        // Output before a client connects is lost
+        port.output.write_all(b"lost").unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"in").unwrap();
+        let start = Instant::now();
+        while port.pending.len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+            port.receive();
+        }
+        assert_eq!(port.pending, b"in");
+        port.output.write_all(b"out").unwrap();
+        let mut bytes = [0; 3];
+        client.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, *b"out");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::SystemTime;
+
+use super::{Queue, VirtioDevice};
+use crate::bus::Dma;
+
+const DEVICE_ID: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const NET_S_LINK_UP: u16 = 1;
+
+const RECEIVE_QUEUE: usize = 0;
+const TRANSMIT_QUEUE: usize = 1;
+
+/// Size of the header preceding each frame in the queues, including the
+/// buffer count that non-legacy devices always have
+const HEADER_SIZE: usize = 12;
+
+/// Largest frame a socket peer may send, room for an IP packet of the
+/// largest size. A peer claiming a longer one is disconnected rather than
+/// have the host allocate whatever it asks for.
+const FRAME_SIZE_MAX: usize = 65535;
+
+/// The address QEMU gives its first network card
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// Where the frames the guest sends go, and those it receives come from
+pub trait Backend {
+    /// Send a frame from the guest
+    fn send(&mut self, frame: &[u8]);
+
+    /// A frame for the guest, if one has arrived
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Returns every frame sent to the guest that sent it
+#[derive(Default)]
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Backend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.frames.push_back(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+}
+
+/// Connects to another emulator over a Unix socket, framing each frame
+/// with its length as a 32-bit big-endian prefix, as QEMU's socket network
+/// backend does. Frames are read on a separate thread so that the guest
+/// never blocks waiting for its peer.
+pub struct Socket {
+    stream: UnixStream,
+    frames: Receiver<Vec<u8>>,
+}
+
+impl Socket {
+    pub fn new(stream: UnixStream) -> io::Result<Self> {
+        let mut reader = stream.try_clone()?;
+        let (sender, frames) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut length = [0; 4];
+            if reader.read_exact(&mut length).is_err() {
+                break;
+            }
+            let length = u32::from_be_bytes(length) as usize;
+            if length > FRAME_SIZE_MAX {
+                let _ = reader.shutdown(Shutdown::Both);
+                break;
+            }
+            let mut frame = vec![0; length];
+            if reader.read_exact(&mut frame).is_err() || sender.send(frame).is_err() {
+                break;
+            }
+        });
+        Ok(Self { stream, frames })
+    }
+
+    /// Listen on a socket at `path`, replacing any stale one, and wait
+    /// for the peer to connect
+    pub fn listen(path: &Path) -> io::Result<Self> {
+        let _ = std::fs::remove_file(path);
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        Self::new(stream)
+    }
+
+    /// Connect to a peer listening on a socket at `path`
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        Self::new(UnixStream::connect(path)?)
+    }
+}
+
+impl Backend for Socket {
+    fn send(&mut self, frame: &[u8]) {
+        // A peer that went away is like an unplugged cable
+        let length = (frame.len() as u32).to_be_bytes();
+        let _ = self
+            .stream
+            .write_all(&length)
+            .and_then(|_| self.stream.write_all(frame));
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.frames.try_recv().ok()
+    }
+}
+
+// Pcap file format constants
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION: (u16, u16) = (2, 4);
+const PCAP_SNAPLEN: u32 = 65535;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+/// Records the frames in both directions to a pcap file, for inspection in
+/// tools such as Wireshark, on their way to and from another backend, if
+/// there is one
+pub struct Pcap {
+    output: Box<dyn Write>,
+    inner: Option<Box<dyn Backend>>,
+}
+
+impl Pcap {
+    pub fn new(mut output: Box<dyn Write>, inner: Option<Box<dyn Backend>>) -> Self {
+        let mut header = Vec::new();
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION.0.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION.1.to_le_bytes());
+        // Time zone offset and timestamp accuracy
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&PCAP_LINKTYPE_ETHERNET.to_le_bytes());
+        let _ = output.write_all(&header);
+        Self { output, inner }
+    }
+
+    fn record(&mut self, frame: &[u8]) {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured = frame.len().min(PCAP_SNAPLEN as usize);
+        let mut record = Vec::new();
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame[..captured]);
+        let _ = self.output.write_all(&record);
+        let _ = self.output.flush();
+    }
+}
+
+impl Backend for Pcap {
+    fn send(&mut self, frame: &[u8]) {
+        self.record(frame);
+        if let Some(inner) = &mut self.inner {
+            inner.send(frame);
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let frame = self.inner.as_mut()?.receive()?;
+        self.record(&frame);
+        Some(frame)
+    }
+}
+
+/// Virtio network device, passing Ethernet frames between the guest and a
+/// backend
+pub struct VirtioNet {
+    backend: Box<dyn Backend>,
+    mac: [u8; 6],
+    /// A frame received from the backend that is waiting for the guest to
+    /// provide a buffer
+    pending: Option<Vec<u8>>,
+}
+
+impl VirtioNet {
+    pub fn new(backend: Box<dyn Backend>, mac: [u8; 6]) -> Self {
+        Self {
+            backend,
+            mac,
+            pending: None,
+        }
+    }
+
+    /// Pass frames from the backend to the guest while it has buffers for
+    /// them. Frames too large for the buffers are dropped.
+    fn receive(&mut self, queue: &mut Queue, memory: &mut Dma) {
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.backend.receive();
+            }
+            let frame = match &self.pending {
+                Some(frame) => frame,
+                None => return,
+            };
+            let chain = match queue.pop(memory) {
+                Some(chain) => chain,
+                None => return,
+            };
+            let mut packet = vec![0; HEADER_SIZE];
+            // The frame takes a single chain
+            packet[HEADER_SIZE - 2] = 1;
+            packet.extend_from_slice(frame);
+            let written = if packet.len() <= chain.writable_len() {
+                chain.write(memory, &packet)
+            } else {
+                0
+            };
+            queue.push(memory, chain, written as u32);
+            self.pending = None;
+        }
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, memory: &mut Dma) {
+        while let Some(chain) = queue.pop(memory) {
+            let packet = chain.read(memory);
+            if packet.len() > HEADER_SIZE {
+                self.backend.send(&packet[HEADER_SIZE..]);
+            }
+            queue.push(memory, chain, 0);
+        }
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn queue_count(&self) -> usize {
+        2
+    }
+
+    /// The MAC address and link status
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.mac.to_vec();
+        config.extend_from_slice(&NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+    }
+
+    fn notify(&mut self, index: usize, queue: &mut Queue, memory: &mut Dma) {
+        match index {
+            RECEIVE_QUEUE => self.receive(queue, memory),
+            TRANSMIT_QUEUE => self.transmit(queue, memory),
+            _ => {}
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], memory: &mut Dma) {
+        self.receive(&mut queues[RECEIVE_QUEUE], memory);
+    }
+}
+
+/// Parse a MAC address written as six colon-separated hexadecimal bytes
+pub fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let bytes = text
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    <[u8; 6]>::try_from(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing::Driver;
+    use super::*;
+    use crate::testing::Buffer;
+    use std::time::{Duration, Instant};
+
+    fn packet(frame: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; HEADER_SIZE];
+        packet.extend_from_slice(frame);
+        packet
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut net = VirtioNet::new(Box::new(Loopback::default()), DEFAULT_MAC);
+        assert_eq!(net.config(), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 1, 0]);
+        let mut receive = Driver::new();
+        let mut transmit = Driver::new();
+
+        transmit.submit(&[&packet(b"first")], &[]);
+        transmit.submit(&[&packet(b"second frame")], &[]);
+        transmit.notify(&mut net, TRANSMIT_QUEUE);
+        assert_eq!(transmit.used(), Some((0, 0)));
+        assert_eq!(transmit.used(), Some((1, 0)));
+
+        // Frames wait for the guest to provide buffers; those too large
+        // for them are dropped
+        let small = receive.submit(&[], &[HEADER_SIZE as u32 + 8]);
+        let large = receive.submit(&[], &[64]);
+        receive.notify(&mut net, RECEIVE_QUEUE);
+        assert_eq!(receive.used(), Some((0, HEADER_SIZE as u32 + 5)));
+        assert_eq!(
+            receive.bytes(small[0], HEADER_SIZE + 5)[10..],
+            *b"\x01\x00first"
+        );
+        assert_eq!(receive.used(), Some((1, HEADER_SIZE as u32 + 12)));
+        assert_eq!(
+            receive.bytes(large[0] + HEADER_SIZE as u64, 12),
+            b"second frame"
+        );
+
+        transmit.submit(&[&packet(&[0xff; 32])], &[]);
+        transmit.notify(&mut net, TRANSMIT_QUEUE);
+        receive.submit(&[], &[HEADER_SIZE as u32 + 8]);
+        receive.notify(&mut net, RECEIVE_QUEUE);
+        assert_eq!(receive.used(), Some((2, 0)));
+        assert!(net.pending.is_none());
+    }
+
+    #[test]
+    fn test_socket() {
+        let (local, mut peer) = UnixStream::pair().unwrap();
+        let mut socket = Socket::new(local).unwrap();
+
+        socket.send(b"ping");
+        let mut bytes = [0; 8];
+        peer.read_exact(&mut bytes).unwrap();
+        assert_eq!(bytes, *b"\0\0\0\x04ping");
+
+        peer.write_all(b"\0\0\0\x04pong").unwrap();
+        let start = Instant::now();
+        let frame = loop {
+            if let Some(frame) = socket.receive() {
+                break frame;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(frame, b"pong");
+
+        // A peer claiming a frame longer than any Ethernet frame is
+        // disconnected
+        peer.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        assert_eq!(peer.read(&mut bytes).unwrap(), 0);
+        assert_eq!(socket.receive(), None);
+    }
+
+    #[test]
+    fn test_pcap() {
+        let output = Buffer::default();
+        let mut pcap = Pcap::new(
+            Box::new(output.clone()),
+            Some(Box::new(Loopback::default())),
+        );
+        assert_eq!(output.0.borrow()[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(output.0.borrow().len(), 24);
+
+        pcap.send(b"frame");
+        assert_eq!(pcap.receive(), Some(b"frame".to_vec()));
+        assert_eq!(pcap.receive(), None);
+        // A record in each direction
+        let output = output.0.borrow();
+        assert_eq!(output.len(), 24 + 2 * (16 + 5));
+        assert_eq!(output[24 + 8..24 + 16], [5, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(output[24 + 16..24 + 21], *b"frame");
+
+        assert_eq!(
+            parse_mac("52:54:00:ab:cd:ef"),
+            Some([0x52, 0x54, 0, 0xab, 0xcd, 0xef])
+        );
+        assert_eq!(parse_mac("52:54:00:ab:cd"), None);
+    }
+}
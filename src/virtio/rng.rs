@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::{self, Read};
+
+use super::{Queue, VirtioDevice};
+use crate::bus::Dma;
+
+const DEVICE_ID: u32 = 4;
+
+/// Most bytes served per request, however large the guest's buffers
+const REQUEST_SIZE_MAX: usize = 0x10000;
+
+/// Deterministic random bytes from a seed, by SplitMix64, for runs that
+/// must be reproducible
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Read for SeededRandom {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+        for chunk in bytes.chunks_mut(8) {
+            let value = self.next().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+        Ok(bytes.len())
+    }
+}
+
+/// Virtio entropy device, filling the guest's buffers from a source of
+/// random bytes
+pub struct VirtioRng {
+    source: Box<dyn Read>,
+}
+
+impl VirtioRng {
+    pub fn new(source: Box<dyn Read>) -> Self {
+        Self { source }
+    }
+
+    /// Entropy from the host's random number generator
+    pub fn host() -> io::Result<Self> {
+        Ok(Self::new(Box::new(File::open("/dev/urandom")?)))
+    }
+
+    /// Entropy that is the same on every run with the same `seed`
+    pub fn seeded(seed: u64) -> Self {
+        Self::new(Box::new(SeededRandom::new(seed)))
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn notify(&mut self, _index: usize, queue: &mut Queue, memory: &mut Dma) {
+        while let Some(chain) = queue.pop(memory) {
+            let mut bytes = vec![0; chain.writable_len().min(REQUEST_SIZE_MAX)];
+            let written = match self.source.read(&mut bytes) {
+                Ok(count) => chain.write(memory, &bytes[..count]),
+                Err(_) => 0,
+            };
+            queue.push(memory, chain, written as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing::Driver;
+    use super::*;
+
+    fn request(rng: &mut VirtioRng, lengths: &[u32]) -> Vec<u8> {
+        let mut driver = Driver::new();
+        let out = driver.submit(&[], lengths);
+        driver.notify(rng, 0);
+        let total = lengths.iter().sum::<u32>();
+        assert_eq!(driver.used(), Some((0, total)));
+        out.iter()
+            .zip(lengths)
+            .flat_map(|(&address, &length)| driver.bytes(address, length as usize).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_seeded() {
+        let mut first = VirtioRng::seeded(42);
+        let mut second = VirtioRng::seeded(42);
+        let bytes = request(&mut first, &[5, 12]);
+        assert_eq!(bytes, request(&mut second, &[17]));
+        assert_ne!(bytes, request(&mut first, &[17]));
+        assert_ne!(bytes, request(&mut VirtioRng::seeded(43), &[17]));
+    }
+
+    #[test]
+    fn test_host() {
+        let mut rng = VirtioRng::host().unwrap();
+        let bytes = request(&mut rng, &[64]);
+        assert_ne!(bytes, vec![0; 64]);
+    }
+}
@@ -0,0 +1,20 @@
+#!/bin/sh
+# Build the riscv-tests ISA binaries that tests/riscv_tests.rs runs into
+# isa/ next to this script, with a riscv64-unknown-elf toolchain on the
+# PATH. The sources are cloned into src/ unless RISCV_TESTS_SRC names an
+# existing checkout.
+set -e
+
+dir=$(cd "$(dirname "$0")" && pwd)
+src=${RISCV_TESTS_SRC:-$dir/src}
+
+if [ ! -d "$src" ]; then
+    git clone --recursive https://github.com/riscv-software-src/riscv-tests "$src"
+fi
+make -C "$src/isa" XLEN=64
+
+mkdir -p "$dir/isa"
+for suite in rv64ui rv64um rv64ua rv64uf rv64ud rv64uc rv64mi rv64si; do
+    find "$src/isa" -maxdepth 1 -type f -name "$suite-p-*" ! -name '*.dump' \
+        -exec cp {} "$dir/isa/" \;
+done
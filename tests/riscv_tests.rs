@@ -0,0 +1,132 @@
+//! Runs the riscv-tests ISA suite (https://github.com/riscv-software-src/riscv-tests).
+//!
+//! The prebuilt `rv64*-p-*` binaries are looked up in `tests/riscv-tests/isa`,
+//! or in the directory named by `RISCV_TESTS_DIR`. Each test reports through
+//! `tohost`: an exit code of 0 is a pass, anything else is the number of the
+//! failing test case. The binaries are not part of the repository: build them
+//! with `tests/riscv-tests/build.sh`, as CI does. A suite with no binaries
+//! present fails.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rvemu::elf::Elf;
+use rvemu::emulator::Emulator;
+
+/// Far more instructions than any test in the suite executes, so that a
+/// test stuck in a loop fails instead of hanging
+const INSTRUCTION_LIMIT: u64 = 10_000_000;
+
+fn test_dir() -> PathBuf {
+    match env::var_os("RISCV_TESTS_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/riscv-tests/isa"),
+    }
+}
+
+/// Run one binary, returning why it failed if it did
+fn run_test(path: &Path) -> Result<(), String> {
+    let data = fs::read(path).map_err(|error| error.to_string())?;
+    let elf = Elf::parse(&data).map_err(|error| error.to_string())?;
+    let mut emu = Emulator::from_elf(&elf);
+    if emu.htif.is_none() {
+        return Err("no tohost symbol".to_string());
+    }
+    let result = emu.run_for(INSTRUCTION_LIMIT);
+    match (emu.exit_status(), result) {
+        (Some(0), _) => Ok(()),
+        (Some(test), _) => Err(format!("test case {} failed", test)),
+        (None, Err(exception)) => Err(format!(
+            "unhandled exception {:?} at pc = {}",
+            exception,
+            emu.symbols.describe(emu.pc)
+        )),
+        (None, Ok(())) => Err(format!(
+            "no result reported, stopped at pc = {}",
+            emu.symbols.describe(emu.pc)
+        )),
+    }
+}
+
+/// Run every binary of a suite, such as `rv64ui-p`
+fn run_suite(suite: &str) {
+    let prefix = format!("{}-", suite);
+    let mut paths: Vec<PathBuf> = match fs::read_dir(test_dir()) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_string_lossy();
+                name.starts_with(&prefix) && path.extension().is_none()
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    assert!(
+        !paths.is_empty(),
+        "no {} binaries in {}, build them with tests/riscv-tests/build.sh",
+        suite,
+        test_dir().display()
+    );
+    paths.sort();
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let name = path.file_name().unwrap().to_string_lossy();
+        match run_test(path) {
+            Ok(()) => println!("{} ... ok", name),
+            Err(reason) => {
+                println!("{} ... FAILED: {}", name, reason);
+                failures.push(name.into_owned());
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} {} tests failed: {}",
+        failures.len(),
+        paths.len(),
+        suite,
+        failures.join(", ")
+    );
+}
+
+#[test]
+fn rv64ui() {
+    run_suite("rv64ui-p");
+}
+
+#[test]
+fn rv64um() {
+    run_suite("rv64um-p");
+}
+
+#[test]
+fn rv64ua() {
+    run_suite("rv64ua-p");
+}
+
+#[test]
+fn rv64uf() {
+    run_suite("rv64uf-p");
+}
+
+#[test]
+fn rv64ud() {
+    run_suite("rv64ud-p");
+}
+
+#[test]
+fn rv64uc() {
+    run_suite("rv64uc-p");
+}
+
+#[test]
+fn rv64mi() {
+    run_suite("rv64mi-p");
+}
+
+#[test]
+fn rv64si() {
+    run_suite("rv64si-p");
+}